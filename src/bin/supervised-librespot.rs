@@ -1,6 +1,10 @@
+use std::sync::Arc;
+
 use rustberry::components::access_token_provider::AccessTokenProvider;
 use rustberry::config::Config;
+use rustberry::effects::spotify::client::{self, SpotifyClient};
 use rustberry::effects::spotify::connect::external_command::ExternalCommand;
+use rustberry::effects::spotify::devices::{self, DeviceService};
 use slog::{self, o, Drain};
 use slog_async;
 use slog_term;
@@ -17,12 +21,33 @@ fn main() {
 
 fn main_with_log() {
     let config = envy::from_env::<Config>().unwrap();
+    let accounts_base_url = client::base_url(
+        config
+            .spotify_accounts_base_url
+            .as_deref()
+            .unwrap_or(client::DEFAULT_ACCOUNTS_BASE_URL),
+    )
+    .unwrap();
+    let api_base_url = client::base_url(
+        config
+            .spotify_api_base_url
+            .as_deref()
+            .unwrap_or(client::DEFAULT_API_BASE_URL),
+    )
+    .unwrap();
     let access_token_provider = AccessTokenProvider::new(
+        &accounts_base_url,
         &config.client_id,
         &config.client_secret,
-        &config.refresh_token,
+        config.refresh_token.as_deref(),
+        config.spotify_state_file.as_deref(),
     )
     .unwrap();
-    let _cmd = ExternalCommand::new_from_env(&access_token_provider, "rustberry-test".to_string());
+    let spotify_client = Arc::new(SpotifyClient::new(
+        api_base_url,
+        Arc::new(access_token_provider),
+    ));
+    let device_service = Arc::new(DeviceService::new(spotify_client, devices::DEFAULT_MAX_AGE));
+    let _cmd = ExternalCommand::new_from_env(device_service, "rustberry-test".to_string(), None);
     std::thread::sleep(std::time::Duration::from_secs(60));
 }
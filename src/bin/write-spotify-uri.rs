@@ -0,0 +1,51 @@
+use failure::Fallible;
+
+use rustberry::components::rfid::*;
+use rustberry::components::spotify_uri::{SpotifyOffset, SpotifyResource, SpotifyUri};
+use rustberry::player::PlaybackResource;
+
+struct Written {
+    _resource: PlaybackResource,
+    _uid: String,
+}
+
+fn run_application() -> Fallible<Written> {
+    let url = dialoguer::Input::<String>::new()
+        .with_prompt("Spotify URI or open.spotify.com URL")
+        .interact()?;
+    let uri: SpotifyUri = url.parse()?;
+    let mut offset = None;
+    if uri.supports_offset() {
+        let track = dialoguer::Input::<u32>::new()
+            .with_prompt("Start at track number")
+            .default(1)
+            .interact()?;
+        if track > 1 {
+            offset = Some(SpotifyOffset::Position(track - 1));
+        }
+    }
+    let resource = PlaybackResource::SpotifyUri(SpotifyResource::new(uri, offset)?);
+    println!("Playback resource: {:?}", &resource);
+    let resource_deserialized = serde_json::to_string(&resource)?;
+    let mut rc = RfidController::new()?;
+    let tag = rc.open_tag().expect("Failed to open RFID tag").unwrap();
+    let uid = format!("{:?}", tag.uid);
+    println!("RFID Tag UID: {}", uid);
+    let mut tag_writer = tag.new_writer();
+    tag_writer.write_string(&resource_deserialized)?;
+    Ok(Written {
+        _resource: resource,
+        _uid: uid,
+    })
+}
+
+fn main() {
+    match run_application() {
+        Ok(_written) => {
+            println!("Successfully written playback resource to RFID tag.");
+        }
+        Err(err) => {
+            println!("Failed to write the playback resource to RFID tag: {}", err);
+        }
+    }
+}
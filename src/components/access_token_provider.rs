@@ -1,54 +1,205 @@
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use failure::Fallible;
 // use gotham_derive::StateData;
 use slog_scope::{info, warn};
+use url::Url;
 
+use super::spotify_credentials::PersistedCredentials;
 use spotify_auth::request_fresh_token;
 
 pub use err::*;
 
+/// Tokens are refreshed this long before they expire.
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+/// Lower bound for the refresh interval, protecting against bogus `expires_in` values.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+/// First retry delay after a failed refresh, doubled on every consecutive failure.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenHealth {
+    /// No token has been retrieved so far.
+    Missing,
+    Valid,
+    /// The current token is still valid, but refreshing it has failed.
+    Degraded,
+    Expired,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenStatus {
+    pub health: TokenHealth,
+    pub expires_at: Option<Instant>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct TokenState {
+    access_token: Option<String>,
+    expires_at: Option<Instant>,
+    /// Incremented on every successful refresh.
+    generation: u64,
+    consecutive_failures: u32,
+    last_error: Option<String>,
+}
+
+impl TokenState {
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| Instant::now() >= expires_at)
+    }
+
+    fn refreshed(&mut self, access_token: String, expires_in: Duration) {
+        self.access_token = Some(access_token);
+        self.expires_at = Some(Instant::now() + expires_in);
+        self.generation += 1;
+        self.consecutive_failures = 0;
+        self.last_error = None;
+    }
+
+    fn failed(&mut self, err: String) -> u32 {
+        self.consecutive_failures += 1;
+        self.last_error = Some(err);
+        self.consecutive_failures
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AccessTokenProvider {
+    state: Arc<RwLock<TokenState>>,
+    refresh_requests: Sender<()>,
+}
+
+struct Credentials {
+    accounts_base_url: Url,
     client_id: String,
     client_secret: String,
+    /// Refresh token from the configuration, if any.
+    configured_refresh_token: Option<String>,
+    /// Refresh token currently in use, possibly rotated by Spotify.
     refresh_token: String,
-    access_token: Arc<RwLock<Option<String>>>,
+    state_file: Option<PathBuf>,
+}
+
+impl Credentials {
+    fn persist(&self, access_token: &str, expires_in: Duration) {
+        if let Some(ref state_file) = self.state_file {
+            let persisted =
+                PersistedCredentials::new(&self.refresh_token, access_token, expires_in);
+            if let Err(err) = persisted.save(state_file) {
+                warn!(
+                    "Failed to persist Spotify credentials to {}: {}",
+                    state_file.display(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+/// Delay until the next refresh of a token valid for `expires_in`.
+fn refresh_delay(expires_in: Duration) -> Duration {
+    let delay = if expires_in > 2 * REFRESH_MARGIN {
+        expires_in - REFRESH_MARGIN
+    } else {
+        expires_in / 2
+    };
+    delay.max(MIN_REFRESH_INTERVAL)
+}
+
+/// Delay until the next attempt after the given number of consecutive failures.
+fn retry_delay(consecutive_failures: u32) -> Duration {
+    let exponent = consecutive_failures.saturating_sub(1).min(16);
+    (INITIAL_RETRY_DELAY * 2u32.pow(exponent)).min(MAX_RETRY_DELAY)
+}
+
+/// Returns true if the accounts service rejected the request itself, e.g. because the refresh
+/// token has been revoked.
+fn is_rejected(err: &failure::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .and_then(|err| err.status())
+        .is_some_and(|status| status.is_client_error())
+}
+
+fn refresh(credentials: &mut Credentials, state: &RwLock<TokenState>) -> Duration {
+    match request_fresh_token(
+        &credentials.accounts_base_url,
+        &credentials.client_id,
+        &credentials.client_secret,
+        &credentials.refresh_token,
+    ) {
+        Ok(rsp) => {
+            let expires_in = Duration::from_secs(rsp.expires_in.max(0) as u64);
+            info!("Retrieved fresh access token"; "expires_in" => expires_in.as_secs());
+            if let Some(refresh_token) = rsp.refresh_token {
+                if refresh_token != credentials.refresh_token {
+                    info!("Spotify rotated the refresh token");
+                    credentials.refresh_token = refresh_token;
+                }
+            }
+            credentials.persist(&rsp.access_token, expires_in);
+            state
+                .write()
+                .unwrap()
+                .refreshed(rsp.access_token, expires_in);
+            refresh_delay(expires_in)
+        }
+        Err(err) => {
+            if let Some(ref configured_refresh_token) = credentials.configured_refresh_token {
+                if is_rejected(&err) && credentials.refresh_token != *configured_refresh_token {
+                    warn!(
+                        "Persisted refresh token rejected, falling back to configured refresh token"
+                    );
+                    credentials.refresh_token = configured_refresh_token.clone();
+                }
+            }
+            let consecutive_failures = state.write().unwrap().failed(err.to_string());
+            let delay = retry_delay(consecutive_failures);
+            warn!(
+                "Failed to retrieve access token (attempt {}), retrying in {:?}: {}",
+                consecutive_failures, delay, err
+            );
+            delay
+        }
+    }
 }
 
 fn token_refresh_thread(
-    client_id: String,
-    client_secret: String,
-    refresh_token: String,
-    access_token: Arc<RwLock<Option<String>>>,
+    mut credentials: Credentials,
+    state: Arc<RwLock<TokenState>>,
+    refresh_requests: Receiver<()>,
+    initial_delay: Duration,
 ) {
+    let mut delay = initial_delay;
     loop {
-        {
-            match request_fresh_token(&client_id, &client_secret, &refresh_token)
-                .map(|x| x.access_token)
-            {
-                Ok(token) => {
-                    info!("Retrieved fresh access token"; "access_token" => &token);
-                    let mut access_token_write = access_token.write().unwrap();
-                    *access_token_write = Some(token);
-                }
-                Err(err) => {
-                    warn!("Failed to retrieve access token: {}", err);
-                }
+        match refresh_requests.recv_timeout(delay) {
+            Ok(()) => {
+                // Coalesce concurrent requests into a single refresh.
+                while refresh_requests.try_recv().is_ok() {}
+                info!("Refreshing access token on request");
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                info!("Access Token Provider dropped, terminating token refresh thread");
+                return;
             }
         }
-        thread::sleep(std::time::Duration::from_secs(600));
+        delay = refresh(&mut credentials, &state);
     }
-    // error!("Token refresh thread terminated unexpectedly");
-    // panic!()
 }
 
 impl AccessTokenProvider {
     pub fn wait_for_token(&self) -> Result<(), AtpError> {
         let n_attempts = 20;
         for _idx in 0..n_attempts {
-            if self.access_token.read().unwrap().is_some() {
+            if self.state.read().unwrap().access_token.is_some() {
                 return Ok(());
             }
             thread::sleep(std::time::Duration::from_millis(500));
@@ -57,11 +208,13 @@ impl AccessTokenProvider {
     }
 
     pub fn get_token(&self) -> Result<String, AtpError> {
-        let access_token = self.access_token.read().unwrap();
-
-        match &*access_token {
+        let state = self.state.read().unwrap();
+        if state.is_expired() {
+            return Err(AtpError::TokenExpired);
+        }
+        match &state.access_token {
             Some(token) => Ok(token.clone()),
-            None => Err(AtpError::NoTokenReceivedYet.into()),
+            None => Err(AtpError::NoTokenReceivedYet),
         }
     }
 
@@ -69,46 +222,107 @@ impl AccessTokenProvider {
         self.get_token().map(|token| format!("Bearer {}", &token))
     }
 
+    /// Asks the refresh thread to retrieve a new token right away, e.g. because the current
+    /// one has been rejected. Does not block.
+    pub fn force_refresh(&self) {
+        let _ = self.refresh_requests.try_send(());
+    }
+
+    /// Changes whenever a new token has been retrieved.
+    pub fn generation(&self) -> u64 {
+        self.state.read().unwrap().generation
+    }
+
+    pub fn status(&self) -> TokenStatus {
+        let state = self.state.read().unwrap();
+        let health = if state.access_token.is_none() {
+            TokenHealth::Missing
+        } else if state.is_expired() {
+            TokenHealth::Expired
+        } else if state.consecutive_failures > 0 {
+            TokenHealth::Degraded
+        } else {
+            TokenHealth::Valid
+        };
+        TokenStatus {
+            health,
+            expires_at: state.expires_at,
+            consecutive_failures: state.consecutive_failures,
+            last_error: state.last_error.clone(),
+        }
+    }
+
+    /// Credentials persisted in `state_file` take precedence over the configured refresh
+    /// token. A still valid persisted access token is used right away.
     pub fn new(
+        accounts_base_url: &Url,
         client_id: &str,
         client_secret: &str,
-        refresh_token: &str,
+        refresh_token: Option<&str>,
+        state_file: Option<&Path>,
     ) -> Fallible<AccessTokenProvider> {
-        let access_token = Arc::new(RwLock::new(None));
+        let mut token_state = TokenState::default();
+        let mut initial_delay = Duration::from_secs(0);
+        let persisted = match state_file.map(PersistedCredentials::load) {
+            Some(Ok(persisted)) => persisted,
+            Some(Err(err)) => {
+                warn!("Failed to load persisted Spotify credentials: {}", err);
+                None
+            }
+            None => None,
+        };
+        let current_refresh_token = match persisted {
+            Some(ref persisted) => {
+                if let Some((access_token, remaining)) = persisted.access_token() {
+                    if remaining > REFRESH_MARGIN {
+                        info!("Using persisted access token"; "expires_in" => remaining.as_secs());
+                        token_state.refreshed(access_token, remaining);
+                        initial_delay = refresh_delay(remaining);
+                    }
+                }
+                persisted.refresh_token.clone()
+            }
+            None => match refresh_token {
+                Some(refresh_token) => refresh_token.to_string(),
+                None => return Err(AtpError::NoRefreshToken.into()),
+            },
+        };
+        let state = Arc::new(RwLock::new(token_state));
+        let (refresh_requests, refresh_requests_rx) = crossbeam_channel::bounded(1);
+        let credentials = Credentials {
+            accounts_base_url: accounts_base_url.clone(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            configured_refresh_token: refresh_token.map(str::to_string),
+            refresh_token: current_refresh_token,
+            state_file: state_file.map(Path::to_path_buf),
+        };
 
         {
-            let access_token_clone = Arc::clone(&access_token);
-            let client_id = client_id.clone().to_string();
-            let client_secret = client_secret.clone().to_string();
-            let refresh_token = refresh_token.clone().to_string();
-
+            let state = Arc::clone(&state);
             thread::Builder::new()
                 .name("access-token-provider".to_string())
                 .spawn(move || {
-                    token_refresh_thread(
-                        client_id,
-                        client_secret,
-                        refresh_token,
-                        access_token_clone,
-                    )
+                    token_refresh_thread(credentials, state, refresh_requests_rx, initial_delay)
                 })?;
         }
 
         Ok(AccessTokenProvider {
-            client_id: client_id.clone().to_string(),
-            client_secret: client_secret.clone().to_string(),
-            refresh_token: refresh_token.clone().to_string(),
-            access_token,
+            state,
+            refresh_requests,
         })
     }
 }
 
 pub mod spotify_auth {
-    use failure::Fallible;
-    const TOKEN_REFRESH_URL: &str = "https://accounts.spotify.com/api/token";
     use base64;
+    use failure::Fallible;
     use reqwest::header::AUTHORIZATION;
     use serde::Deserialize;
+    use url::Url;
+
+    /// Token endpoint, relative to the accounts base URL.
+    const TOKEN_REFRESH_PATH: &str = "api/token";
 
     #[derive(Debug, Clone, Deserialize)]
     pub struct AuthResponse {
@@ -125,6 +339,8 @@ pub mod spotify_auth {
         pub token_type: String,
         pub scope: String,
         pub expires_in: i32,
+        /// Only present if Spotify rotated the refresh token.
+        pub refresh_token: Option<String>,
     }
 
     fn encode_client_id_and_secret(client_id: &str, client_secret: &str) -> String {
@@ -134,6 +350,7 @@ pub mod spotify_auth {
     }
 
     pub fn request_fresh_token(
+        accounts_base_url: &Url,
         client_id: &str,
         client_secret: &str,
         refresh_token: &str,
@@ -145,7 +362,7 @@ pub mod spotify_auth {
 
         let http_client = reqwest::blocking::Client::new();
         let res = http_client
-            .post(TOKEN_REFRESH_URL)
+            .post(accounts_base_url.join(TOKEN_REFRESH_PATH)?)
             .header(AUTHORIZATION, auth_token)
             .form(&params)
             .send()?
@@ -161,6 +378,8 @@ pub mod err {
     #[derive(Clone, Copy, Debug)]
     pub enum AtpError {
         NoTokenReceivedYet,
+        TokenExpired,
+        NoRefreshToken,
     }
 
     impl std::fmt::Display for AtpError {
@@ -169,9 +388,86 @@ pub mod err {
 
             match self {
                 NoTokenReceivedYet => write!(f, "No initial token received yet"),
+                TokenExpired => write!(f, "Access token expired"),
+                NoRefreshToken => write!(
+                    f,
+                    "No refresh token configured or persisted, run `jukeboxd auth` first"
+                ),
             }
         }
     }
 
     impl std::error::Error for AtpError {}
 }
+
+#[cfg(test)]
+mod test {
+    use crate::effects::spotify::mock_server::MockServer;
+
+    use super::*;
+
+    #[test]
+    fn rotated_refresh_token_is_persisted_and_preferred() {
+        let server = MockServer::start();
+        server.respond(
+            "POST",
+            "/api/token",
+            200,
+            r#"{"access_token": "access", "token_type": "Bearer", "scope": "", "expires_in": 3600, "refresh_token": "rotated"}"#,
+        );
+        let state_file =
+            std::env::temp_dir().join(format!("rustberry-atp-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&state_file);
+
+        let atp = AccessTokenProvider::new(
+            &server.base_url(),
+            "id",
+            "secret",
+            Some("configured"),
+            Some(&state_file),
+        )
+        .unwrap();
+        atp.wait_for_token().unwrap();
+        // Persisting happens before the token is published.
+        let persisted = PersistedCredentials::load(&state_file).unwrap().unwrap();
+        assert_eq!(persisted.refresh_token, "rotated");
+        assert_eq!(persisted.access_token.as_deref(), Some("access"));
+
+        // The persisted access token is used without contacting Spotify.
+        let atp = AccessTokenProvider::new(
+            &server.base_url(),
+            "id",
+            "secret",
+            Some("configured"),
+            Some(&state_file),
+        )
+        .unwrap();
+        assert_eq!(atp.get_token().unwrap(), "access");
+        assert_eq!(server.requests().len(), 1);
+        assert!(server.requests()[0]
+            .body
+            .contains("refresh_token=configured"));
+        std::fs::remove_file(&state_file).unwrap();
+    }
+
+    #[test]
+    fn refreshes_are_scheduled_before_expiry() {
+        assert_eq!(
+            refresh_delay(Duration::from_secs(3600)),
+            Duration::from_secs(3540)
+        );
+        assert_eq!(
+            refresh_delay(Duration::from_secs(60)),
+            Duration::from_secs(30)
+        );
+        assert_eq!(refresh_delay(Duration::from_secs(0)), MIN_REFRESH_INTERVAL);
+    }
+
+    #[test]
+    fn retries_back_off_exponentially() {
+        assert_eq!(retry_delay(1), Duration::from_secs(5));
+        assert_eq!(retry_delay(2), Duration::from_secs(10));
+        assert_eq!(retry_delay(4), Duration::from_secs(40));
+        assert_eq!(retry_delay(100), MAX_RETRY_DELAY);
+    }
+}
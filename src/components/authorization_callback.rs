@@ -0,0 +1,88 @@
+//! Local HTTP server receiving the OAuth redirect after the user granted access.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use gotham::middleware::state::StateMiddleware;
+use gotham::pipeline::single::single_pipeline;
+use gotham::pipeline::single_middleware;
+use gotham::router::builder::{build_router, DefineSingleRoute, DrawRoutes};
+use gotham::router::Router;
+use gotham::state::{FromState, State};
+use gotham_derive::{StateData, StaticResponseExtender};
+use serde::Deserialize;
+use slog_scope::info;
+
+use super::spotify_authorization::{CallbackParams, Error};
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Clone, StateData)]
+struct CallbackState {
+    params: Arc<Mutex<Sender<CallbackParams>>>,
+}
+
+fn handle_callback(mut state: State) -> (State, (mime::Mime, String)) {
+    let query = CallbackQuery::take_from(&mut state);
+    let params = CallbackParams {
+        code: query.code,
+        state: query.state,
+        error: query.error,
+    };
+    let message = if params.error.is_some() {
+        "Authorization failed, see the jukebox output for details."
+    } else {
+        "Authorization received, you can close this window."
+    };
+    let _ = CallbackState::borrow_from(&state)
+        .params
+        .lock()
+        .unwrap()
+        .try_send(params);
+    (state, (mime::TEXT_PLAIN, message.to_string()))
+}
+
+fn router(callback_state: CallbackState, path: &str) -> Router {
+    let middleware = StateMiddleware::new(callback_state);
+    let (chain, pipelines) = single_pipeline(single_middleware(middleware));
+    build_router(chain, pipelines, |route| {
+        route
+            .get(path)
+            .with_query_extractor::<CallbackQuery>()
+            .to(handle_callback);
+    })
+}
+
+/// Serves the callback path on the given address until the first callback arrived.
+pub fn wait_for_callback(
+    address: SocketAddr,
+    path: &str,
+    timeout: Duration,
+) -> Result<CallbackParams, Error> {
+    let (tx, rx) = crossbeam_channel::bounded(1);
+    let callback_state = CallbackState {
+        params: Arc::new(Mutex::new(tx)),
+    };
+    let router = router(callback_state, path);
+    info!("Waiting for authorization callback on {}{}", address, path);
+    // The server thread ends with the process.
+    thread::Builder::new()
+        .name("authorization-callback".to_string())
+        .spawn(move || gotham::start(address, router))
+        .map_err(|err| Error::CallbackServer(err.to_string()))?;
+    match rx.recv_timeout(timeout) {
+        Ok(params) => Ok(params),
+        Err(RecvTimeoutError::Timeout) => Err(Error::CallbackTimeout),
+        Err(RecvTimeoutError::Disconnected) => Err(Error::CallbackServer(
+            "Callback server terminated".to_string(),
+        )),
+    }
+}
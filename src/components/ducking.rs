@@ -0,0 +1,126 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::Source;
+
+/// Duration of the transition between two ducking factors.
+const RAMP_DURATION: Duration = Duration::from_millis(200);
+
+/// Volume factor shared between all playing music sources, used to lower the music while
+/// announcements are playing.
+#[derive(Debug, Clone)]
+pub struct Ducking {
+    /// Bit representation of the `f32` factor.
+    factor: Arc<AtomicU32>,
+}
+
+impl Default for Ducking {
+    fn default() -> Self {
+        Ducking {
+            factor: Arc::new(AtomicU32::new(1f32.to_bits())),
+        }
+    }
+}
+
+impl Ducking {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn factor(&self) -> f32 {
+        f32::from_bits(self.factor.load(Ordering::Relaxed))
+    }
+
+    pub fn duck(&self, factor: f32) {
+        self.factor.store(factor.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn restore(&self) {
+        self.duck(1.0);
+    }
+
+    pub fn apply<S>(&self, source: S) -> DuckedSource<S>
+    where
+        S: Source<Item = i16>,
+    {
+        DuckedSource {
+            source,
+            ducking: self.clone(),
+            current: self.factor(),
+        }
+    }
+}
+
+/// Source following the shared ducking factor, changes are ramped to avoid clicks.
+pub struct DuckedSource<S> {
+    source: S,
+    ducking: Ducking,
+    current: f32,
+}
+
+impl<S> Iterator for DuckedSource<S>
+where
+    S: Source<Item = i16>,
+{
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = self.source.next()?;
+        let target = self.ducking.factor();
+        if self.current != target {
+            let samples_per_sec =
+                self.source.sample_rate() as f32 * self.source.channels() as f32;
+            let step = 1.0 / (RAMP_DURATION.as_secs_f32() * samples_per_sec);
+            self.current = if self.current < target {
+                (self.current + step).min(target)
+            } else {
+                (self.current - step).max(target)
+            };
+        }
+        Some((sample as f32 * self.current) as i16)
+    }
+}
+
+impl<S> Source for DuckedSource<S>
+where
+    S: Source<Item = i16>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    #[test]
+    fn ducking_is_ramped() {
+        let ducking = Ducking::new();
+        let constant = SamplesBuffer::new(1, 1000, vec![10000i16; 1000]);
+        let mut source = ducking.apply(constant);
+
+        assert_eq!(source.next(), Some(10000));
+        ducking.duck(0.2);
+        let samples: Vec<i16> = source.by_ref().take(400).collect();
+        // Ramping down takes 160 samples at 1000 Hz.
+        assert!(samples[0] < 10000 && samples[0] > 9900);
+        assert!(samples[100] > 2000);
+        assert_eq!(samples[399], 2000);
+
+        ducking.restore();
+        assert_eq!(source.last(), Some(10000));
+    }
+}
@@ -0,0 +1,429 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use rodio::Source;
+use serde::Deserialize;
+
+pub use err::*;
+
+/// Corner frequency of the bass control.
+pub const BASS_FREQUENCY: f32 = 250.0;
+/// Corner frequency of the treble control.
+pub const TREBLE_FREQUENCY: f32 = 4000.0;
+
+/// Quality factor used if a band does not specify one. For shelves this yields the
+/// steepest slope without overshoot.
+const DEFAULT_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Number of samples after which the equalizer settings are checked for updates.
+const UPDATE_INTERVAL: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BandKind {
+    LowShelf,
+    HighShelf,
+    Peaking,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Band {
+    pub kind: BandKind,
+    pub frequency: f32,
+    pub gain_db: f32,
+    pub q: Option<f32>,
+}
+
+impl Band {
+    pub fn new(kind: BandKind, frequency: f32, gain_db: f32) -> Self {
+        Band {
+            kind,
+            frequency,
+            gain_db,
+            q: None,
+        }
+    }
+}
+
+/// Parses bands of the form `<kind>:<frequency>:<gain_db>[:<q>]`, e.g. `peaking:1000:-3:1.4`.
+impl FromStr for Band {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidBand(s.to_string());
+        let fields: Vec<&str> = s.trim().split(':').collect();
+        if fields.len() != 3 && fields.len() != 4 {
+            return Err(invalid());
+        }
+        let kind = match fields[0] {
+            "low-shelf" => BandKind::LowShelf,
+            "high-shelf" => BandKind::HighShelf,
+            "peaking" => BandKind::Peaking,
+            _ => return Err(invalid()),
+        };
+        let frequency: f32 = fields[1].parse().map_err(|_| invalid())?;
+        let gain_db: f32 = fields[2].parse().map_err(|_| invalid())?;
+        let q = match fields.get(3) {
+            Some(q) => Some(q.parse::<f32>().map_err(|_| invalid())?),
+            None => None,
+        };
+        if frequency <= 0.0 || q.map(|q| q <= 0.0).unwrap_or(false) {
+            return Err(invalid());
+        }
+        Ok(Band {
+            kind,
+            frequency,
+            gain_db,
+            q,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct EqualizerSettings {
+    pub bands: Vec<Band>,
+}
+
+impl EqualizerSettings {
+    /// Parses a comma separated list of bands.
+    pub fn parse(bands: &str) -> Result<Self, Error> {
+        let bands = bands
+            .split(',')
+            .filter(|band| !band.trim().is_empty())
+            .map(Band::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(EqualizerSettings { bands })
+    }
+
+    /// Parses a `;` separated list of settings, each in the format expected by `parse`.
+    pub fn parse_presets(presets: &str) -> Result<Vec<Self>, Error> {
+        presets.split(';').map(Self::parse).collect()
+    }
+
+    /// Settings consisting of a bass and a treble shelf.
+    pub fn bass_treble(bass_db: f32, treble_db: f32) -> Self {
+        EqualizerSettings {
+            bands: vec![
+                Band::new(BandKind::LowShelf, BASS_FREQUENCY, bass_db),
+                Band::new(BandKind::HighShelf, TREBLE_FREQUENCY, treble_db),
+            ],
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    settings: EqualizerSettings,
+    generation: usize,
+}
+
+/// Equalizer settings shared between all playing sources. Updates are picked up by the
+/// sources while playing.
+#[derive(Debug, Clone)]
+pub struct Equalizer {
+    state: Arc<RwLock<State>>,
+}
+
+impl Equalizer {
+    pub fn new(settings: EqualizerSettings) -> Self {
+        Equalizer {
+            state: Arc::new(RwLock::new(State {
+                settings,
+                generation: 0,
+            })),
+        }
+    }
+
+    pub fn settings(&self) -> EqualizerSettings {
+        self.state.read().unwrap().settings.clone()
+    }
+
+    pub fn set(&self, settings: EqualizerSettings) {
+        let mut state = self.state.write().unwrap();
+        state.settings = settings;
+        state.generation += 1;
+    }
+
+    pub fn apply<S>(&self, source: S) -> EqualizerSource<S>
+    where
+        S: Source<Item = i16>,
+    {
+        EqualizerSource::new(source, self.clone())
+    }
+
+    fn generation(&self) -> usize {
+        self.state.read().unwrap().generation
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Coefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Coefficients {
+    /// Computes biquad coefficients according to the Audio EQ Cookbook by R. Bristow-Johnson.
+    fn new(band: &Band, sample_rate: u32) -> Option<Self> {
+        let nyquist = sample_rate as f32 / 2.0;
+        if band.gain_db == 0.0 || band.frequency >= nyquist {
+            return None;
+        }
+        let a = 10f32.powf(band.gain_db / 40.0);
+        let w0 = 2.0 * PI * band.frequency / sample_rate as f32;
+        let (sin, cos) = (w0.sin(), w0.cos());
+        let alpha = sin / (2.0 * band.q.unwrap_or(DEFAULT_Q));
+        let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match band.kind {
+            BandKind::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            BandKind::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha),
+                (a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha,
+            ),
+            BandKind::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha),
+                (a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha,
+            ),
+        };
+
+        Some(Coefficients {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        })
+    }
+}
+
+/// Direct form I filter state of a single channel.
+#[derive(Debug, Clone, Copy, Default)]
+struct FilterState {
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl FilterState {
+    fn process(&mut self, c: &Coefficients, x: f32) -> f32 {
+        let y = c.b0 * x + c.b1 * self.x1 + c.b2 * self.x2 - c.a1 * self.y1 - c.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+struct Filter {
+    coefficients: Coefficients,
+    /// One state per channel.
+    states: Vec<FilterState>,
+}
+
+/// Source applying the shared equalizer settings to the wrapped source.
+pub struct EqualizerSource<S> {
+    source: S,
+    equalizer: Equalizer,
+    generation: Option<usize>,
+    sample_rate: u32,
+    channels: u16,
+    filters: Vec<Filter>,
+    channel: usize,
+    samples_until_update: usize,
+}
+
+impl<S> EqualizerSource<S>
+where
+    S: Source<Item = i16>,
+{
+    fn new(source: S, equalizer: Equalizer) -> Self {
+        EqualizerSource {
+            sample_rate: source.sample_rate(),
+            channels: source.channels(),
+            source,
+            equalizer,
+            generation: None,
+            filters: Vec::new(),
+            channel: 0,
+            samples_until_update: 0,
+        }
+    }
+
+    /// Rebuilds the filters if the settings or the format of the source changed.
+    fn update(&mut self) {
+        let generation = self.equalizer.generation();
+        let sample_rate = self.source.sample_rate();
+        let channels = self.source.channels();
+        if self.generation == Some(generation)
+            && self.sample_rate == sample_rate
+            && self.channels == channels
+        {
+            return;
+        }
+
+        let settings = self.equalizer.settings();
+        self.filters = settings
+            .bands
+            .iter()
+            .filter_map(|band| Coefficients::new(band, sample_rate))
+            .map(|coefficients| Filter {
+                coefficients,
+                states: vec![FilterState::default(); channels as usize],
+            })
+            .collect();
+        self.generation = Some(generation);
+        self.sample_rate = sample_rate;
+        self.channels = channels;
+    }
+}
+
+impl<S> Iterator for EqualizerSource<S>
+where
+    S: Source<Item = i16>,
+{
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        // Only update at frame boundaries, such that channels stay aligned.
+        if self.channel == 0 && self.samples_until_update == 0 {
+            self.update();
+            self.samples_until_update = UPDATE_INTERVAL;
+        }
+        let sample = self.source.next()?;
+        self.samples_until_update = self.samples_until_update.saturating_sub(1);
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % std::cmp::max(1, self.channels as usize);
+
+        if self.filters.is_empty() {
+            return Some(sample);
+        }
+        let mut value = sample as f32;
+        for filter in self.filters.iter_mut() {
+            value = filter.states[channel].process(&filter.coefficients, value);
+        }
+        let max = i16::MAX as f32;
+        Some(value.max(-max - 1.0).min(max) as i16)
+    }
+}
+
+impl<S> Source for EqualizerSource<S>
+where
+    S: Source<Item = i16>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+pub mod err {
+    use std::fmt::{self, Display};
+
+    #[derive(Debug)]
+    pub enum Error {
+        InvalidBand(String),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::InvalidBand(band) => write!(
+                    f,
+                    "Invalid equalizer band '{}', expected <kind>:<frequency>:<gain_db>[:<q>]",
+                    band
+                ),
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+#[cfg(test)]
+mod test {
+    use rodio::source::SineWave;
+
+    use super::*;
+
+    /// Amplitude of a sine wave, derived from its RMS level.
+    fn amplitude<S: Iterator<Item = i16>>(source: S) -> f32 {
+        // Skip the transient response of the filters.
+        let samples: Vec<f32> = source
+            .skip(4800)
+            .map(|s| s as f32 / i16::MAX as f32)
+            .collect();
+        let mean_square = samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32;
+        (2.0 * mean_square).sqrt()
+    }
+
+    fn sine(freq: u32) -> impl Source<Item = i16> {
+        SineWave::new(freq)
+            .take_duration(Duration::from_millis(200))
+            .amplify(0.5)
+            .convert_samples::<i16>()
+    }
+
+    #[test]
+    fn bands_are_parsed() {
+        let settings = EqualizerSettings::parse("low-shelf:120:-4, peaking:1000:2.5:1.4").unwrap();
+        assert_eq!(
+            settings.bands,
+            vec![
+                Band::new(BandKind::LowShelf, 120.0, -4.0),
+                Band {
+                    kind: BandKind::Peaking,
+                    frequency: 1000.0,
+                    gain_db: 2.5,
+                    q: Some(1.4),
+                },
+            ]
+        );
+        assert!(EqualizerSettings::parse("notch:1000:3").is_err());
+    }
+
+    #[test]
+    fn bass_is_attenuated() {
+        let equalizer = Equalizer::new(EqualizerSettings::bass_treble(-12.0, 0.0));
+
+        // Far below the corner frequency the full attenuation applies, far above none.
+        let bass = amplitude(equalizer.apply(sine(40)));
+        assert!((bass - 0.5 * 0.25).abs() < 0.02, "bass amplitude {}", bass);
+        let treble = amplitude(equalizer.apply(sine(8000)));
+        assert!((treble - 0.5).abs() < 0.02, "treble amplitude {}", treble);
+
+        equalizer.set(EqualizerSettings::default());
+        let flat = amplitude(equalizer.apply(sine(40)));
+        assert!((flat - 0.5).abs() < 0.01, "flat amplitude {}", flat);
+    }
+}
@@ -1,62 +1,296 @@
 use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
 use std::io::{Error, ErrorKind, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
+use futures::future::{self, AbortHandle};
 use futures::prelude::*;
 use reqwest::{self, Response};
+use slog_scope::{error, info, warn};
 
+static SPILL_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Interval for logging the progress of running downloads.
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct BufferConfig {
+    /// Number of most recently downloaded bytes kept in memory.
+    pub window_size: usize,
+    /// Directory for the spill files holding the complete download.
+    pub spill_directory: PathBuf,
+    /// Maximum time to wait for the next chunk of the download.
+    pub chunk_timeout: Duration,
+    /// Maximum time a read waits for data to arrive.
+    pub read_timeout: Duration,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        BufferConfig {
+            window_size: 1024 * 1024,
+            spill_directory: std::env::temp_dir(),
+            chunk_timeout: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Storage for downloaded bytes with bounded memory usage. Every byte is spilled to an
+/// (already unlinked) temporary file, only the most recent `window_size` bytes are kept in memory.
+/// Backward seeks behind the window are served from the spill file.
+struct SpillBuffer {
+    /// Written by the producer without holding the lock on the buffer.
+    file: Arc<File>,
+    window: Vec<u8>,
+    window_start: usize,
+    window_size: usize,
+}
+
+impl SpillBuffer {
+    fn new(config: &BufferConfig) -> Result<Self, Error> {
+        let path = config.spill_directory.join(format!(
+            "rustberry-stream-{}-{}",
+            std::process::id(),
+            SPILL_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // The file descriptor stays valid, this way no stale spill files survive a crash.
+        fs::remove_file(&path)?;
+        Ok(SpillBuffer {
+            file: Arc::new(file),
+            window: Vec::with_capacity(config.window_size),
+            window_start: 0,
+            window_size: config.window_size,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.window_start + self.window.len()
+    }
+
+    /// Publishes bytes, which must already have been written to the spill file at `len()`.
+    fn append(&mut self, bytes: &[u8]) {
+        self.window.extend_from_slice(bytes);
+        // Shrink lazily, so that the window is not shifted for every single chunk.
+        if self.window.len() > 2 * self.window_size {
+            let excess = self.window.len() - self.window_size;
+            self.window.drain(..excess);
+            self.window_start += excess;
+        }
+    }
+
+    fn read_at(&self, pos: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        if pos >= self.len() {
+            return Ok(0);
+        }
+        if pos >= self.window_start {
+            let offset = pos - self.window_start;
+            let n = std::cmp::min(buf.len(), self.window.len() - offset);
+            buf[..n].copy_from_slice(&self.window[offset..offset + n]);
+            Ok(n)
+        } else {
+            let n = std::cmp::min(buf.len(), self.window_start - pos);
+            self.file.read_at(&mut buf[..n], pos as u64)
+        }
+    }
+}
+
+/// Download progress of a `FiniteStream`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadProgress {
+    pub downloaded: usize,
+    pub total: usize,
+}
+
+struct Shared {
+    buffer: SpillBuffer,
+    complete: bool,
+    cancelled: bool,
+    error: Option<(ErrorKind, String)>,
+}
+
+struct State {
+    shared: Mutex<Shared>,
+    cond: Condvar,
+    abort_handle: Mutex<Option<AbortHandle>>,
+}
+
+impl State {
+    fn finish(&self, update: impl FnOnce(&mut Shared)) {
+        let mut shared = self.shared.lock().unwrap();
+        update(&mut shared);
+        self.cond.notify_all();
+    }
+}
+
+/// Handle for observing and cancelling the download feeding a `FiniteStream`.
+#[derive(Clone)]
+pub struct DownloadHandle {
+    length: usize,
+    state: Arc<State>,
+}
+
+impl DownloadHandle {
+    pub fn progress(&self) -> DownloadProgress {
+        let shared = self.state.shared.lock().unwrap();
+        DownloadProgress {
+            downloaded: shared.buffer.len(),
+            total: self.length,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.state.shared.lock().unwrap().complete
+    }
+
+    /// Stops the download. Pending and future reads beyond the downloaded data return EOF.
+    pub fn cancel(&self) {
+        if let Some(abort_handle) = self.state.abort_handle.lock().unwrap().take() {
+            abort_handle.abort();
+        }
+        self.state.finish(|shared| shared.cancelled = true);
+    }
+}
+
+/// `Read + Seek` adapter for an HTTP download of known length. The download is driven by a
+/// producer task on the tokio runtime, reading only blocks until the requested bytes have arrived.
 pub struct FiniteStream {
     length: usize,
-    bytes: Vec<u8>,
     pos: usize,
-    stream: Pin<Box<dyn Send + 'static + Stream<Item = Result<Bytes, reqwest::Error>>>>,
+    read_timeout: Duration,
+    download: DownloadHandle,
 }
 
 impl FiniteStream {
-    pub fn from_response(response: Response) -> Result<Self, Error> {
+    /// Must be called from within the context of a tokio runtime.
+    pub fn from_response(response: Response, config: &BufferConfig) -> Result<Self, Error> {
         let length = if let Some(length) = response.content_length() {
             length
         } else {
-            return Err(Error::new(
-                ErrorKind::Other,
+            return Err(Error::other(
                 "HTTP Response does not contain Content-Length",
             ));
         };
-        let length = usize::try_from(length).map_err(|err| Error::new(ErrorKind::Other, err))?;
-        let bytes = Vec::with_capacity(length);
-        let pos = 0;
-        let stream = Box::pin(response.bytes_stream());
-        Ok(FiniteStream {
-            length,
-            bytes,
-            pos,
-            stream,
-        })
+        let length = usize::try_from(length).map_err(Error::other)?;
+        Self::new(length, response.bytes_stream(), config)
     }
 
+    /// Must be called from within the context of a tokio runtime.
     pub fn new(
         length: usize,
         stream: impl Send + 'static + Stream<Item = Result<Bytes, reqwest::Error>> + 'static,
-    ) -> Self {
-        let stream = Box::pin(stream);
-        Self {
-            bytes: Vec::new(),
-            pos: 0,
-            stream,
+        config: &BufferConfig,
+    ) -> Result<Self, Error> {
+        let state = Arc::new(State {
+            shared: Mutex::new(Shared {
+                buffer: SpillBuffer::new(config)?,
+                complete: false,
+                cancelled: false,
+                error: None,
+            }),
+            cond: Condvar::new(),
+            abort_handle: Mutex::new(None),
+        });
+        let (producer, abort_handle) = future::abortable(Self::produce(
+            Box::pin(stream),
+            length,
+            state.clone(),
+            config.chunk_timeout,
+        ));
+        *state.abort_handle.lock().unwrap() = Some(abort_handle);
+        tokio::spawn(producer);
+        Ok(Self {
             length,
+            pos: 0,
+            read_timeout: config.read_timeout,
+            download: DownloadHandle { length, state },
+        })
+    }
+
+    pub fn download_handle(&self) -> DownloadHandle {
+        self.download.clone()
+    }
+
+    async fn produce(
+        mut stream: Pin<Box<dyn Send + 'static + Stream<Item = Result<Bytes, reqwest::Error>>>>,
+        length: usize,
+        state: Arc<State>,
+        chunk_timeout: Duration,
+    ) {
+        let file = state.shared.lock().unwrap().buffer.file.clone();
+        let mut spilled = 0;
+        let mut last_progress_log = Instant::now();
+        loop {
+            let next = tokio::time::timeout(chunk_timeout, stream.next()).await;
+            // Readers wait on the lock, hence the spill file is written before taking it.
+            // They only access the file below the published length.
+            let spill_result = match next {
+                Ok(Some(Ok(ref bytes))) => file.write_all_at(bytes.as_ref(), spilled as u64),
+                _ => Ok(()),
+            };
+            let mut shared = state.shared.lock().unwrap();
+            if shared.cancelled {
+                break;
+            }
+            match next {
+                Ok(Some(Ok(bytes))) => {
+                    if let Err(err) = spill_result {
+                        error!("Failed to buffer downloaded data: {}", err);
+                        shared.error = Some((err.kind(), err.to_string()));
+                        break;
+                    }
+                    shared.buffer.append(bytes.as_ref());
+                    spilled += bytes.len();
+                    if last_progress_log.elapsed() >= PROGRESS_LOG_INTERVAL {
+                        info!("Downloaded {} of {} bytes", shared.buffer.len(), length);
+                        last_progress_log = Instant::now();
+                    }
+                }
+                Ok(Some(Err(err))) => {
+                    warn!("Download failed: {}", err);
+                    shared.error = Some((ErrorKind::Other, err.to_string()));
+                    break;
+                }
+                Ok(None) => {
+                    shared.complete = true;
+                    break;
+                }
+                Err(_) => {
+                    warn!("Download stalled for {:?}, giving up", chunk_timeout);
+                    shared.error = Some((ErrorKind::TimedOut, "Download stalled".to_string()));
+                    break;
+                }
+            }
+            state.cond.notify_all();
         }
+        state.cond.notify_all();
+    }
+}
+
+impl Drop for FiniteStream {
+    fn drop(&mut self) {
+        self.download.cancel();
     }
 }
 
-/// NOTE: May block current threads.
 impl std::io::Seek for FiniteStream {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64, std::io::Error> {
         let pos: u64 = match pos {
             SeekFrom::Start(n) => n,
             SeekFrom::End(n) => {
                 if n < 0 {
-                    self.length as u64 - (n.abs() as u64)
+                    (self.length as u64).saturating_sub(n.unsigned_abs())
                 } else {
                     // truncate
                     self.length as u64
@@ -64,64 +298,126 @@ impl std::io::Seek for FiniteStream {
             }
             SeekFrom::Current(n) => {
                 if n < 0 {
-                    (self.pos as u64).checked_sub(n.abs() as u64).unwrap_or(0)
+                    (self.pos as u64).saturating_sub(n.unsigned_abs())
                 } else {
                     self.pos as u64 + (n as u64)
                 }
             }
         };
 
-        // truncate cast!
-        let mut pos = pos as usize;
-        while pos >= self.bytes.len() {
-            //fixme
-            match futures::executor::block_on(self.stream.next()) {
-                Some(res) => match res {
-                    Ok(bytes) => {
-                        self.bytes.extend_from_slice(bytes.as_ref());
-                    }
-                    Err(err) => {
-                        return Err(std::io::Error::new(std::io::ErrorKind::Other, err));
-                    }
-                },
-                None => {
-                    // truncate pos
-                    pos = self.bytes.len();
-                    break;
-                }
-            }
-        }
-
-        self.pos = pos;
-        Ok(pos as u64)
+        // Waiting for the data is deferred to the next read.
+        self.pos = std::cmp::min(pos, self.length as u64) as usize;
+        Ok(self.pos as u64)
     }
 }
 
-/// NOTE: May block current threads.
+/// NOTE: Blocks until the requested data has been downloaded.
 impl std::io::Read for FiniteStream {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-        let n_buf = buf.len();
-        // fill buffer more if required.
-        if self.pos == self.bytes.len() && self.bytes.len() < self.length {
-            if let Some(res) = futures::executor::block_on(self.stream.next()) {
-                match res {
-                    Ok(bytes) => {
-                        self.bytes.extend_from_slice(bytes.as_ref());
-                    }
-                    Err(err) => {
-                        return Err(std::io::Error::new(std::io::ErrorKind::Other, err));
-                    }
-                }
-            }
+        if buf.is_empty() || self.pos >= self.length {
+            return Ok(0);
+        }
+        let state = &self.download.state;
+        let pos = self.pos;
+        let (shared, wait_result) = state
+            .cond
+            .wait_timeout_while(state.shared.lock().unwrap(), self.read_timeout, |shared| {
+                shared.buffer.len() <= pos
+                    && !shared.complete
+                    && !shared.cancelled
+                    && shared.error.is_none()
+            })
+            .unwrap();
+
+        if shared.buffer.len() > pos {
+            let n_read = shared.buffer.read_at(pos, buf)?;
+            self.pos += n_read;
+            Ok(n_read)
+        } else if let Some((kind, ref msg)) = shared.error {
+            Err(Error::new(kind, msg.clone()))
+        } else if wait_result.timed_out() {
+            Err(Error::new(
+                ErrorKind::TimedOut,
+                "Timed out waiting for downloaded data",
+            ))
+        } else {
+            // Complete or cancelled.
+            Ok(0)
         }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Seek};
+
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    fn test_stream(runtime: &Runtime, data: &[u8], chunk_size: usize) -> FiniteStream {
+        let chunks: Vec<Result<Bytes, reqwest::Error>> = data
+            .chunks(chunk_size)
+            .map(|chunk| Ok(Bytes::from(chunk.to_vec())))
+            .collect();
+        let config = BufferConfig {
+            window_size: 16,
+            ..BufferConfig::default()
+        };
+        runtime.enter(|| FiniteStream::new(data.len(), stream::iter(chunks), &config).unwrap())
+    }
+
+    #[test]
+    fn memory_window_is_bounded() {
+        let data: Vec<u8> = (0..1000).map(|x| (x % 251) as u8).collect();
+        let runtime = Runtime::new().unwrap();
+        let mut stream = test_stream(&runtime, &data, 7);
+        let mut read = Vec::new();
+        stream.read_to_end(&mut read).unwrap();
+
+        assert_eq!(read, data);
+        let shared = stream.download.state.shared.lock().unwrap();
+        assert!(shared.buffer.window.len() <= 2 * 16);
+    }
 
-        let n_remaining_available = self.bytes.len() - self.pos;
-        let n_to_read = std::cmp::min(n_buf, n_remaining_available);
+    #[test]
+    fn backward_seeks_are_served_from_spill_file() {
+        let data: Vec<u8> = (0..1000).map(|x| (x % 251) as u8).collect();
+        let runtime = Runtime::new().unwrap();
+        let mut stream = test_stream(&runtime, &data, 10);
+        stream.seek(SeekFrom::End(-100)).unwrap();
+        let mut tail = Vec::new();
+        stream.read_to_end(&mut tail).unwrap();
+        assert_eq!(&tail[..], &data[900..]);
 
-        let slice: &[u8] = self.bytes.as_ref();
-        buf[0..n_to_read].copy_from_slice(&slice[self.pos..self.pos + n_to_read]);
-        self.pos += n_to_read;
+        stream.seek(SeekFrom::Start(3)).unwrap();
+        let mut head = [0u8; 50];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(&head[..], &data[3..53]);
+    }
 
-        Ok(n_to_read)
+    #[test]
+    fn cancelled_download_ends_stream() {
+        let runtime = Runtime::new().unwrap();
+        let config = BufferConfig::default();
+        let mut stream = runtime.enter(|| {
+            FiniteStream::new(
+                100,
+                stream::pending::<Result<Bytes, reqwest::Error>>(),
+                &config,
+            )
+            .unwrap()
+        });
+        let download = stream.download_handle();
+        assert_eq!(
+            download.progress(),
+            DownloadProgress {
+                downloaded: 0,
+                total: 100
+            }
+        );
+        download.cancel();
+        let mut buf = [0u8; 10];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
     }
 }
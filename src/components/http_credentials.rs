@@ -0,0 +1,573 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::Ipv6Addr;
+use std::path::{Path, PathBuf};
+
+use failure::Fallible;
+use reqwest::{self, header, redirect, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use slog_scope::{info, warn};
+use url::Url;
+
+pub use err::*;
+
+const MAX_REDIRECTS: usize = 10;
+
+/// A secret is either given inline or read from a file, e.g. `{"file": "/etc/rustberry/token"}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Secret {
+    Inline(String),
+    File { file: PathBuf },
+}
+
+impl Secret {
+    fn resolve(&self) -> Result<String, Error> {
+        match self {
+            Secret::Inline(secret) => Ok(secret.clone()),
+            Secret::File { file } => fs::read_to_string(file)
+                .map(|secret| secret.trim_end().to_string())
+                .map_err(|err| Error::SecretFile(file.clone(), err)),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum AuthSpec {
+    Basic { username: String, password: Secret },
+    Bearer { token: Secret },
+    Headers { headers: HashMap<String, Secret> },
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct EntrySpec {
+    host: String,
+    auth: AuthSpec,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum Auth {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+    Headers { headers: Vec<(String, String)> },
+}
+
+// Never print secrets.
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Auth::Basic { username, .. } => write!(f, "Basic {{ username = {} }}", username),
+            Auth::Bearer { .. } => write!(f, "Bearer"),
+            Auth::Headers { headers } => {
+                let names: Vec<&str> = headers.iter().map(|(name, _)| name.as_str()).collect();
+                write!(f, "Headers {{ names = {:?} }}", names)
+            }
+        }
+    }
+}
+
+impl Auth {
+    fn resolve(spec: &AuthSpec) -> Result<Self, Error> {
+        match spec {
+            AuthSpec::Basic { username, password } => Ok(Auth::Basic {
+                username: username.clone(),
+                password: password.resolve()?,
+            }),
+            AuthSpec::Bearer { token } => Ok(Auth::Bearer {
+                token: token.resolve()?,
+            }),
+            AuthSpec::Headers { headers } => {
+                let mut resolved = Vec::new();
+                for (name, value) in headers {
+                    resolved.push((name.clone(), value.resolve()?));
+                }
+                Ok(Auth::Headers { headers: resolved })
+            }
+        }
+    }
+
+    fn apply(&self, builder: RequestBuilder) -> RequestBuilder {
+        match self {
+            Auth::Basic { username, password } => builder.basic_auth(username, Some(password)),
+            Auth::Bearer { token } => builder.bearer_auth(token),
+            Auth::Headers { headers } => headers.iter().fold(builder, |builder, (name, value)| {
+                builder.header(name.as_str(), value.as_str())
+            }),
+        }
+    }
+}
+
+/// Host pattern of a credential entry. Supports exact host names (`media.example.org`),
+/// bracketed IPv6 addresses (`[::1]`), an optional port (`media.example.org:8443`),
+/// subdomain wildcards (`*.example.org`) and `*` for every host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HostPattern {
+    /// Empty for `*`.
+    host: String,
+    wildcard: bool,
+    port: Option<u16>,
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> Result<Self, Error> {
+        let pattern = pattern.trim().to_lowercase();
+        let invalid = || Error::InvalidHostPattern(pattern.clone());
+        // IPv6 addresses contain colons themselves, the port follows the closing bracket.
+        let port_separator = if pattern.starts_with('[') {
+            let end = pattern.find(']').ok_or_else(invalid)?;
+            match &pattern[end + 1..] {
+                "" => None,
+                rest if rest.starts_with(':') => Some(end + 1),
+                _ => return Err(invalid()),
+            }
+        } else {
+            pattern.rfind(':')
+        };
+        let (host, port) = match port_separator {
+            Some(idx) => {
+                let port = pattern[idx + 1..].parse::<u16>().map_err(|_| invalid())?;
+                (pattern[..idx].to_string(), Some(port))
+            }
+            None => (pattern.clone(), None),
+        };
+        if host == "*" {
+            return Ok(HostPattern {
+                host: String::new(),
+                wildcard: true,
+                port,
+            });
+        }
+        let (host, wildcard) = match host.strip_prefix("*.") {
+            Some(domain) => (domain.to_string(), true),
+            None => (host, false),
+        };
+        if host.starts_with('[') && host.ends_with(']') && !wildcard {
+            // Same notation as the host of a parsed URL.
+            let addr: Ipv6Addr = host[1..host.len() - 1].parse().map_err(|_| invalid())?;
+            return Ok(HostPattern {
+                host: format!("[{}]", addr),
+                wildcard,
+                port,
+            });
+        }
+        if host.is_empty() || host.contains(|c| "*/:[]".contains(c)) {
+            return Err(invalid());
+        }
+        Ok(HostPattern {
+            host,
+            wildcard,
+            port,
+        })
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return false,
+        };
+        if let Some(port) = self.port {
+            if url.port_or_known_default() != Some(port) {
+                return false;
+            }
+        }
+        if self.wildcard && self.host.is_empty() {
+            true
+        } else if self.wildcard {
+            host.ends_with(&format!(".{}", self.host))
+        } else {
+            host == self.host
+        }
+    }
+}
+
+impl std::fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.wildcard, self.host.is_empty()) {
+            (true, true) => write!(f, "*")?,
+            (true, false) => write!(f, "*.{}", self.host)?,
+            (false, _) => write!(f, "{}", self.host)?,
+        }
+        match self.port {
+            Some(port) => write!(f, ":{}", port),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Table of credentials, each of which is only sent to the hosts it is configured for.
+/// Credentials are only sent via HTTPS, unless plain HTTP has been allowed explicitly.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    entries: Vec<(HostPattern, Auth)>,
+    allow_http: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct EnvConfig {
+    http_player_credentials_file: Option<PathBuf>,
+    http_player_username: Option<String>,
+    http_player_password: Option<String>,
+    http_player_host: Option<String>,
+    #[serde(default)]
+    http_player_allow_insecure_credentials: bool,
+}
+
+impl Credentials {
+    /// Loads the credential table from the JSON file referenced by `HTTP_PLAYER_CREDENTIALS_FILE`.
+    /// The legacy `HTTP_PLAYER_USERNAME`/`HTTP_PLAYER_PASSWORD` pair is used for
+    /// `HTTP_PLAYER_HOST`, which is required. Sending credentials via plain HTTP requires
+    /// `HTTP_PLAYER_ALLOW_INSECURE_CREDENTIALS=true`.
+    pub fn new_from_env() -> Fallible<Self> {
+        let config: EnvConfig = envy::from_env()?;
+        let credentials = Self::from_config(config)?;
+        info!(
+            "Loaded {} HTTP credential entries",
+            credentials.entries.len()
+        );
+        Ok(credentials)
+    }
+
+    fn from_config(config: EnvConfig) -> Result<Self, Error> {
+        let mut credentials = match config.http_player_credentials_file {
+            Some(ref path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        if let (Some(username), Some(password)) =
+            (config.http_player_username, config.http_player_password)
+        {
+            let host = config.http_player_host.ok_or(Error::MissingHost)?;
+            credentials.entries.push((
+                HostPattern::parse(&host)?,
+                Auth::Basic { username, password },
+            ));
+        }
+        credentials.allow_http = config.http_player_allow_insecure_credentials;
+        credentials.validate()?;
+        Ok(credentials)
+    }
+
+    /// Rejects entries for port 80, which would only be used via plain HTTP, unless that has
+    /// been allowed.
+    fn validate(&self) -> Result<(), Error> {
+        if self.allow_http {
+            return Ok(());
+        }
+        match self
+            .entries
+            .iter()
+            .find(|(pattern, _)| pattern.port == Some(80))
+        {
+            Some((pattern, _)) => Err(Error::InsecureHost(pattern.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Allows sending credentials via plain HTTP.
+    pub fn allow_http(self, allow_http: bool) -> Self {
+        Credentials { allow_http, ..self }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let content =
+            fs::read_to_string(path).map_err(|err| Error::SecretFile(path.to_path_buf(), err))?;
+        Self::from_json(&content)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let specs: Vec<EntrySpec> = serde_json::from_str(json)?;
+        let mut entries = Vec::new();
+        for spec in specs.iter() {
+            entries.push((HostPattern::parse(&spec.host)?, Auth::resolve(&spec.auth)?));
+        }
+        Ok(Credentials {
+            entries,
+            allow_http: false,
+        })
+    }
+
+    /// Returns the credentials to use for the given URL, if any. The first matching entry wins.
+    pub fn lookup(&self, url: &Url) -> Option<&Auth> {
+        let auth = self
+            .entries
+            .iter()
+            .find(|(pattern, _)| pattern.matches(url))
+            .map(|(_, auth)| auth)?;
+        if url.scheme() != "https" && !self.allow_http {
+            warn!(
+                "Not sending credentials to {} via plain HTTP",
+                url.host_str().unwrap_or_default()
+            );
+            return None;
+        }
+        Some(auth)
+    }
+}
+
+/// HTTP client attaching per-host credentials. Redirects are followed manually, so that
+/// credentials are re-evaluated for every hop and never leak to a different host.
+#[derive(Debug, Clone)]
+pub struct AuthenticatingClient {
+    http_client: reqwest::Client,
+    credentials: Credentials,
+}
+
+impl AuthenticatingClient {
+    pub fn new(credentials: Credentials) -> Result<Self, Error> {
+        let http_client = reqwest::Client::builder()
+            .redirect(redirect::Policy::none())
+            .build()?;
+        Ok(AuthenticatingClient {
+            http_client,
+            credentials,
+        })
+    }
+
+    pub fn get(&self, url: &Url) -> RequestBuilder {
+        let builder = self.http_client.get(url.clone());
+        match self.credentials.lookup(url) {
+            Some(auth) => auth.apply(builder),
+            None => builder,
+        }
+    }
+
+    /// Sends a GET request to `url`, following redirects.
+    pub async fn fetch(&self, url: &str) -> Result<Response, Error> {
+        self.fetch_with(url, |builder| builder).await
+    }
+
+    /// Like `fetch`, but allows adding request headers, which are repeated on every hop.
+    pub async fn fetch_with<F>(&self, url: &str, customize: F) -> Result<Response, Error>
+    where
+        F: Fn(RequestBuilder) -> RequestBuilder,
+    {
+        let mut url = Url::parse(url)?;
+        for _ in 0..MAX_REDIRECTS {
+            let response = customize(self.get(&url)).send().await?;
+            if !is_redirect(response.status()) {
+                return Ok(response);
+            }
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or(Error::InvalidRedirect)?;
+            url = url.join(location)?;
+        }
+        Err(Error::TooManyRedirects)
+    }
+}
+
+fn is_redirect(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::SEE_OTHER
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT
+    )
+}
+
+pub mod err {
+    use std::fmt::{self, Display};
+    use std::path::PathBuf;
+
+    #[derive(Debug)]
+    pub enum Error {
+        SecretFile(PathBuf, std::io::Error),
+        Json(serde_json::Error),
+        InvalidHostPattern(String),
+        /// `HTTP_PLAYER_USERNAME`/`HTTP_PLAYER_PASSWORD` given without `HTTP_PLAYER_HOST`.
+        MissingHost,
+        /// Credentials for the host would only be sent via plain HTTP.
+        InsecureHost(String),
+        Url(url::ParseError),
+        Http(reqwest::Error),
+        InvalidRedirect,
+        TooManyRedirects,
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::SecretFile(path, err) => {
+                    write!(f, "Failed to read credentials from {:?}: {}", path, err)
+                }
+                Error::Json(err) => write!(f, "Invalid HTTP credentials configuration: {}", err),
+                Error::InvalidHostPattern(pattern) => {
+                    write!(f, "Invalid host pattern in HTTP credentials: {}", pattern)
+                }
+                Error::MissingHost => write!(
+                    f,
+                    "HTTP_PLAYER_HOST is required for HTTP_PLAYER_USERNAME/HTTP_PLAYER_PASSWORD"
+                ),
+                Error::InsecureHost(host) => write!(
+                    f,
+                    "Credentials for {} are only sent via plain HTTP, which requires HTTP_PLAYER_ALLOW_INSECURE_CREDENTIALS=true",
+                    host
+                ),
+                Error::Url(err) => write!(f, "Invalid URL: {}", err),
+                Error::Http(err) => write!(f, "HTTP Error {}", err),
+                Error::InvalidRedirect => write!(f, "Received redirect without valid Location"),
+                Error::TooManyRedirects => write!(f, "Too many redirects"),
+            }
+        }
+    }
+
+    impl From<serde_json::Error> for Error {
+        fn from(err: serde_json::Error) -> Self {
+            Error::Json(err)
+        }
+    }
+
+    impl From<url::ParseError> for Error {
+        fn from(err: url::ParseError) -> Self {
+            Error::Url(err)
+        }
+    }
+
+    impl From<reqwest::Error> for Error {
+        fn from(err: reqwest::Error) -> Self {
+            Error::Http(err)
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn credentials_are_only_used_for_matching_hosts() {
+        let credentials = Credentials::from_json(
+            r#"[
+                {"host": "media.example.org", "auth": {"type": "basic", "username": "kid", "password": "secret"}},
+                {"host": "*.cdn.example.com:8443", "auth": {"type": "bearer", "token": "abc"}},
+                {"host": "api.example.net", "auth": {"type": "headers", "headers": {"X-Api-Key": "key"}}}
+            ]"#,
+        )
+        .unwrap();
+        let lookup = |url: &str| credentials.lookup(&Url::parse(url).unwrap()).cloned();
+
+        assert_eq!(
+            lookup("https://media.example.org/audio.mp3"),
+            Some(Auth::Basic {
+                username: "kid".to_string(),
+                password: "secret".to_string()
+            })
+        );
+        assert_eq!(lookup("https://other.media.example.org/audio.mp3"), None);
+        assert_eq!(lookup("https://evil.org/media.example.org"), None);
+        assert_eq!(
+            lookup("https://eu.cdn.example.com:8443/a.mp3"),
+            Some(Auth::Bearer {
+                token: "abc".to_string()
+            })
+        );
+        assert_eq!(lookup("https://eu.cdn.example.com/a.mp3"), None);
+        assert_eq!(lookup("https://cdn.example.com:8443/a.mp3"), None);
+        assert_eq!(
+            lookup("https://API.example.net/feed"),
+            Some(Auth::Headers {
+                headers: vec![("X-Api-Key".to_string(), "key".to_string())]
+            })
+        );
+    }
+
+    #[test]
+    fn plain_http_requires_opt_in() {
+        let credentials = Credentials::from_json(
+            r#"[{"host": "media.example.org", "auth": {"type": "bearer", "token": "abc"}}]"#,
+        )
+        .unwrap();
+        let url = Url::parse("http://media.example.org/audio.mp3").unwrap();
+        assert_eq!(credentials.lookup(&url), None);
+        let credentials = credentials.allow_http(true);
+        assert_eq!(
+            credentials.lookup(&url),
+            Some(&Auth::Bearer {
+                token: "abc".to_string()
+            })
+        );
+    }
+
+    fn legacy_config(host: Option<&str>, allow_insecure: bool) -> EnvConfig {
+        EnvConfig {
+            http_player_credentials_file: None,
+            http_player_username: Some("kid".to_string()),
+            http_player_password: Some("secret".to_string()),
+            http_player_host: host.map(str::to_string),
+            http_player_allow_insecure_credentials: allow_insecure,
+        }
+    }
+
+    #[test]
+    fn legacy_credentials_require_host() {
+        match Credentials::from_config(legacy_config(None, false)) {
+            Err(Error::MissingHost) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        let credentials =
+            Credentials::from_config(legacy_config(Some("example.org"), false)).unwrap();
+        assert!(credentials
+            .lookup(&Url::parse("https://example.org/a.mp3").unwrap())
+            .is_some());
+        assert!(credentials
+            .lookup(&Url::parse("https://evil.org/a.mp3").unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn http_only_hosts_require_opt_in() {
+        match Credentials::from_config(legacy_config(Some("*.Example.org:80"), false)) {
+            Err(Error::InsecureHost(host)) => assert_eq!(host, "*.example.org:80"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        let credentials =
+            Credentials::from_config(legacy_config(Some("*.example.org:80"), true)).unwrap();
+        assert!(credentials
+            .lookup(&Url::parse("http://media.example.org/a.mp3").unwrap())
+            .is_some());
+    }
+
+    #[test]
+    fn host_patterns_support_ipv6_and_any_host() {
+        let pattern = HostPattern::parse("[::1]:8080").unwrap();
+        assert_eq!(pattern.host, "[::1]");
+        assert_eq!(pattern.port, Some(8080));
+        assert!(pattern.matches(&Url::parse("https://[::1]:8080/a.mp3").unwrap()));
+        assert!(!pattern.matches(&Url::parse("https://[::1]:8081/a.mp3").unwrap()));
+        let pattern = HostPattern::parse("[2001:DB8:0::1]").unwrap();
+        assert_eq!(pattern.port, None);
+        assert!(pattern.matches(&Url::parse("https://[2001:db8::1]/a.mp3").unwrap()));
+        for invalid in &["::1", "[::1", "[::1]8080", "[example.org]", "*.[::1]"] {
+            assert!(HostPattern::parse(invalid).is_err(), "{}", invalid);
+        }
+
+        let any = HostPattern::parse("*").unwrap();
+        assert!(any.matches(&Url::parse("https://example.org/a.mp3").unwrap()));
+        assert!(any.matches(&Url::parse("https://[::1]/a.mp3").unwrap()));
+    }
+
+    #[test]
+    fn secrets_can_be_read_from_files() {
+        let path = std::env::temp_dir().join("rustberry-http-credentials-test-secret");
+        fs::write(&path, "file-secret\n").unwrap();
+        let json = format!(
+            r#"[{{"host": "example.org", "auth": {{"type": "bearer", "token": {{"file": {:?}}}}}}}]"#,
+            path
+        );
+        let credentials = Credentials::from_json(&json).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(
+            credentials.lookup(&Url::parse("https://example.org/").unwrap()),
+            Some(&Auth::Bearer {
+                token: "file-secret".to_string()
+            })
+        );
+    }
+}
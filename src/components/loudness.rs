@@ -0,0 +1,578 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+use slog_scope::{error, info};
+
+pub use err::*;
+
+/// Loudness which normalized playback aims for, in dBFS. This corresponds to the
+/// ReplayGain 2.0 reference level of -18 LUFS.
+pub const REFERENCE_LEVEL_DB: f32 = -18.0;
+
+/// R128 gain tags are relative to -23 LUFS, ReplayGain tags to -18 LUFS.
+const R128_TO_REPLAYGAIN_DB: f32 = 5.0;
+
+/// Only the beginning of a resource is inspected for tags.
+const MAX_TAG_SIZE: u64 = 1024 * 1024;
+
+/// FLAC metadata block type carrying the tags.
+const FLAC_VORBIS_COMMENT: u8 = 4;
+
+/// Converts a gain in dB into the linear factor applied to samples.
+pub fn gain_to_factor(gain_db: f32) -> f32 {
+    10f32.powf(gain_db / 20.0)
+}
+
+/// Reads the ReplayGain track gain (in dB) from ID3v2 TXXX frames or FLAC Vorbis comments.
+/// R128 gains are converted to the ReplayGain reference level. The reader is rewound to the
+/// start afterwards, also if the tags are invalid, such that it can be passed on to the decoder.
+pub fn read_tagged_gain<R: Read + Seek>(reader: &mut R) -> Result<Option<f32>, Error> {
+    let gain = read_gain(reader);
+    reader.seek(SeekFrom::Start(0))?;
+    gain
+}
+
+fn read_gain<R: Read + Seek>(reader: &mut R) -> Result<Option<f32>, Error> {
+    let mut magic = [0u8; 4];
+    match reader.read_exact(&mut magic) {
+        Ok(()) if &magic[..3] == b"ID3" => read_id3v2_gain(reader, magic[3]),
+        Ok(()) if &magic == b"fLaC" => read_flac_gain(reader),
+        Ok(()) => Ok(None),
+        Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn read_id3v2_gain<R: Read>(reader: &mut R, version: u8) -> Result<Option<f32>, Error> {
+    // Remaining header: revision, flags, syncsafe size.
+    let mut header = [0u8; 6];
+    reader.read_exact(&mut header)?;
+    if version != 3 && version != 4 {
+        return Ok(None);
+    }
+    let flags = header[1];
+    let size = syncsafe(&header[2..6]) as u64;
+    let mut tag = Vec::new();
+    reader
+        .take(std::cmp::min(size, MAX_TAG_SIZE))
+        .read_to_end(&mut tag)?;
+
+    let mut pos = 0;
+    if flags & 0x40 != 0 && tag.len() >= 4 {
+        // Skip extended header.
+        pos = if version == 4 {
+            syncsafe(&tag[0..4]) as usize
+        } else {
+            (u32::from_be_bytes([tag[0], tag[1], tag[2], tag[3]]) as usize).saturating_add(4)
+        };
+    }
+
+    let mut gains = Tags::default();
+    while pos + 10 <= tag.len() {
+        let id = &tag[pos..pos + 4];
+        if id[0] == 0 {
+            // Padding.
+            break;
+        }
+        let frame_size = if version == 4 {
+            syncsafe(&tag[pos + 4..pos + 8])
+        } else {
+            u32::from_be_bytes([tag[pos + 4], tag[pos + 5], tag[pos + 6], tag[pos + 7]])
+        } as usize;
+        let body_start = pos + 10;
+        // Corrupt frame sizes must not overflow on 32 bit platforms.
+        let body_end = std::cmp::min(body_start.saturating_add(frame_size), tag.len());
+        if id == b"TXXX" && body_end > body_start {
+            let body = &tag[body_start..body_end];
+            let mut fields = decode_id3_text(body[0], &body[1..]).into_iter();
+            if let (Some(key), Some(value)) = (fields.next(), fields.next()) {
+                gains.insert(&key, &value);
+            }
+        }
+        pos = body_end;
+    }
+    Ok(gains.track_gain())
+}
+
+fn read_flac_gain<R: Read + Seek>(reader: &mut R) -> Result<Option<f32>, Error> {
+    loop {
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header)?;
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+
+        if block_type == FLAC_VORBIS_COMMENT {
+            let mut block = Vec::new();
+            reader
+                .take(std::cmp::min(length, MAX_TAG_SIZE))
+                .read_to_end(&mut block)?;
+            return Ok(parse_vorbis_comments(&block).track_gain());
+        }
+        if is_last {
+            return Ok(None);
+        }
+        // Other blocks, e.g. embedded pictures, can be large and are not read.
+        reader.seek(SeekFrom::Current(length as i64))?;
+    }
+}
+
+fn parse_vorbis_comments(block: &[u8]) -> Tags {
+    fn read_u32(block: &[u8], pos: usize) -> Option<usize> {
+        let bytes = block.get(pos..pos.checked_add(4)?)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    let mut tags = Tags::default();
+    let vendor_length = match read_u32(block, 0) {
+        Some(length) => length,
+        None => return tags,
+    };
+    let mut pos = vendor_length.saturating_add(4);
+    let n_comments = read_u32(block, pos).unwrap_or(0);
+    pos = pos.saturating_add(4);
+    for _ in 0..n_comments {
+        let length = match read_u32(block, pos) {
+            Some(length) => length,
+            None => break,
+        };
+        pos += 4;
+        let comment = match block.get(pos..pos.saturating_add(length)) {
+            Some(comment) => String::from_utf8_lossy(comment),
+            None => break,
+        };
+        let mut fields = comment.splitn(2, '=');
+        if let (Some(key), Some(value)) = (fields.next(), fields.next()) {
+            tags.insert(key, value);
+        }
+        pos += length;
+    }
+    tags
+}
+
+/// Decodes the null-separated strings of an ID3v2 text frame.
+fn decode_id3_text(encoding: u8, data: &[u8]) -> Vec<String> {
+    match encoding {
+        // UTF-16 with BOM or UTF-16BE.
+        1 | 2 => {
+            let mut big_endian = encoding == 2;
+            let mut fields = Vec::new();
+            for field in data.chunks(2).collect::<Vec<_>>().split(|c| c == &[0, 0]) {
+                let mut units = Vec::new();
+                for chunk in field.iter().filter(|c| c.len() == 2) {
+                    match chunk {
+                        [0xfe, 0xff] => big_endian = true,
+                        [0xff, 0xfe] => big_endian = false,
+                        [a, b] if big_endian => units.push(u16::from_be_bytes([*a, *b])),
+                        [a, b] => units.push(u16::from_le_bytes([*a, *b])),
+                        _ => {}
+                    }
+                }
+                fields.push(String::from_utf16_lossy(&units));
+            }
+            fields
+        }
+        // ISO-8859-1 or UTF-8.
+        _ => data
+            .split(|b| *b == 0)
+            .map(|field| {
+                if encoding == 0 {
+                    field.iter().map(|b| *b as char).collect()
+                } else {
+                    String::from_utf8_lossy(field).into_owned()
+                }
+            })
+            .collect(),
+    }
+}
+
+fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0, |acc, byte| (acc << 7) | (*byte as u32 & 0x7f))
+}
+
+#[derive(Debug, Default)]
+struct Tags {
+    replaygain: Option<f32>,
+    r128: Option<f32>,
+}
+
+impl Tags {
+    fn insert(&mut self, key: &str, value: &str) {
+        let value = value.trim();
+        match key.trim().to_uppercase().as_str() {
+            "REPLAYGAIN_TRACK_GAIN" => {
+                let value = value.trim_end_matches(|c: char| c.is_alphabetic()).trim();
+                self.replaygain = value.parse().ok();
+            }
+            "R128_TRACK_GAIN" => {
+                // Q7.8 fixed point number.
+                self.r128 = value.parse::<i32>().ok().map(|gain| gain as f32 / 256.0);
+            }
+            _ => {}
+        }
+    }
+
+    fn track_gain(&self) -> Option<f32> {
+        self.replaygain
+            .or_else(|| self.r128.map(|gain| gain + R128_TO_REPLAYGAIN_DB))
+    }
+}
+
+/// Locally persisted gains of resources without loudness tags, keyed by resource URL.
+#[derive(Debug)]
+pub struct GainCache {
+    path: PathBuf,
+    gains: HashMap<String, f32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct GainCacheFile {
+    gains: HashMap<String, f32>,
+}
+
+impl GainCache {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let gains = if path.exists() {
+            let content = fs::read_to_string(path)?;
+            serde_json::from_str::<GainCacheFile>(&content)?.gains
+        } else {
+            HashMap::new()
+        };
+        Ok(GainCache {
+            path: path.to_path_buf(),
+            gains,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<f32> {
+        self.gains.get(key).cloned()
+    }
+
+    pub fn insert(&mut self, key: &str, gain_db: f32) -> Result<(), Error> {
+        self.gains.insert(key.to_string(), gain_db);
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = GainCacheFile {
+            gains: self.gains.clone(),
+        };
+        fs::write(&self.path, serde_json::to_string(&file)?)?;
+        Ok(())
+    }
+}
+
+/// Applies loudness normalization to decoded sources.
+pub struct Normalizer {
+    max_gain_db: f32,
+    cache: Arc<Mutex<GainCache>>,
+}
+
+impl Normalizer {
+    pub fn new(cache: GainCache, max_gain_db: f32) -> Self {
+        Normalizer {
+            max_gain_db,
+            cache: Arc::new(Mutex::new(cache)),
+        }
+    }
+
+    /// Amplifies the source by the tagged gain or, lacking tags, by the gain measured during an
+    /// earlier playback. Unknown sources are played unmodified while their loudness is measured.
+    pub fn normalize<S>(
+        &self,
+        key: &str,
+        source: S,
+        tagged_gain: Option<f32>,
+    ) -> Box<dyn Source<Item = i16> + Send>
+    where
+        S: Source<Item = i16> + Send + 'static,
+    {
+        let gain = tagged_gain.or_else(|| self.cache.lock().unwrap().get(key));
+        match gain {
+            Some(gain) => {
+                let gain = gain.min(self.max_gain_db);
+                info!("Applying gain of {:.2} dB to {}", gain, key);
+                Box::new(source.amplify(gain_to_factor(gain)))
+            }
+            None => {
+                let cache = self.cache.clone();
+                let key = key.to_string();
+                Box::new(LoudnessMeter::new(source, move |gain| {
+                    info!("Measured gain of {:.2} dB for {}", gain, key);
+                    if let Err(err) = cache.lock().unwrap().insert(&key, gain) {
+                        error!("Failed to cache gain for {}: {}", key, err);
+                    }
+                }))
+            }
+        }
+    }
+}
+
+/// Length of the blocks whose mean square is computed.
+const BLOCK_DURATION: Duration = Duration::from_millis(400);
+/// Blocks quieter than this are considered silence and do not contribute to the loudness.
+const SILENCE_GATE_DB: f32 = -70.0;
+
+/// Passes samples through unmodified while measuring their loudness. Once the source is
+/// exhausted the gain required to reach the reference level is reported.
+///
+/// The measurement is an unweighted, silence gated RMS level and thereby only approximates
+/// the ITU-R BS.1770 loudness used by ReplayGain 2.0 taggers.
+pub struct LoudnessMeter<S, F> {
+    source: S,
+    on_complete: Option<F>,
+    block_sum: f64,
+    block_samples: usize,
+    total_sum: f64,
+    total_blocks: usize,
+}
+
+impl<S, F> LoudnessMeter<S, F>
+where
+    S: Source<Item = i16>,
+    F: FnOnce(f32),
+{
+    pub fn new(source: S, on_complete: F) -> Self {
+        LoudnessMeter {
+            source,
+            on_complete: Some(on_complete),
+            block_sum: 0.0,
+            block_samples: 0,
+            total_sum: 0.0,
+            total_blocks: 0,
+        }
+    }
+
+    fn block_size(&self) -> usize {
+        let samples_per_sec = self.source.sample_rate() as usize * self.source.channels() as usize;
+        std::cmp::max(
+            1,
+            samples_per_sec * BLOCK_DURATION.as_millis() as usize / 1000,
+        )
+    }
+
+    fn finish_block(&mut self) {
+        if self.block_samples == 0 {
+            return;
+        }
+        let mean_square = self.block_sum / self.block_samples as f64;
+        if to_db(mean_square) > SILENCE_GATE_DB {
+            self.total_sum += mean_square;
+            self.total_blocks += 1;
+        }
+        self.block_sum = 0.0;
+        self.block_samples = 0;
+    }
+
+    fn complete(&mut self) {
+        // A trailing partial block would be weighted like a complete one.
+        if self.total_blocks == 0 {
+            self.finish_block();
+        }
+        if self.total_blocks == 0 {
+            return;
+        }
+        if let Some(on_complete) = self.on_complete.take() {
+            let level = to_db(self.total_sum / self.total_blocks as f64);
+            on_complete(REFERENCE_LEVEL_DB - level);
+        }
+    }
+}
+
+fn to_db(mean_square: f64) -> f32 {
+    (10.0 * mean_square.max(1e-20).log10()) as f32
+}
+
+impl<S, F> Iterator for LoudnessMeter<S, F>
+where
+    S: Source<Item = i16>,
+    F: FnOnce(f32),
+{
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        match self.source.next() {
+            Some(sample) => {
+                let value = sample as f64 / i16::MAX as f64;
+                self.block_sum += value * value;
+                self.block_samples += 1;
+                if self.block_samples >= self.block_size() {
+                    self.finish_block();
+                }
+                Some(sample)
+            }
+            None => {
+                self.complete();
+                None
+            }
+        }
+    }
+}
+
+impl<S, F> Source for LoudnessMeter<S, F>
+where
+    S: Source<Item = i16>,
+    F: FnOnce(f32),
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+pub mod err {
+    use std::fmt::{self, Display};
+
+    #[derive(Debug)]
+    pub enum Error {
+        IO(std::io::Error),
+        Json(serde_json::Error),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::IO(err) => write!(f, "Loudness IO Error {}", err),
+                Error::Json(err) => write!(f, "Loudness gain cache JSON Error {}", err),
+            }
+        }
+    }
+
+    impl From<std::io::Error> for Error {
+        fn from(err: std::io::Error) -> Self {
+            Error::IO(err)
+        }
+    }
+
+    impl From<serde_json::Error> for Error {
+        fn from(err: serde_json::Error) -> Self {
+            Error::Json(err)
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::sync::mpsc;
+
+    use rodio::source::SineWave;
+
+    use super::*;
+
+    fn id3v2_txxx(key: &str, value: &str) -> Vec<u8> {
+        let mut body = vec![3u8];
+        body.extend_from_slice(key.as_bytes());
+        body.push(0);
+        body.extend_from_slice(value.as_bytes());
+
+        let mut frame = b"TXXX".to_vec();
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(&body);
+
+        let mut tag = b"ID3\x03\x00\x00".to_vec();
+        let size = frame.len() as u32;
+        tag.extend_from_slice(&[
+            (size >> 21) as u8 & 0x7f,
+            (size >> 14) as u8 & 0x7f,
+            (size >> 7) as u8 & 0x7f,
+            size as u8 & 0x7f,
+        ]);
+        tag.extend_from_slice(&frame);
+        tag.extend_from_slice(b"audio data");
+        tag
+    }
+
+    #[test]
+    fn gain_tags_are_read() {
+        let mut mp3 = Cursor::new(id3v2_txxx("replaygain_track_gain", "-6.50 dB"));
+        assert_eq!(read_tagged_gain(&mut mp3).unwrap(), Some(-6.5));
+        assert_eq!(mp3.position(), 0);
+
+        let comment = b"R128_TRACK_GAIN=-512";
+        let mut block = 4u32.to_le_bytes().to_vec();
+        block.extend_from_slice(b"test");
+        block.extend_from_slice(&1u32.to_le_bytes());
+        block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        block.extend_from_slice(comment);
+        let mut flac = b"fLaC".to_vec();
+        flac.push(0x80 | 4);
+        flac.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+        flac.extend_from_slice(&block);
+        assert_eq!(read_tagged_gain(&mut Cursor::new(flac)).unwrap(), Some(3.0));
+
+        let mut untagged = Cursor::new(b"RIFF....WAVE".to_vec());
+        assert_eq!(read_tagged_gain(&mut untagged).unwrap(), None);
+    }
+
+    #[test]
+    fn reader_is_rewound_after_invalid_tags() {
+        // Header announcing a tag, but truncated within the header.
+        let mut truncated = Cursor::new(b"ID3\x03\x00".to_vec());
+        assert!(read_tagged_gain(&mut truncated).is_err());
+        assert_eq!(truncated.position(), 0);
+
+        // Frame size beyond the tag and any address space, the frame ends with the tag.
+        let mut tag = id3v2_txxx("replaygain_track_gain", "-6.50 dB");
+        tag[14..18].copy_from_slice(&[0xff; 4]);
+        let mut corrupt = Cursor::new(tag);
+        assert_eq!(read_tagged_gain(&mut corrupt).unwrap(), Some(-6.5));
+        assert_eq!(corrupt.position(), 0);
+    }
+
+    #[test]
+    fn large_flac_blocks_are_skipped() {
+        let comment = b"REPLAYGAIN_TRACK_GAIN=-2.00 dB";
+        let mut comments = 4u32.to_le_bytes().to_vec();
+        comments.extend_from_slice(b"test");
+        comments.extend_from_slice(&1u32.to_le_bytes());
+        comments.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        comments.extend_from_slice(comment);
+
+        // Picture block larger than the tag size inspected otherwise.
+        let picture_length = 2 * MAX_TAG_SIZE as u32;
+        let mut flac = b"fLaC".to_vec();
+        flac.push(6);
+        flac.extend_from_slice(&picture_length.to_be_bytes()[1..]);
+        flac.resize(flac.len() + picture_length as usize, 0);
+        flac.push(0x80 | FLAC_VORBIS_COMMENT);
+        flac.extend_from_slice(&(comments.len() as u32).to_be_bytes()[1..]);
+        flac.extend_from_slice(&comments);
+        let mut flac = Cursor::new(flac);
+        assert_eq!(read_tagged_gain(&mut flac).unwrap(), Some(-2.0));
+        assert_eq!(flac.position(), 0);
+    }
+
+    #[test]
+    fn loudness_is_measured() {
+        let (tx, rx) = mpsc::channel();
+        // A sine wave with amplitude 0.1 has an RMS level of about -23 dBFS.
+        let source = SineWave::new(440)
+            .take_duration(Duration::from_secs(2))
+            .amplify(0.1)
+            .convert_samples::<i16>();
+        let meter = LoudnessMeter::new(source, move |gain| tx.send(gain).unwrap());
+        assert!(meter.count() >= 2 * 48000);
+
+        let gain = rx.recv().unwrap();
+        assert!((gain - 5.0).abs() < 0.1, "unexpected gain {}", gain);
+    }
+}
@@ -1,3 +1,13 @@
 pub mod access_token_provider;
+pub mod authorization_callback;
+pub mod ducking;
+pub mod equalizer;
 pub mod finite_stream;
+pub mod http_credentials;
+pub mod loudness;
+pub mod playlist;
+pub mod podcast;
 pub mod rfid;
+pub mod spotify_authorization;
+pub mod spotify_credentials;
+pub mod spotify_uri;
@@ -0,0 +1,81 @@
+use url::Url;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaylistItem {
+    pub url: String,
+    pub title: Option<String>,
+}
+
+/// Returns true if the URL refers to an M3U playlist, judging by its path.
+pub fn is_playlist_url(url: &Url) -> bool {
+    let path = url.path().to_lowercase();
+    path.ends_with(".m3u") || path.ends_with(".m3u8")
+}
+
+/// Parses an (extended) M3U playlist. Relative entries are resolved against the playlist URL.
+pub fn parse_m3u(playlist_url: &Url, content: &str) -> Result<Vec<PlaylistItem>, url::ParseError> {
+    let mut items = Vec::new();
+    let mut title = None;
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with("#EXTINF:") {
+            // Format: #EXTINF:<duration>,<title>
+            title = line
+                .split_once(',')
+                .map(|x| x.1)
+                .map(|title| title.trim().to_string())
+                .filter(|title| !title.is_empty());
+        } else if line.starts_with('#') {
+            continue;
+        } else {
+            let url = playlist_url.join(line)?;
+            items.push(PlaylistItem {
+                url: url.into_string(),
+                title: title.take(),
+            });
+        }
+    }
+
+    Ok(items)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn extended_m3u_is_parsed() {
+        let playlist_url = Url::parse("https://example.org/albums/kids/album.m3u").unwrap();
+        let content = "#EXTM3U\n\
+                       #EXTINF:123,Artist - First Song\n\
+                       01-first.mp3\n\
+                       \n\
+                       #EXTINF:-1,\n\
+                       /shared/02-second.mp3\n\
+                       https://cdn.example.org/03-third.mp3\n";
+        let items = parse_m3u(&playlist_url, content).unwrap();
+
+        assert!(is_playlist_url(&playlist_url));
+        assert_eq!(
+            items,
+            vec![
+                PlaylistItem {
+                    url: "https://example.org/albums/kids/01-first.mp3".to_string(),
+                    title: Some("Artist - First Song".to_string()),
+                },
+                PlaylistItem {
+                    url: "https://example.org/shared/02-second.mp3".to_string(),
+                    title: None,
+                },
+                PlaylistItem {
+                    url: "https://cdn.example.org/03-third.mp3".to_string(),
+                    title: None,
+                },
+            ]
+        );
+    }
+}
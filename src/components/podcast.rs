@@ -0,0 +1,424 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, FixedOffset};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde::{Deserialize, Serialize};
+
+pub use err::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Episode {
+    pub id: String,
+    pub title: Option<String>,
+    pub url: String,
+    pub published: Option<DateTime<FixedOffset>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum EpisodePolicy {
+    /// Play the newest episode which has not been played yet.
+    #[default]
+    Latest,
+    /// Play the oldest episode which has not been played yet.
+    OldestUnplayed,
+}
+
+/// Parses an RSS or Atom feed into the list of episodes carrying an audio enclosure.
+/// The returned episodes are sorted from newest to oldest. Episodes without publication
+/// date come last, in the order of the feed document.
+pub fn parse_feed(feed: &str) -> Result<Vec<Episode>, Error> {
+    let mut reader = Reader::from_str(feed);
+    reader.trim_text(true);
+
+    let mut episodes = Vec::new();
+    let mut current: Option<PartialEpisode> = None;
+    let mut current_field: Option<Field> = None;
+    let mut buf = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(ref e) => match e.local_name() {
+                b"item" | b"entry" => {
+                    current = Some(PartialEpisode::default());
+                }
+                b"link" => {
+                    if let Some(ref mut episode) = current {
+                        episode.handle_atom_link(e, &reader)?;
+                    }
+                }
+                b"enclosure" => {
+                    if let Some(ref mut episode) = current {
+                        episode.handle_rss_enclosure(e, &reader)?;
+                    }
+                }
+                name => {
+                    if current.is_some() {
+                        current_field = Field::from_tag(name);
+                    }
+                }
+            },
+            Event::Empty(ref e) => {
+                if let Some(ref mut episode) = current {
+                    match e.local_name() {
+                        b"enclosure" => episode.handle_rss_enclosure(e, &reader)?,
+                        b"link" => episode.handle_atom_link(e, &reader)?,
+                        _ => {}
+                    }
+                }
+            }
+            Event::Text(ref e) | Event::CData(ref e) => {
+                if let (Some(ref mut episode), Some(field)) = (&mut current, current_field) {
+                    let text = e.unescape_and_decode(&reader)?;
+                    episode.set(field, text);
+                }
+            }
+            Event::End(ref e) => match e.local_name() {
+                b"item" | b"entry" => {
+                    if let Some(episode) = current.take().and_then(PartialEpisode::finish) {
+                        episodes.push(episode);
+                    }
+                    current_field = None;
+                }
+                _ => {
+                    current_field = None;
+                }
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    // Stable sort, hence episodes without date keep their order. `None` is the smallest
+    // date, so that these come last.
+    episodes.sort_by_key(|e| Reverse(e.published));
+
+    Ok(episodes)
+}
+
+/// Picks the episode to play from a list of episodes sorted from newest to oldest.
+/// If every episode has been played already, the newest episode is picked.
+pub fn select_episode<'a>(
+    episodes: &'a [Episode],
+    played: &HashSet<String>,
+    policy: EpisodePolicy,
+) -> Option<&'a Episode> {
+    let mut unplayed = episodes.iter().filter(|e| !played.contains(&e.id));
+    let selected = match policy {
+        EpisodePolicy::Latest => unplayed.next(),
+        EpisodePolicy::OldestUnplayed => unplayed.next_back(),
+    };
+    selected.or_else(|| episodes.first())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Title,
+    Guid,
+    Id,
+    PubDate,
+    Published,
+    Updated,
+}
+
+impl Field {
+    fn from_tag(name: &[u8]) -> Option<Self> {
+        match name {
+            b"title" => Some(Field::Title),
+            b"guid" => Some(Field::Guid),
+            b"id" => Some(Field::Id),
+            b"pubDate" => Some(Field::PubDate),
+            b"published" => Some(Field::Published),
+            b"updated" => Some(Field::Updated),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PartialEpisode {
+    id: Option<String>,
+    title: Option<String>,
+    url: Option<String>,
+    published: Option<DateTime<FixedOffset>>,
+}
+
+impl PartialEpisode {
+    fn set(&mut self, field: Field, text: String) {
+        match field {
+            Field::Title => self.title = Some(text),
+            Field::Guid | Field::Id => self.id = Some(text),
+            Field::PubDate => {
+                self.published = DateTime::parse_from_rfc2822(&text).ok().or(self.published)
+            }
+            Field::Published => self.published = DateTime::parse_from_rfc3339(&text).ok(),
+            Field::Updated => {
+                if self.published.is_none() {
+                    self.published = DateTime::parse_from_rfc3339(&text).ok();
+                }
+            }
+        }
+    }
+
+    fn handle_rss_enclosure<B: std::io::BufRead>(
+        &mut self,
+        e: &BytesStart,
+        reader: &Reader<B>,
+    ) -> Result<(), Error> {
+        for attr in e.attributes() {
+            let attr = attr?;
+            if attr.key == b"url" {
+                self.url = Some(attr.unescape_and_decode_value(reader)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_atom_link<B: std::io::BufRead>(
+        &mut self,
+        e: &BytesStart,
+        reader: &Reader<B>,
+    ) -> Result<(), Error> {
+        let mut rel = None;
+        let mut href = None;
+        for attr in e.attributes() {
+            let attr = attr?;
+            match attr.key {
+                b"rel" => rel = Some(attr.unescape_and_decode_value(reader)?),
+                b"href" => href = Some(attr.unescape_and_decode_value(reader)?),
+                _ => {}
+            }
+        }
+        if rel.as_deref() == Some("enclosure") {
+            self.url = href;
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Option<Episode> {
+        let url = self.url?;
+        Some(Episode {
+            // Feeds without GUIDs are identified by their enclosure URL.
+            id: self.id.unwrap_or_else(|| url.clone()),
+            title: self.title,
+            url,
+            published: self.published,
+        })
+    }
+}
+
+/// Locally persisted set of played episodes, keyed by feed URL.
+#[derive(Debug)]
+pub struct PlayedEpisodes {
+    path: PathBuf,
+    feeds: HashMap<String, HashSet<String>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PlayedEpisodesFile {
+    feeds: HashMap<String, HashSet<String>>,
+}
+
+impl PlayedEpisodes {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let feeds = if path.exists() {
+            let content = fs::read_to_string(path)?;
+            serde_json::from_str::<PlayedEpisodesFile>(&content)?.feeds
+        } else {
+            HashMap::new()
+        };
+        Ok(PlayedEpisodes {
+            path: path.to_path_buf(),
+            feeds,
+        })
+    }
+
+    pub fn played(&self, feed_url: &str) -> HashSet<String> {
+        self.feeds.get(feed_url).cloned().unwrap_or_default()
+    }
+
+    pub fn mark_played(&mut self, feed_url: &str, episode_id: &str) -> Result<(), Error> {
+        let inserted = self
+            .feeds
+            .entry(feed_url.to_string())
+            .or_default()
+            .insert(episode_id.to_string());
+        if inserted {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = PlayedEpisodesFile {
+            feeds: self.feeds.clone(),
+        };
+        fs::write(&self.path, serde_json::to_string(&file)?)?;
+        Ok(())
+    }
+}
+
+pub mod err {
+    use std::fmt::{self, Display};
+
+    #[derive(Debug)]
+    pub enum Error {
+        Xml(quick_xml::Error),
+        IO(std::io::Error),
+        Json(serde_json::Error),
+        NoEpisodes,
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::Xml(err) => write!(f, "Podcast feed XML Error {}", err),
+                Error::IO(err) => write!(f, "Podcast IO Error {}", err),
+                Error::Json(err) => write!(f, "Podcast history JSON Error {}", err),
+                Error::NoEpisodes => write!(f, "Podcast feed does not contain any episodes"),
+            }
+        }
+    }
+
+    impl From<quick_xml::Error> for Error {
+        fn from(err: quick_xml::Error) -> Self {
+            Error::Xml(err)
+        }
+    }
+
+    impl From<std::io::Error> for Error {
+        fn from(err: std::io::Error) -> Self {
+            Error::IO(err)
+        }
+    }
+
+    impl From<serde_json::Error> for Error {
+        fn from(err: serde_json::Error) -> Self {
+            Error::Json(err)
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RSS_FEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0">
+  <channel>
+    <title>Kids Stories</title>
+    <item>
+      <title>Episode 1</title>
+      <guid>ep-1</guid>
+      <pubDate>Mon, 06 Apr 2020 08:00:00 +0000</pubDate>
+      <enclosure url="https://example.org/ep1.mp3" type="audio/mpeg" length="1"/>
+    </item>
+    <item>
+      <title>Episode 2 &amp; more</title>
+      <guid>ep-2</guid>
+      <pubDate>Mon, 13 Apr 2020 08:00:00 +0000</pubDate>
+      <enclosure url="https://example.org/ep2.mp3" type="audio/mpeg" length="1"/>
+    </item>
+    <item>
+      <title>Announcement without audio</title>
+      <guid>news</guid>
+    </item>
+  </channel>
+</rss>"#;
+
+    const ATOM_FEED: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Kids Stories</title>
+  <entry>
+    <title>Episode 2</title>
+    <id>urn:ep-2</id>
+    <published>2020-04-13T08:00:00Z</published>
+    <link rel="alternate" href="https://example.org/ep2.html"/>
+    <link rel="enclosure" href="https://example.org/ep2.mp3" type="audio/mpeg"/>
+  </entry>
+  <entry>
+    <title>Episode 1</title>
+    <id>urn:ep-1</id>
+    <published>2020-04-06T08:00:00Z</published>
+    <link rel="enclosure" href="https://example.org/ep1.mp3" type="audio/mpeg"/>
+  </entry>
+</feed>"#;
+
+    #[test]
+    fn rss_feed_is_parsed_newest_first() {
+        let episodes = parse_feed(RSS_FEED).unwrap();
+        let ids: Vec<&str> = episodes.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, vec!["ep-2", "ep-1"]);
+        assert_eq!(episodes[0].url, "https://example.org/ep2.mp3");
+        assert_eq!(episodes[0].title.as_deref(), Some("Episode 2 & more"));
+    }
+
+    #[test]
+    fn atom_feed_uses_enclosure_links() {
+        let episodes = parse_feed(ATOM_FEED).unwrap();
+        let urls: Vec<&str> = episodes.iter().map(|e| e.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            vec!["https://example.org/ep2.mp3", "https://example.org/ep1.mp3"]
+        );
+    }
+
+    #[test]
+    fn undated_episodes_come_last_in_feed_order() {
+        let feed = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0">
+  <channel>
+    <item>
+      <guid>undated-1</guid>
+      <enclosure url="https://example.org/u1.mp3" type="audio/mpeg" length="1"/>
+    </item>
+    <item>
+      <guid>ep-2020</guid>
+      <pubDate>Mon, 06 Apr 2020 08:00:00 +0000</pubDate>
+      <enclosure url="https://example.org/ep2020.mp3" type="audio/mpeg" length="1"/>
+    </item>
+    <item>
+      <guid>undated-2</guid>
+      <enclosure url="https://example.org/u2.mp3" type="audio/mpeg" length="1"/>
+    </item>
+    <item>
+      <guid>ep-2021</guid>
+      <pubDate>Tue, 06 Apr 2021 08:00:00 +0000</pubDate>
+      <enclosure url="https://example.org/ep2021.mp3" type="audio/mpeg" length="1"/>
+    </item>
+  </channel>
+</rss>"#;
+        let episodes = parse_feed(feed).unwrap();
+        let ids: Vec<&str> = episodes.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, vec!["ep-2021", "ep-2020", "undated-1", "undated-2"]);
+    }
+
+    #[test]
+    fn episode_selection_respects_policy_and_history() {
+        let episodes = parse_feed(RSS_FEED).unwrap();
+        let mut played = HashSet::new();
+
+        let latest = select_episode(&episodes, &played, EpisodePolicy::Latest).unwrap();
+        assert_eq!(latest.id, "ep-2");
+        let oldest = select_episode(&episodes, &played, EpisodePolicy::OldestUnplayed).unwrap();
+        assert_eq!(oldest.id, "ep-1");
+
+        played.insert("ep-2".to_string());
+        let latest = select_episode(&episodes, &played, EpisodePolicy::Latest).unwrap();
+        assert_eq!(latest.id, "ep-1");
+
+        played.insert("ep-1".to_string());
+        let fallback = select_episode(&episodes, &played, EpisodePolicy::OldestUnplayed).unwrap();
+        assert_eq!(fallback.id, "ep-2");
+    }
+}
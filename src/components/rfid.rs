@@ -80,7 +80,7 @@ impl RfidController {
         let mut mfrc522 = self.mfrc522.lock().unwrap();
         mfrc522.init().map_err(|err| {
             error!("Failed to initialize MFRC522");
-            std::io::Error::new(std::io::ErrorKind::Other, err)
+            std::io::Error::other(err)
         })?;
 
         mfrc522.new_card_present()?;
@@ -160,24 +160,24 @@ impl Write for TagWriter {
                         picc::Command::MfAuthKeyA,
                         DATA_BLOCKS[self.current_block as usize],
                         MIFARE_KEY_A,
-                        &(*self.uid),
+                        &self.uid,
                     )
                     .map_err(|err| {
                         // error!("Failed to authenticate RFID tag during writing: {:?}", err);
-                        std::io::Error::new(std::io::ErrorKind::Other, err)
+                        std::io::Error::other(err)
                     })?;
 
                 mfrc522
-                    .mifare_write(DATA_BLOCKS[self.current_block as usize], &block)
+                    .mifare_write(DATA_BLOCKS[self.current_block as usize], block)
                     .map_err(|err| {
                         // error!("Failed to write data block to RFID tag: {:?}", err);
-                        std::io::Error::new(std::io::ErrorKind::Other, err)
+                        std::io::Error::other(err)
                     })?;
 
                 self.current_block += 1;
             } else {
                 // Partial block.
-                self.buffered_data[0..block.len()].copy_from_slice(&block);
+                self.buffered_data[0..block.len()].copy_from_slice(block);
                 self.current_pos_in_buffered_data += block.len() as u8;
             }
         }
@@ -194,11 +194,11 @@ impl Write for TagWriter {
                     picc::Command::MfAuthKeyA,
                     DATA_BLOCKS[self.current_block as usize],
                     MIFARE_KEY_A,
-                    &(*self.uid),
+                    &self.uid,
                 )
                 .map_err(|err| {
                     // error!("Failed to authenticate RFID tag during flushing");
-                    std::io::Error::new(std::io::ErrorKind::Other, err)
+                    std::io::Error::other(err)
                 })?;
 
             let mut buffer: [u8; N_BLOCK_SIZE as usize] = [0; N_BLOCK_SIZE as usize];
@@ -209,7 +209,7 @@ impl Write for TagWriter {
                 .mifare_write(DATA_BLOCKS[self.current_block as usize], &buffer)
                 .map_err(|err| {
                     // error!("Failed to write data block to RFID tag during flushing");
-                    std::io::Error::new(std::io::ErrorKind::Other, err)
+                    std::io::Error::other(err)
                 })?;
             self.current_pos_in_buffered_data = 0;
             self.current_block += 1;
@@ -224,22 +224,20 @@ impl TagReader {
     pub fn read_string(&mut self) -> Result<String, std::io::Error> {
         let mut bytes: [u8; 1024] = [0; 1024];
         let string = rmp::decode::read_str(self, &mut bytes)
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))?;
+            .map_err(|err| std::io::Error::other(err.to_string()))?;
         Ok(string.to_string().clone())
     }
     pub fn tag_still_readable(&mut self) -> Result<(), std::io::Error> {
         let mut bytes: [u8; 1] = [0];
-        let _ = self
-            .read_exact(&mut bytes)
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))?;
+        self.read_exact(&mut bytes)
+            .map_err(|err| std::io::Error::other(err.to_string()))?;
         Ok(())
     }
 }
 
 impl TagWriter {
     pub fn write_string(&mut self, s: &str) -> Result<(), std::io::Error> {
-        rmp::encode::write_str(self, s)
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))?;
+        rmp::encode::write_str(self, s).map_err(|err| std::io::Error::other(err.to_string()))?;
         self.flush()
     }
 }
@@ -262,7 +260,7 @@ impl Read for TagReader {
             )
             .map_err(|err| {
                 // error!("Failed to authenticate RFID tag during reading");
-                std::io::Error::new(std::io::ErrorKind::Other, err)
+                std::io::Error::other(err)
             })?;
 
         let bytes_to_read = N_BLOCK_SIZE + 2;
@@ -272,15 +270,12 @@ impl Read for TagReader {
             .mifare_read(DATA_BLOCKS[self.current_block as usize], bytes_to_read)
             .map_err(|err| {
                 // error!("Failed to read data block from RFID tag");
-                std::io::Error::new(std::io::ErrorKind::Other, err)
+                std::io::Error::other(err)
             })?;
 
         if response.data.len() != bytes_to_read as usize {
             // Invalid / incomplete read.
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Incomplete read from RFID Tag",
-            ));
+            return Err(std::io::Error::other("Incomplete read from RFID Tag"));
         }
 
         // Received complete block from RFID tag.
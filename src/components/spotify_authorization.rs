@@ -0,0 +1,314 @@
+//! OAuth authorization code flow with PKCE for obtaining a Spotify refresh token.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use reqwest::header::AUTHORIZATION;
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use url::Url;
+
+use crate::effects::spotify::client::{self, DEFAULT_ACCOUNTS_BASE_URL};
+
+use super::access_token_provider::spotify_auth::AuthResponse;
+use super::spotify_credentials::{self, PersistedCredentials};
+
+pub use err::*;
+
+/// Permissions needed for controlling playback on the jukebox.
+pub const SCOPES: &str = "user-read-playback-state user-modify-playback-state \
+                          user-read-currently-playing streaming";
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub spotify_accounts_base_url: Option<String>,
+    pub spotify_state_file: Option<PathBuf>,
+    /// Has to be registered as redirect URI of the Spotify application.
+    #[serde(default = "default_spotify_auth_redirect_uri")]
+    pub spotify_auth_redirect_uri: String,
+    /// Address the callback server listens on, defaults to all interfaces on the port of
+    /// the redirect URI.
+    pub spotify_auth_listen_address: Option<SocketAddr>,
+}
+
+fn default_spotify_auth_redirect_uri() -> String {
+    "http://127.0.0.1:8888/callback".to_string()
+}
+
+impl Config {
+    pub fn listen_address(&self) -> Result<SocketAddr, Error> {
+        if let Some(address) = self.spotify_auth_listen_address {
+            return Ok(address);
+        }
+        let redirect_uri = Url::parse(&self.spotify_auth_redirect_uri)?;
+        let port = redirect_uri.port_or_known_default().unwrap_or(80);
+        Ok(SocketAddr::from(([0, 0, 0, 0], port)))
+    }
+
+    /// Path of the redirect URI, which the callback server serves.
+    pub fn callback_path(&self) -> Result<String, Error> {
+        Ok(Url::parse(&self.spotify_auth_redirect_uri)?
+            .path()
+            .to_string())
+    }
+
+    pub fn state_file(&self) -> PathBuf {
+        self.spotify_state_file
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(spotify_credentials::DEFAULT_STATE_FILE))
+    }
+}
+
+/// Proof Key for Code Exchange as specified in RFC 7636.
+#[derive(Debug, Clone)]
+pub struct Pkce {
+    pub verifier: String,
+    pub challenge: String,
+}
+
+impl Pkce {
+    pub fn new() -> Result<Self, Error> {
+        Ok(Self::from_verifier(&random_string(64)?))
+    }
+
+    pub fn from_verifier(verifier: &str) -> Self {
+        let hash = digest(&SHA256, verifier.as_bytes());
+        Pkce {
+            verifier: verifier.to_string(),
+            challenge: base64::encode_config(hash.as_ref(), base64::URL_SAFE_NO_PAD),
+        }
+    }
+}
+
+/// URL safe random string encoding `n_bytes` random bytes.
+pub fn random_string(n_bytes: usize) -> Result<String, Error> {
+    let mut bytes = vec![0; n_bytes];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::Random)?;
+    Ok(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
+}
+
+pub fn authorization_url(
+    accounts_base_url: &Url,
+    client_id: &str,
+    redirect_uri: &str,
+    state: &str,
+    pkce: &Pkce,
+) -> Result<Url, Error> {
+    let mut url = accounts_base_url.join("authorize")?;
+    url.query_pairs_mut()
+        .append_pair("client_id", client_id)
+        .append_pair("response_type", "code")
+        .append_pair("redirect_uri", redirect_uri)
+        .append_pair("code_challenge_method", "S256")
+        .append_pair("code_challenge", &pkce.challenge)
+        .append_pair("state", state)
+        .append_pair("scope", SCOPES);
+    Ok(url)
+}
+
+/// Query parameters Spotify passes to the redirect URI.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CallbackParams {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Extracts the authorization code, rejecting callbacks not belonging to our request.
+pub fn authorization_code(params: CallbackParams, expected_state: &str) -> Result<String, Error> {
+    if params.state.as_deref() != Some(expected_state) {
+        return Err(Error::StateMismatch);
+    }
+    if let Some(error) = params.error {
+        return Err(Error::Denied(error));
+    }
+    params.code.ok_or(Error::MissingCode)
+}
+
+pub fn exchange_code(
+    accounts_base_url: &Url,
+    client_id: &str,
+    client_secret: Option<&str>,
+    code: &str,
+    redirect_uri: &str,
+    pkce: &Pkce,
+) -> Result<AuthResponse, Error> {
+    let params = [
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", redirect_uri),
+        ("client_id", client_id),
+        ("code_verifier", &pkce.verifier),
+    ];
+    let http_client = reqwest::blocking::Client::new();
+    let mut req = http_client
+        .post(accounts_base_url.join("api/token")?)
+        .form(&params);
+    // The jukebox refreshes tokens as a confidential client.
+    if let Some(client_secret) = client_secret {
+        let client_id_and_secret =
+            base64::encode(format!("{}:{}", client_id, client_secret).as_bytes());
+        req = req.header(AUTHORIZATION, format!("Basic {}", client_id_and_secret));
+    }
+    Ok(req.send()?.error_for_status()?.json()?)
+}
+
+/// Runs the complete flow: prints the authorization URL, waits for the callback and writes
+/// the obtained credentials to the state file.
+pub fn authorize(config: &Config, callback_timeout: Duration) -> Result<PathBuf, Error> {
+    let accounts_base_url = client::base_url(
+        config
+            .spotify_accounts_base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_ACCOUNTS_BASE_URL),
+    )?;
+    let pkce = Pkce::new()?;
+    let state = random_string(16)?;
+    let url = authorization_url(
+        &accounts_base_url,
+        &config.client_id,
+        &config.spotify_auth_redirect_uri,
+        &state,
+        &pkce,
+    )?;
+    println!("Open the following URL in a browser and grant access to the jukebox:\n");
+    println!("    {}\n", url);
+
+    let params = super::authorization_callback::wait_for_callback(
+        config.listen_address()?,
+        &config.callback_path()?,
+        callback_timeout,
+    )?;
+    let code = authorization_code(params, &state)?;
+    let rsp = exchange_code(
+        &accounts_base_url,
+        &config.client_id,
+        config.client_secret.as_deref(),
+        &code,
+        &config.spotify_auth_redirect_uri,
+        &pkce,
+    )?;
+    let state_file = config.state_file();
+    PersistedCredentials::new(
+        &rsp.refresh_token,
+        &rsp.access_token,
+        Duration::from_secs(rsp.expires_in.max(0) as u64),
+    )
+    .save(&state_file)?;
+    Ok(state_file)
+}
+
+pub mod err {
+    use std::fmt::{self, Display};
+
+    use crate::components::spotify_credentials;
+
+    #[derive(Debug)]
+    pub enum Error {
+        Random,
+        StateMismatch,
+        Denied(String),
+        MissingCode,
+        CallbackTimeout,
+        CallbackServer(String),
+        Http(reqwest::Error),
+        Url(url::ParseError),
+        Credentials(spotify_credentials::Error),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::Random => write!(f, "Failed to generate random data"),
+                Error::StateMismatch => write!(f, "Callback does not belong to this request"),
+                Error::Denied(err) => write!(f, "Authorization denied: {}", err),
+                Error::MissingCode => write!(f, "Callback is missing the authorization code"),
+                Error::CallbackTimeout => write!(f, "Timed out waiting for authorization"),
+                Error::CallbackServer(err) => write!(f, "Callback Server Error {}", err),
+                Error::Http(err) => write!(f, "Authorization HTTP Error {}", err),
+                Error::Url(err) => write!(f, "Authorization URL Error {}", err),
+                Error::Credentials(err) => write!(f, "{}", err),
+            }
+        }
+    }
+
+    impl From<reqwest::Error> for Error {
+        fn from(err: reqwest::Error) -> Self {
+            Error::Http(err)
+        }
+    }
+
+    impl From<url::ParseError> for Error {
+        fn from(err: url::ParseError) -> Self {
+            Error::Url(err)
+        }
+    }
+
+    impl From<spotify_credentials::Error> for Error {
+        fn from(err: spotify_credentials::Error) -> Self {
+            Error::Credentials(err)
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pkce_challenge_matches_rfc_7636() {
+        let pkce = Pkce::from_verifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk");
+        assert_eq!(
+            pkce.challenge,
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+        assert_eq!(Pkce::new().unwrap().verifier.len(), 86);
+    }
+
+    #[test]
+    fn callback_is_served_on_redirect_uri() {
+        let config = Config {
+            client_id: "client".to_string(),
+            client_secret: None,
+            spotify_accounts_base_url: None,
+            spotify_state_file: None,
+            spotify_auth_redirect_uri: "http://jukebox.local:9000/spotify/auth?x=1".to_string(),
+            spotify_auth_listen_address: None,
+        };
+        assert_eq!(config.callback_path().unwrap(), "/spotify/auth");
+        assert_eq!(
+            config.listen_address().unwrap(),
+            SocketAddr::from(([0, 0, 0, 0], 9000))
+        );
+    }
+
+    #[test]
+    fn callbacks_are_validated() {
+        let params =
+            |code: Option<&str>, state: Option<&str>, error: Option<&str>| CallbackParams {
+                code: code.map(str::to_string),
+                state: state.map(str::to_string),
+                error: error.map(str::to_string),
+            };
+        assert_eq!(
+            authorization_code(params(Some("code"), Some("xyz"), None), "xyz").unwrap(),
+            "code"
+        );
+        assert!(matches!(
+            authorization_code(params(Some("code"), Some("abc"), None), "xyz"),
+            Err(Error::StateMismatch)
+        ));
+        assert!(matches!(
+            authorization_code(params(None, Some("xyz"), Some("access_denied")), "xyz"),
+            Err(Error::Denied(_))
+        ));
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+pub use err::*;
+
+pub const DEFAULT_STATE_FILE: &str = "/var/lib/rustberry/spotify-credentials.json";
+
+/// Spotify credentials surviving restarts. The file is only readable by its owner.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PersistedCredentials {
+    pub refresh_token: String,
+    pub access_token: Option<String>,
+    /// Expiry of the access token in seconds since the Unix epoch.
+    pub expires_at: Option<u64>,
+}
+
+impl PersistedCredentials {
+    pub fn new(refresh_token: &str, access_token: &str, expires_in: Duration) -> Self {
+        let expires_at = SystemTime::now() + expires_in;
+        PersistedCredentials {
+            refresh_token: refresh_token.to_string(),
+            access_token: Some(access_token.to_string()),
+            expires_at: expires_at
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|x| x.as_secs()),
+        }
+    }
+
+    /// Returns `None` if there is no state file yet.
+    pub fn load(path: &Path) -> Result<Option<Self>, Error> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(path)?;
+        Ok(Some(serde_json::from_str(&content)?))
+    }
+
+    /// Replaces the state file atomically.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(serde_json::to_string(self)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// The persisted access token and its remaining validity, if it has not expired yet.
+    pub fn access_token(&self) -> Option<(String, Duration)> {
+        let access_token = self.access_token.as_ref()?;
+        let expires_at = UNIX_EPOCH + Duration::from_secs(self.expires_at?);
+        let remaining = expires_at.duration_since(SystemTime::now()).ok()?;
+        Some((access_token.clone(), remaining))
+    }
+}
+
+pub mod err {
+    use std::fmt::{self, Display};
+
+    #[derive(Debug)]
+    pub enum Error {
+        IO(std::io::Error),
+        Json(serde_json::Error),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::IO(err) => write!(f, "Credentials IO Error {}", err),
+                Error::Json(err) => write!(f, "Credentials JSON Error {}", err),
+            }
+        }
+    }
+
+    impl From<std::io::Error> for Error {
+        fn from(err: std::io::Error) -> Self {
+            Error::IO(err)
+        }
+    }
+
+    impl From<serde_json::Error> for Error {
+        fn from(err: serde_json::Error) -> Self {
+            Error::Json(err)
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn credentials_are_persisted_privately() {
+        let path = std::env::temp_dir().join(format!(
+            "rustberry-spotify-credentials-{}.json",
+            std::process::id()
+        ));
+        let credentials = PersistedCredentials::new("refresh", "access", Duration::from_secs(3600));
+        credentials.save(&path).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let loaded = PersistedCredentials::load(&path).unwrap().unwrap();
+        assert_eq!(loaded, credentials);
+        let (access_token, remaining) = loaded.access_token().unwrap();
+        assert_eq!(access_token, "access");
+        assert!(remaining > Duration::from_secs(3500));
+
+        let expired = PersistedCredentials {
+            expires_at: Some(0),
+            ..loaded
+        };
+        assert_eq!(expired.access_token(), None);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+pub use err::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpotifyUriKind {
+    Track,
+    Episode,
+    Album,
+    Playlist,
+    Artist,
+    Show,
+}
+
+impl SpotifyUriKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            SpotifyUriKind::Track => "track",
+            SpotifyUriKind::Episode => "episode",
+            SpotifyUriKind::Album => "album",
+            SpotifyUriKind::Playlist => "playlist",
+            SpotifyUriKind::Artist => "artist",
+            SpotifyUriKind::Show => "show",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "track" => Some(SpotifyUriKind::Track),
+            "episode" => Some(SpotifyUriKind::Episode),
+            "album" => Some(SpotifyUriKind::Album),
+            "playlist" => Some(SpotifyUriKind::Playlist),
+            "artist" => Some(SpotifyUriKind::Artist),
+            "show" => Some(SpotifyUriKind::Show),
+            _ => None,
+        }
+    }
+}
+
+/// Validated Spotify URI such as `spotify:album:1DFixLWuPkv3KT3TnV35m3`. Also parses
+/// `https://open.spotify.com/...` links as shared from the Spotify apps.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SpotifyUri {
+    kind: SpotifyUriKind,
+    id: String,
+}
+
+impl SpotifyUri {
+    pub fn new(kind: SpotifyUriKind, id: &str) -> Result<Self, Error> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(Error::InvalidId(id.to_string()));
+        }
+        Ok(SpotifyUri {
+            kind,
+            id: id.to_string(),
+        })
+    }
+
+    pub fn kind(&self) -> SpotifyUriKind {
+        self.kind
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Albums, playlists, artists and shows are played as context, tracks and episodes
+    /// are played as list of URIs.
+    pub fn is_context(&self) -> bool {
+        match self.kind {
+            SpotifyUriKind::Album
+            | SpotifyUriKind::Playlist
+            | SpotifyUriKind::Artist
+            | SpotifyUriKind::Show => true,
+            SpotifyUriKind::Track | SpotifyUriKind::Episode => false,
+        }
+    }
+
+    /// Spotify only supports start offsets within albums and playlists.
+    pub fn supports_offset(&self) -> bool {
+        matches!(self.kind, SpotifyUriKind::Album | SpotifyUriKind::Playlist)
+    }
+
+    fn from_uri(s: &str) -> Result<Self, Error> {
+        let segments: Vec<&str> = s.split(':').collect();
+        match segments.as_slice() {
+            ["spotify", kind, id] => Self::from_segments(s, kind, id),
+            // Legacy playlist URIs include the owner.
+            ["spotify", "user", _user, "playlist", id] => Self::new(SpotifyUriKind::Playlist, id),
+            _ => Err(Error::Invalid(s.to_string())),
+        }
+    }
+
+    fn from_url(s: &str) -> Result<Self, Error> {
+        let url = Url::parse(s).map_err(|_| Error::Invalid(s.to_string()))?;
+        if url.host_str() != Some("open.spotify.com") {
+            return Err(Error::Invalid(s.to_string()));
+        }
+        let segments: Vec<&str> = url
+            .path_segments()
+            .map(|segments| {
+                segments
+                    .filter(|segment| {
+                        !segment.is_empty() && *segment != "embed" && !segment.starts_with("intl-")
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        match segments.as_slice() {
+            [kind, id] => Self::from_segments(s, kind, id),
+            ["user", _user, "playlist", id] => Self::new(SpotifyUriKind::Playlist, id),
+            _ => Err(Error::Invalid(s.to_string())),
+        }
+    }
+
+    fn from_segments(s: &str, kind: &str, id: &str) -> Result<Self, Error> {
+        match SpotifyUriKind::parse(kind) {
+            Some(kind) => Self::new(kind, id),
+            None => Err(Error::UnsupportedKind(s.to_string())),
+        }
+    }
+}
+
+impl FromStr for SpotifyUri {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let s = s.trim();
+        if s.starts_with("spotify:") {
+            Self::from_uri(s)
+        } else {
+            Self::from_url(s)
+        }
+    }
+}
+
+impl TryFrom<String> for SpotifyUri {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Error> {
+        s.parse()
+    }
+}
+
+impl From<SpotifyUri> for String {
+    fn from(uri: SpotifyUri) -> Self {
+        uri.to_string()
+    }
+}
+
+impl Display for SpotifyUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "spotify:{}:{}", self.kind.as_str(), self.id)
+    }
+}
+
+/// Where playback starts within an album or playlist, serialized as expected by the
+/// `offset` field of the Spotify play request.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpotifyOffset {
+    /// Zero based index of the track.
+    Position(u32),
+    Uri(SpotifyUri),
+}
+
+/// Spotify URI with an optional start offset. Resources without offset are serialized as
+/// plain URI string, keeping existing tags readable.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "SpotifyResourceRepr", into = "SpotifyResourceRepr")]
+pub struct SpotifyResource {
+    uri: SpotifyUri,
+    offset: Option<SpotifyOffset>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SpotifyResourceRepr {
+    Uri(SpotifyUri),
+    WithOffset {
+        uri: SpotifyUri,
+        offset: Option<SpotifyOffset>,
+    },
+}
+
+impl SpotifyResource {
+    pub fn new(uri: SpotifyUri, offset: Option<SpotifyOffset>) -> Result<Self, Error> {
+        if offset.is_some() && !uri.supports_offset() {
+            return Err(Error::UnsupportedOffset(uri.to_string()));
+        }
+        Ok(SpotifyResource { uri, offset })
+    }
+
+    pub fn uri(&self) -> &SpotifyUri {
+        &self.uri
+    }
+
+    pub fn offset(&self) -> Option<&SpotifyOffset> {
+        self.offset.as_ref()
+    }
+}
+
+impl From<SpotifyUri> for SpotifyResource {
+    fn from(uri: SpotifyUri) -> Self {
+        SpotifyResource { uri, offset: None }
+    }
+}
+
+impl FromStr for SpotifyResource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(SpotifyResource::from(s.parse::<SpotifyUri>()?))
+    }
+}
+
+impl TryFrom<SpotifyResourceRepr> for SpotifyResource {
+    type Error = Error;
+
+    fn try_from(repr: SpotifyResourceRepr) -> Result<Self, Error> {
+        match repr {
+            SpotifyResourceRepr::Uri(uri) => Ok(SpotifyResource::from(uri)),
+            SpotifyResourceRepr::WithOffset { uri, offset } => SpotifyResource::new(uri, offset),
+        }
+    }
+}
+
+impl From<SpotifyResource> for SpotifyResourceRepr {
+    fn from(resource: SpotifyResource) -> Self {
+        match resource.offset {
+            None => SpotifyResourceRepr::Uri(resource.uri),
+            offset => SpotifyResourceRepr::WithOffset {
+                uri: resource.uri,
+                offset,
+            },
+        }
+    }
+}
+
+impl Display for SpotifyResource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.offset {
+            None => write!(f, "{}", self.uri),
+            Some(SpotifyOffset::Position(position)) => {
+                write!(f, "{} (track {})", self.uri, position + 1)
+            }
+            Some(SpotifyOffset::Uri(ref uri)) => write!(f, "{} (from {})", self.uri, uri),
+        }
+    }
+}
+
+pub mod err {
+    use std::fmt::{self, Display};
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Error {
+        Invalid(String),
+        UnsupportedKind(String),
+        InvalidId(String),
+        UnsupportedOffset(String),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::Invalid(s) => write!(f, "Invalid Spotify URI '{}'", s),
+                Error::UnsupportedKind(s) => write!(f, "Unsupported Spotify URI type in '{}'", s),
+                Error::InvalidId(id) => write!(f, "Invalid Spotify ID '{}'", id),
+                Error::UnsupportedOffset(uri) => {
+                    write!(
+                        f,
+                        "Offsets are only supported for albums and playlists, not '{}'",
+                        uri
+                    )
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn uris_and_urls_are_parsed() {
+        let cases = vec![
+            (
+                "spotify:track:5j6ZZwA9BnxZi5Bk0Ng4jB",
+                "spotify:track:5j6ZZwA9BnxZi5Bk0Ng4jB",
+            ),
+            (
+                "spotify:user:someone:playlist:37i9dQZF1DXcBWIGoYBM5M",
+                "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M",
+            ),
+            (
+                "https://open.spotify.com/album/1DFixLWuPkv3KT3TnV35m3?si=abc",
+                "spotify:album:1DFixLWuPkv3KT3TnV35m3",
+            ),
+            (
+                "https://open.spotify.com/intl-de/show/2mTUnDkuKUkhiueKcVWoP0",
+                "spotify:show:2mTUnDkuKUkhiueKcVWoP0",
+            ),
+            (
+                " https://open.spotify.com/embed/episode/512ojhOuo1ktJprKbVcKyQ\n",
+                "spotify:episode:512ojhOuo1ktJprKbVcKyQ",
+            ),
+        ];
+        for (input, expected) in cases {
+            let uri: SpotifyUri = input.parse().unwrap();
+            assert_eq!(uri.to_string(), expected);
+        }
+
+        for invalid in &[
+            "",
+            "foo",
+            "spotify:album",
+            "spotify:album:",
+            "spotify:radio:abc",
+            "spotify:track:abc/def",
+            "https://example.com/track/5j6ZZwA9BnxZi5Bk0Ng4jB",
+        ] {
+            assert!(invalid.parse::<SpotifyUri>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn contexts_are_distinguished() {
+        let album: SpotifyUri = "spotify:album:1DFixLWuPkv3KT3TnV35m3".parse().unwrap();
+        let episode: SpotifyUri = "spotify:episode:512ojhOuo1ktJprKbVcKyQ".parse().unwrap();
+        assert!(album.is_context());
+        assert!(!episode.is_context());
+    }
+
+    #[test]
+    fn resources_with_offset_are_serialized() {
+        let plain: SpotifyResource =
+            serde_json::from_str(r#""spotify:album:1DFixLWuPkv3KT3TnV35m3""#).unwrap();
+        assert_eq!(plain.offset(), None);
+        assert_eq!(
+            serde_json::to_string(&plain).unwrap(),
+            r#""spotify:album:1DFixLWuPkv3KT3TnV35m3""#
+        );
+
+        let json = r#"{"uri":"spotify:album:1DFixLWuPkv3KT3TnV35m3","offset":{"position":4}}"#;
+        let with_offset: SpotifyResource = serde_json::from_str(json).unwrap();
+        assert_eq!(with_offset.offset(), Some(&SpotifyOffset::Position(4)));
+        assert_eq!(serde_json::to_string(&with_offset).unwrap(), json);
+
+        let json = r#"{"uri":"spotify:track:5j6ZZwA9BnxZi5Bk0Ng4jB","offset":{"position":4}}"#;
+        assert!(serde_json::from_str::<SpotifyResource>(json).is_err());
+    }
+}
@@ -1,8 +1,11 @@
+use std::path::PathBuf;
+
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
-    pub refresh_token: String,
+    /// Only needed if no refresh token has been persisted via `jukeboxd auth`.
+    pub refresh_token: Option<String>,
     pub client_id: String,
     pub client_secret: String,
     pub device_name: String,
@@ -10,4 +13,15 @@ pub struct Config {
     pub shutdown_command: Option<String>,
     pub volume_up_command: Option<String>,
     pub volume_down_command: Option<String>,
+    /// Command which succeeds if the volume cannot be increased any further.
+    pub volume_limit_reached_command: Option<String>,
+    /// Equalizer presets selected in turn by the equalizer button, separated by `;`. Each preset
+    /// is a comma separated list of bands, an empty preset disables the equalizer.
+    pub equalizer_presets: Option<String>,
+    /// Base URL of the Spotify Web API, e.g. for testing against a mock server.
+    pub spotify_api_base_url: Option<String>,
+    /// Base URL of the Spotify accounts service used for refreshing access tokens.
+    pub spotify_accounts_base_url: Option<String>,
+    /// File persisting the current Spotify refresh and access tokens.
+    pub spotify_state_file: Option<PathBuf>,
 }
@@ -0,0 +1,245 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use failure::Fallible;
+use serde::Deserialize;
+use slog_scope::{info, warn};
+
+pub use err::*;
+
+use crate::components::ducking::Ducking;
+use crate::components::http_credentials;
+
+use super::audio_output::AudioBackend;
+use super::spotify::player::SpotifyDucking;
+use super::{http_player, spotify};
+
+#[derive(Deserialize, Debug, Clone)]
+struct Config {
+    /// Command template rendering `{text}` into the WAV file `{output}`,
+    /// e.g. `pico2wave -l en-US -w {output} {text}`.
+    tts_command: Option<String>,
+    #[serde(default = "default_announcement_ducking")]
+    announcement_ducking: f32,
+    /// Titles are only known for HTTP streams and podcasts, Spotify playback is not announced.
+    #[serde(default)]
+    announce_titles: bool,
+    #[serde(default = "default_no_internet_announcement")]
+    no_internet_announcement: String,
+}
+
+fn default_announcement_ducking() -> f32 {
+    0.2
+}
+
+fn default_no_internet_announcement() -> String {
+    "No internet connection".to_string()
+}
+
+/// Announcements are cut off after this duration.
+const MAX_ANNOUNCEMENT_DURATION: Duration = Duration::from_secs(60);
+
+/// Renders text via an external TTS command and plays it, while music is ducked.
+/// Spotify playback is ducked via the volume of the Spotify Connect device.
+pub struct Announcer {
+    tts_command: Option<String>,
+    ducking: Ducking,
+    spotify_ducking: SpotifyDucking,
+    ducking_factor: f32,
+    announce_titles: bool,
+    no_internet_announcement: String,
+    backend: Arc<dyn AudioBackend>,
+    /// Serializes announcements.
+    lock: Mutex<()>,
+    counter: AtomicUsize,
+}
+
+impl Announcer {
+    pub fn new(
+        ducking: Ducking,
+        spotify_ducking: SpotifyDucking,
+        backend: Arc<dyn AudioBackend>,
+    ) -> Fallible<Self> {
+        info!("Creating new Announcer...");
+        let config: Config = envy::from_env()?;
+        if config.tts_command.is_none() {
+            info!("No TTS command configured, announcements are disabled");
+        }
+        Ok(Announcer {
+            tts_command: config.tts_command,
+            ducking,
+            spotify_ducking,
+            ducking_factor: config.announcement_ducking,
+            announce_titles: config.announce_titles,
+            no_internet_announcement: config.no_internet_announcement,
+            backend,
+            lock: Mutex::new(()),
+            counter: AtomicUsize::new(0),
+        })
+    }
+
+    /// Whether titles are to be announced at the start of a resource.
+    pub fn announce_titles(&self) -> bool {
+        self.announce_titles
+    }
+
+    pub fn no_internet_announcement(&self) -> &str {
+        &self.no_internet_announcement
+    }
+
+    /// Renders and plays the text, blocking until the announcement is over.
+    pub fn announce(&self, text: &str) -> Fallible<()> {
+        let tts_command = match self.tts_command {
+            Some(ref tts_command) => tts_command,
+            None => {
+                info!("Not announcing '{}', no TTS command configured", text);
+                return Ok(());
+            }
+        };
+        let _guard = self.lock.lock().unwrap();
+        let output = std::env::temp_dir().join(format!(
+            "rustberry-announcement-{}-{}.wav",
+            std::process::id(),
+            self.counter.fetch_add(1, Ordering::SeqCst)
+        ));
+        info!("Announcing '{}'", text);
+        let result = self.render(tts_command, text, &output).and_then(|()| {
+            self.ducking.duck(self.ducking_factor);
+            if let Err(err) = self.spotify_ducking.duck(self.ducking_factor) {
+                warn!("Failed to duck Spotify playback: {}", err);
+            }
+            let result = self.play(&output);
+            self.ducking.restore();
+            if let Err(err) = self.spotify_ducking.restore() {
+                warn!("Failed to restore Spotify volume: {}", err);
+            }
+            result
+        });
+        if output.exists() {
+            if let Err(err) = fs::remove_file(&output) {
+                warn!("Failed to remove {}: {}", output.display(), err);
+            }
+        }
+        result
+    }
+
+    fn render(&self, tts_command: &str, text: &str, output: &Path) -> Fallible<()> {
+        let cmd = render_command(tts_command, text, output);
+        let status = Command::new("/bin/sh").arg("-c").arg(&cmd).status()?;
+        if !status.success() {
+            return Err(Error::TtsFailed(status.code()).into());
+        }
+        Ok(())
+    }
+
+    fn play(&self, path: &Path) -> Fallible<()> {
+        let source = rodio::Decoder::new(BufReader::new(File::open(path)?))?;
+        let output = self.backend.open()?;
+        output.append(Box::new(source));
+        output.play();
+        let deadline = Instant::now() + MAX_ANNOUNCEMENT_DURATION;
+        while !output.is_empty() {
+            if Instant::now() > deadline {
+                warn!("Announcement takes too long, cutting it off");
+                output.stop();
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        Ok(())
+    }
+}
+
+/// Substitutes the shell quoted text and output path into the command template.
+fn render_command(template: &str, text: &str, output: &Path) -> String {
+    template
+        .replace("{output}", &shell_quote(&output.to_string_lossy()))
+        .replace("{text}", &shell_quote(text))
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Returns true if the error has been caused by a remote host being unreachable.
+pub fn is_connection_error(err: &failure::Error) -> bool {
+    let http_err = err
+        .downcast_ref::<reqwest::Error>()
+        .or_else(|| match err.downcast_ref::<http_player::Error>() {
+            Some(http_player::Error::Http(err)) => Some(err),
+            _ => None,
+        })
+        .or_else(|| match err.downcast_ref::<http_credentials::Error>() {
+            Some(http_credentials::Error::Http(err)) => Some(err),
+            _ => None,
+        })
+        .or_else(|| match err.downcast_ref::<spotify::player::Error>() {
+            Some(spotify::player::Error::HTTP(err)) => Some(err),
+            _ => None,
+        })
+        .or_else(|| match err.downcast_ref::<spotify::client::Error>() {
+            Some(spotify::client::Error::Http(err)) => Some(err),
+            _ => None,
+        });
+    http_err.map(is_connection_failure).unwrap_or(false)
+}
+
+/// Failures to connect surface as IO errors within the error chain.
+fn is_connection_failure(err: &reqwest::Error) -> bool {
+    if err.is_timeout() {
+        return true;
+    }
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        if err.downcast_ref::<std::io::Error>().is_some() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+pub mod err {
+    use std::fmt::{self, Display};
+
+    #[derive(Debug)]
+    pub enum Error {
+        TtsFailed(Option<i32>),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::TtsFailed(Some(code)) => {
+                    write!(f, "TTS command terminated with exit code {}", code)
+                }
+                Error::TtsFailed(None) => write!(f, "TTS command terminated by signal"),
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tts_command_is_rendered() {
+        let cmd = render_command(
+            "pico2wave -l en-US -w {output} {text}",
+            "It's Bob's podcast",
+            Path::new("/tmp/out.wav"),
+        );
+        assert_eq!(
+            cmd,
+            r"pico2wave -l en-US -w '/tmp/out.wav' 'It'\''s Bob'\''s podcast'"
+        );
+    }
+}
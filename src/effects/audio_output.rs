@@ -0,0 +1,434 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::Builder;
+
+use hound::{WavSpec, WavWriter};
+use rodio::source::UniformSourceIterator;
+use rodio::{Sink, Source};
+use serde::Deserialize;
+use slog_scope::{error, info};
+
+pub use err::*;
+
+use super::output_device;
+
+pub type BoxedSource = Box<dyn Source<Item = i16> + Send>;
+
+/// Queue of sources being played back, modelled after `rodio::Sink`.
+pub trait AudioOutput: Send + Sync {
+    fn append(&self, source: BoxedSource);
+    fn play(&self);
+    fn pause(&self);
+    /// Stops playback and discards queued sources. A stopped output cannot be reused.
+    fn stop(&self);
+    /// Number of queued sources, including the one currently playing.
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Creates fresh audio outputs, e.g. for every (re)start of a playback.
+pub trait AudioBackend: Send + Sync {
+    fn open(&self) -> Result<Arc<dyn AudioOutput>, Error>;
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// Play on an audio device.
+    #[default]
+    Rodio,
+    /// Discard all audio.
+    Null,
+    /// Write the rendered audio into a WAV file.
+    Wav,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Config {
+    #[serde(default)]
+    audio_output_backend: BackendKind,
+    audio_output_device: Option<String>,
+    audio_output_wav_file: Option<PathBuf>,
+}
+
+/// Creates the audio backend selected via `AUDIO_OUTPUT_BACKEND`.
+pub fn backend_from_env() -> Result<Arc<dyn AudioBackend>, Error> {
+    let config: Config = envy::from_env()?;
+    info!(
+        "Using audio output backend {:?}",
+        config.audio_output_backend
+    );
+    let backend: Arc<dyn AudioBackend> = match config.audio_output_backend {
+        BackendKind::Rodio => Arc::new(RodioBackend::new(config.audio_output_device)),
+        BackendKind::Null => Arc::new(NullBackend),
+        BackendKind::Wav => {
+            let path = config.audio_output_wav_file.ok_or(Error::NoWavFile)?;
+            Arc::new(WavBackend::new(path))
+        }
+    };
+    Ok(backend)
+}
+
+/// Plays on the configured audio output device, or the default device.
+pub struct RodioBackend {
+    device_name: Option<String>,
+}
+
+impl RodioBackend {
+    pub fn new(device_name: Option<String>) -> Self {
+        RodioBackend { device_name }
+    }
+}
+
+impl AudioBackend for RodioBackend {
+    fn open(&self) -> Result<Arc<dyn AudioOutput>, Error> {
+        let device = output_device::select_output_device(self.device_name.as_deref())?;
+        Ok(Arc::new(Sink::new(&device)))
+    }
+}
+
+impl AudioOutput for Sink {
+    fn append(&self, source: BoxedSource) {
+        Sink::append(self, source)
+    }
+    fn play(&self) {
+        Sink::play(self)
+    }
+    fn pause(&self) {
+        Sink::pause(self)
+    }
+    fn stop(&self) {
+        Sink::stop(self)
+    }
+    fn len(&self) -> usize {
+        Sink::len(self)
+    }
+}
+
+/// Consumes audio as fast as possible without playing it.
+pub struct NullBackend;
+
+impl AudioBackend for NullBackend {
+    fn open(&self) -> Result<Arc<dyn AudioOutput>, Error> {
+        Ok(Arc::new(RenderingOutput::new(Renderer::Null)?))
+    }
+}
+
+/// Renders audio as fast as possible into a WAV file, which is created by the first rendered
+/// source. Sources of all outputs opened from the same backend are appended to that file, in
+/// the order they are rendered. All sources are converted to the format of the first source.
+pub struct WavBackend {
+    path: PathBuf,
+    writer: SharedWavWriter,
+}
+
+/// The writer finalizes the file once the backend and all of its outputs are dropped.
+type SharedWavWriter = Arc<Mutex<Option<WavWriter<BufWriter<File>>>>>;
+
+impl WavBackend {
+    pub fn new(path: PathBuf) -> Self {
+        WavBackend {
+            path,
+            writer: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl AudioBackend for WavBackend {
+    fn open(&self) -> Result<Arc<dyn AudioOutput>, Error> {
+        let renderer = Renderer::Wav {
+            path: self.path.clone(),
+            writer: self.writer.clone(),
+        };
+        Ok(Arc::new(RenderingOutput::new(renderer)?))
+    }
+}
+
+enum Renderer {
+    Null,
+    Wav {
+        path: PathBuf,
+        writer: SharedWavWriter,
+    },
+}
+
+impl Renderer {
+    /// Returns the format all sources are to be converted to, if any.
+    fn spec(&self) -> Option<WavSpec> {
+        match self {
+            Renderer::Wav { writer, .. } => writer.lock().unwrap().as_ref().map(WavWriter::spec),
+            Renderer::Null => None,
+        }
+    }
+
+    fn start(&mut self, channels: u16, sample_rate: u32) -> Result<(), Error> {
+        if let Renderer::Wav { path, writer } = self {
+            let mut writer = writer.lock().unwrap();
+            if writer.is_none() {
+                let spec = WavSpec {
+                    channels,
+                    sample_rate,
+                    bits_per_sample: 16,
+                    sample_format: hound::SampleFormat::Int,
+                };
+                *writer = Some(WavWriter::create(path, spec)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[i16]) -> Result<(), Error> {
+        if let Renderer::Wav { writer, .. } = self {
+            if let Some(writer) = writer.lock().unwrap().as_mut() {
+                for sample in samples {
+                    writer.write_sample(*sample)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes everything rendered so far readable by others.
+    fn flush(&mut self) -> Result<(), Error> {
+        if let Renderer::Wav { writer, .. } = self {
+            if let Some(writer) = writer.lock().unwrap().as_mut() {
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct RenderState {
+    queue: VecDeque<BoxedSource>,
+    rendering: bool,
+    paused: bool,
+    stopped: bool,
+}
+
+/// Output rendering its sources on a dedicated thread, not bound to real time.
+struct RenderingOutput {
+    state: Arc<(Mutex<RenderState>, Condvar)>,
+}
+
+const RENDER_CHUNK_SIZE: usize = 4096;
+
+impl RenderingOutput {
+    fn new(renderer: Renderer) -> Result<Self, Error> {
+        let state = Arc::new((Mutex::new(RenderState::default()), Condvar::new()));
+        let state_cp = state.clone();
+        Builder::new()
+            .name("audio-renderer".to_string())
+            .spawn(move || {
+                if let Err(err) = Self::render(&state_cp, renderer) {
+                    error!("Failed to render audio: {}", err);
+                }
+                let (state, cond) = &*state_cp;
+                let mut state = state.lock().unwrap();
+                state.queue.clear();
+                state.rendering = false;
+                cond.notify_all();
+            })?;
+        Ok(RenderingOutput { state })
+    }
+
+    /// Waits for the next source to render, returns None once stopped.
+    fn next_source(state: &(Mutex<RenderState>, Condvar)) -> Option<BoxedSource> {
+        let (state, cond) = state;
+        let mut state = cond
+            .wait_while(state.lock().unwrap(), |state| {
+                !state.stopped && (state.paused || state.queue.is_empty())
+            })
+            .unwrap();
+        if state.stopped {
+            return None;
+        }
+        state.rendering = true;
+        state.queue.pop_front()
+    }
+
+    /// Blocks while paused, returns false once stopped.
+    fn keep_rendering(state: &(Mutex<RenderState>, Condvar)) -> bool {
+        let (state, cond) = state;
+        let state = cond
+            .wait_while(state.lock().unwrap(), |state| {
+                !state.stopped && state.paused
+            })
+            .unwrap();
+        !state.stopped
+    }
+
+    fn render(state: &(Mutex<RenderState>, Condvar), mut renderer: Renderer) -> Result<(), Error> {
+        while let Some(source) = Self::next_source(state) {
+            let mut source: BoxedSource = match renderer.spec() {
+                Some(spec) => Box::new(UniformSourceIterator::<_, i16>::new(
+                    source,
+                    spec.channels,
+                    spec.sample_rate,
+                )),
+                None => source,
+            };
+            renderer.start(source.channels(), source.sample_rate())?;
+
+            let mut chunk = Vec::with_capacity(RENDER_CHUNK_SIZE);
+            loop {
+                chunk.clear();
+                chunk.extend(source.by_ref().take(RENDER_CHUNK_SIZE));
+                renderer.write(&chunk)?;
+                if chunk.len() < RENDER_CHUNK_SIZE || !Self::keep_rendering(state) {
+                    break;
+                }
+            }
+            renderer.flush()?;
+
+            let (state, cond) = state;
+            state.lock().unwrap().rendering = false;
+            cond.notify_all();
+        }
+        Ok(())
+    }
+
+    fn update<F: FnOnce(&mut RenderState)>(&self, f: F) {
+        let (state, cond) = &*self.state;
+        f(&mut state.lock().unwrap());
+        cond.notify_all();
+    }
+}
+
+impl AudioOutput for RenderingOutput {
+    fn append(&self, source: BoxedSource) {
+        self.update(|state| state.queue.push_back(source))
+    }
+    fn play(&self) {
+        self.update(|state| state.paused = false)
+    }
+    fn pause(&self) {
+        self.update(|state| state.paused = true)
+    }
+    fn stop(&self) {
+        self.update(|state| {
+            state.stopped = true;
+            state.queue.clear();
+        })
+    }
+    fn len(&self) -> usize {
+        let state = self.state.0.lock().unwrap();
+        state.queue.len() + if state.rendering { 1 } else { 0 }
+    }
+}
+
+impl Drop for RenderingOutput {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+pub mod err {
+    use std::fmt::{self, Display};
+
+    use super::output_device;
+
+    #[derive(Debug)]
+    pub enum Error {
+        OutputDevice(output_device::Error),
+        Wav(hound::Error),
+        IO(std::io::Error),
+        Config(envy::Error),
+        NoWavFile,
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::OutputDevice(err) => write!(f, "Audio Output Device Error {}", err),
+                Error::Wav(err) => write!(f, "Audio Output WAV Error {}", err),
+                Error::IO(err) => write!(f, "Audio Output IO Error {}", err),
+                Error::Config(err) => write!(f, "Audio Output Configuration Error {}", err),
+                Error::NoWavFile => write!(
+                    f,
+                    "Audio Output Error: WAV backend requires AUDIO_OUTPUT_WAV_FILE"
+                ),
+            }
+        }
+    }
+
+    impl From<output_device::Error> for Error {
+        fn from(err: output_device::Error) -> Self {
+            Error::OutputDevice(err)
+        }
+    }
+
+    impl From<hound::Error> for Error {
+        fn from(err: hound::Error) -> Self {
+            Error::Wav(err)
+        }
+    }
+
+    impl From<std::io::Error> for Error {
+        fn from(err: std::io::Error) -> Self {
+            Error::IO(err)
+        }
+    }
+
+    impl From<envy::Error> for Error {
+        fn from(err: envy::Error) -> Self {
+            Error::Config(err)
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use rodio::source::SineWave;
+
+    use super::*;
+
+    fn wait_until_empty(output: &dyn AudioOutput) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !output.is_empty() {
+            assert!(Instant::now() < deadline, "rendering timed out");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn wav_backend_renders_sources_of_all_outputs() {
+        let path = std::env::temp_dir().join(format!("rustberry-test-{}.wav", std::process::id()));
+        let backend = WavBackend::new(path.clone());
+        let output = backend.open().unwrap();
+        let sine = || {
+            SineWave::new(440)
+                .take_duration(Duration::from_millis(500))
+                .amplify(0.5)
+                .convert_samples::<i16>()
+        };
+        output.append(Box::new(sine()));
+        wait_until_empty(&*output);
+        // Outputs of the same backend append to the same file.
+        let second_output = backend.open().unwrap();
+        second_output.append(Box::new(sine()));
+        wait_until_empty(&*second_output);
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let spec = reader.spec();
+        assert_eq!((spec.channels, spec.sample_rate), (1, 48000));
+        let samples: Vec<i16> = reader.samples().map(|s| s.unwrap()).collect();
+        assert!((samples.len() as i64 - 48000).abs() <= 2);
+        let peak = samples.iter().map(|s| s.abs()).max().unwrap();
+        assert!((peak as f32 / i16::MAX as f32 - 0.5).abs() < 0.01);
+
+        output.stop();
+        second_output.stop();
+        let _ = std::fs::remove_file(&path);
+    }
+}
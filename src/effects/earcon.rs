@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use failure::Fallible;
+use rodio::source::{self, SineWave, Zero};
+use rodio::Source;
+use serde::Deserialize;
+use slog_scope::{info, warn};
+
+use super::audio_output::{AudioBackend, AudioOutput, BoxedSource};
+
+/// Short feedback sounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Earcon {
+    TagRecognized,
+    TagUnknown,
+    Error,
+    VolumeLimitReached,
+    ShuttingDown,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Config {
+    #[serde(default = "default_earcons_enabled")]
+    earcons_enabled: bool,
+    earcon_tag_recognized_file: Option<PathBuf>,
+    earcon_tag_unknown_file: Option<PathBuf>,
+    earcon_error_file: Option<PathBuf>,
+    earcon_volume_limit_reached_file: Option<PathBuf>,
+    earcon_shutting_down_file: Option<PathBuf>,
+}
+
+fn default_earcons_enabled() -> bool {
+    true
+}
+
+/// Waiting for an earcon blocks at most this long, longer configured sounds continue playing
+/// in the background.
+const MAX_WAITING_DURATION: Duration = Duration::from_secs(3);
+
+const TONE_AMPLITUDE: f32 = 0.3;
+const TONE_SAMPLE_RATE: u32 = 48000;
+
+pub struct EarconPlayer {
+    enabled: bool,
+    backend: Arc<dyn AudioBackend>,
+    files: HashMap<Earcon, PathBuf>,
+    /// Output of the most recent earcon, kept around such that it is not cut off.
+    output: Mutex<Option<Arc<dyn AudioOutput>>>,
+}
+
+impl EarconPlayer {
+    pub fn new(backend: Arc<dyn AudioBackend>) -> Fallible<Self> {
+        info!("Creating new EarconPlayer...");
+        let config: Config = envy::from_env()?;
+        let files = vec![
+            (Earcon::TagRecognized, config.earcon_tag_recognized_file),
+            (Earcon::TagUnknown, config.earcon_tag_unknown_file),
+            (Earcon::Error, config.earcon_error_file),
+            (
+                Earcon::VolumeLimitReached,
+                config.earcon_volume_limit_reached_file,
+            ),
+            (Earcon::ShuttingDown, config.earcon_shutting_down_file),
+        ]
+        .into_iter()
+        .filter_map(|(earcon, file)| file.map(|file| (earcon, file)))
+        .collect();
+        Ok(EarconPlayer {
+            enabled: config.earcons_enabled,
+            backend,
+            files,
+            output: Mutex::new(None),
+        })
+    }
+
+    /// Starts playing the earcon without waiting for it, a previous earcon is cut off.
+    pub fn play(&self, earcon: Earcon) -> Fallible<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        info!("Playing earcon {:?}", earcon);
+        let source = self.source(earcon);
+        let output = self.backend.open()?;
+        output.append(source);
+        output.play();
+        if let Some(previous) = self.output.lock().unwrap().replace(output.clone()) {
+            previous.stop();
+        }
+        Ok(())
+    }
+
+    /// Blocks until the most recent earcon has been played or `MAX_WAITING_DURATION` passed.
+    pub fn wait(&self) {
+        let output = match self.output.lock().unwrap().clone() {
+            Some(output) => output,
+            None => return,
+        };
+        let deadline = Instant::now() + MAX_WAITING_DURATION;
+        while !output.is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// Uses the configured file, falling back to the bundled sound.
+    fn source(&self, earcon: Earcon) -> BoxedSource {
+        if let Some(path) = self.files.get(&earcon) {
+            match File::open(path)
+                .map_err(failure::Error::from)
+                .and_then(|file| Ok(rodio::Decoder::new(BufReader::new(file))?))
+            {
+                Ok(decoder) => return Box::new(decoder),
+                Err(err) => warn!(
+                    "Failed to load earcon {:?} from {}, using bundled sound: {}",
+                    earcon,
+                    path.display(),
+                    err
+                ),
+            }
+        }
+        bundled_source(earcon)
+    }
+}
+
+/// Sequence of tones given as (frequency in Hz, duration in ms). A frequency of zero
+/// denotes silence.
+fn bundled_tones(earcon: Earcon) -> &'static [(u32, u64)] {
+    match earcon {
+        Earcon::TagRecognized => &[(660, 80), (0, 20), (880, 120)],
+        Earcon::TagUnknown => &[(440, 120), (0, 40), (330, 200)],
+        Earcon::Error => &[(220, 200), (0, 80), (220, 200)],
+        Earcon::VolumeLimitReached => &[(1320, 60)],
+        Earcon::ShuttingDown => &[(880, 150), (660, 150), (440, 300)],
+    }
+}
+
+fn bundled_source(earcon: Earcon) -> BoxedSource {
+    let tones: Vec<BoxedSource> = bundled_tones(earcon)
+        .iter()
+        .map(|&(frequency, millis)| {
+            let duration = Duration::from_millis(millis);
+            if frequency == 0 {
+                Box::new(Zero::<i16>::new(1, TONE_SAMPLE_RATE).take_duration(duration))
+                    as BoxedSource
+            } else {
+                Box::new(
+                    SineWave::new(frequency)
+                        .take_duration(duration)
+                        .amplify(TONE_AMPLITUDE)
+                        // Avoids clicks at tone boundaries.
+                        .fade_in(Duration::from_millis(5))
+                        .convert_samples::<i16>(),
+                )
+            }
+        })
+        .collect();
+    Box::new(source::from_iter(tones))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::audio_output::WavBackend;
+    use super::*;
+
+    #[test]
+    fn waiting_lets_the_earcon_finish() {
+        let path =
+            std::env::temp_dir().join(format!("rustberry-earcon-test-{}.wav", std::process::id()));
+        let player = EarconPlayer {
+            enabled: true,
+            backend: Arc::new(WavBackend::new(path.clone())),
+            files: HashMap::new(),
+            output: Mutex::new(None),
+        };
+        player.play(Earcon::ShuttingDown).unwrap();
+        player.wait();
+
+        let reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(
+            reader.len() as usize,
+            bundled_source(Earcon::ShuttingDown).count()
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn bundled_earcons_are_short() {
+        for &earcon in &[
+            Earcon::TagRecognized,
+            Earcon::TagUnknown,
+            Earcon::Error,
+            Earcon::VolumeLimitReached,
+            Earcon::ShuttingDown,
+        ] {
+            let n_samples = bundled_source(earcon).count();
+            let duration = Duration::from_secs_f32(n_samples as f32 / TONE_SAMPLE_RATE as f32);
+            assert!(n_samples > 0, "{:?} is silent", earcon);
+            assert!(
+                duration < Duration::from_secs(1),
+                "{:?} is too long",
+                earcon
+            );
+        }
+    }
+}
@@ -1,52 +1,240 @@
 use failure::Fallible;
 use reqwest;
-use rodio::Sink;
-use slog_scope::{info, warn};
+use serde::Deserialize;
+use slog_scope::{error, info, warn};
 use std::convert::From;
-use std::env;
 use std::fmt::{self, Display};
 use std::io::BufReader;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
 
 use async_trait::async_trait;
-use crossbeam_channel::{self, Receiver, Sender};
+use crossbeam_channel::{self, Sender};
 use tokio::runtime::Runtime;
 use tokio::task::spawn_blocking;
+use url::Url;
 
 pub use err::*;
 
-use crate::components::finite_stream::FiniteStream;
+use crate::components::ducking::Ducking;
+use crate::components::equalizer::{self, Band, BandKind, Equalizer, EqualizerSettings};
+use crate::components::finite_stream::{BufferConfig, DownloadHandle, FiniteStream};
+use crate::components::http_credentials::{AuthenticatingClient, Credentials};
+use crate::components::loudness::{self, GainCache, Normalizer};
+use crate::components::playlist::{self, PlaylistItem};
 use crate::player::{PauseState, PlaybackHandle};
 
+use super::audio_output::{AudioBackend, AudioOutput, BoxedSource};
+
+#[derive(Deserialize, Debug, Clone)]
+struct Config {
+    http_player_spill_directory: Option<PathBuf>,
+    http_player_memory_window: Option<usize>,
+    http_player_download_timeout_secs: Option<u64>,
+    #[serde(default = "default_loudness_normalization")]
+    loudness_normalization: bool,
+    #[serde(default = "default_loudness_gain_cache_file")]
+    loudness_gain_cache_file: PathBuf,
+    #[serde(default = "default_loudness_max_gain_db")]
+    loudness_max_gain_db: f32,
+    equalizer_bands: Option<String>,
+    equalizer_bass_db: Option<f32>,
+    equalizer_treble_db: Option<f32>,
+}
+
+impl Config {
+    /// Explicitly configured bands are followed by the bass and treble shelves, if configured.
+    fn equalizer_settings(&self) -> Result<EqualizerSettings, equalizer::Error> {
+        let mut settings = match self.equalizer_bands {
+            Some(ref bands) => EqualizerSettings::parse(bands)?,
+            None => EqualizerSettings::default(),
+        };
+        if let Some(gain_db) = self.equalizer_bass_db {
+            settings.bands.push(Band::new(
+                BandKind::LowShelf,
+                equalizer::BASS_FREQUENCY,
+                gain_db,
+            ));
+        }
+        if let Some(gain_db) = self.equalizer_treble_db {
+            settings.bands.push(Band::new(
+                BandKind::HighShelf,
+                equalizer::TREBLE_FREQUENCY,
+                gain_db,
+            ));
+        }
+        Ok(settings)
+    }
+}
+
+fn default_loudness_normalization() -> bool {
+    true
+}
+
+fn default_loudness_gain_cache_file() -> PathBuf {
+    PathBuf::from("/var/lib/rustberry/loudness-gains.json")
+}
+
+fn default_loudness_max_gain_db() -> f32 {
+    6.0
+}
+
+impl From<Config> for BufferConfig {
+    fn from(config: Config) -> Self {
+        let default = BufferConfig::default();
+        BufferConfig {
+            window_size: config
+                .http_player_memory_window
+                .unwrap_or(default.window_size),
+            spill_directory: config
+                .http_player_spill_directory
+                .unwrap_or(default.spill_directory),
+            chunk_timeout: config
+                .http_player_download_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(default.chunk_timeout),
+            ..default
+        }
+    }
+}
+
 pub struct HttpPlayer {
     _handle: Option<JoinHandle<()>>,
-    basic_auth: Option<(String, String)>,
-    http_client: Arc<reqwest::Client>,
+    http_client: Arc<AuthenticatingClient>,
+    buffer_config: BufferConfig,
+    audio_backend: Arc<dyn AudioBackend>,
+    normalizer: Option<Arc<Normalizer>>,
+    equalizer: Equalizer,
+    ducking: Ducking,
 }
 
 pub struct HttpPlaybackHandle {
-    tx: Sender<()>,
-    sink: Arc<Sink>,
-    basic_auth: Option<(String, String)>,
-    url: String,
-    http_client: Arc<reqwest::Client>,
+    /// Dropping it ends the thread started for the playback.
+    _tx: Sender<()>,
+    queue: Arc<PlaybackQueue>,
 }
 
-impl HttpPlaybackHandle {
-    pub async fn queue(&self) -> Fallible<()> {
-        let mut builder = self.http_client.get(&self.url);
-        if let Some((ref username, ref password)) = &self.basic_auth {
-            builder = builder.basic_auth(username, Some(password));
+/// Items of a single playback, shared with the task prefetching upcoming items.
+struct PlaybackQueue {
+    items: Vec<PlaylistItem>,
+    audio_backend: Arc<dyn AudioBackend>,
+    sink: Mutex<Option<Arc<dyn AudioOutput>>>,
+    http_client: Arc<AuthenticatingClient>,
+    buffer_config: BufferConfig,
+    downloads: Mutex<Vec<DownloadHandle>>,
+    normalizer: Option<Arc<Normalizer>>,
+    equalizer: Equalizer,
+    ducking: Ducking,
+    /// Incremented on every (re)start, lets outdated prefetching tasks terminate.
+    generation: AtomicUsize,
+    n_appended: AtomicUsize,
+    prefetch_failed: AtomicBool,
+}
+
+const PREFETCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+impl PlaybackQueue {
+    fn sink(&self) -> Option<Arc<dyn AudioOutput>> {
+        self.sink.lock().unwrap().clone()
+    }
+
+    async fn append(&self, url: &str, generation: usize) -> Fallible<()> {
+        let response = self.http_client.fetch(url).await?.error_for_status()?;
+        let stream = FiniteStream::from_response(response, &self.buffer_config)?;
+        let download = stream.download_handle();
+        self.downloads.lock().unwrap().push(download.clone());
+        let read_tags = self.normalizer.is_some();
+        let (tagged_gain, source) = spawn_blocking(move || {
+            let mut stream = stream;
+            let tagged_gain = if read_tags {
+                loudness::read_tagged_gain(&mut stream).unwrap_or_else(|err| {
+                    warn!("Failed to read loudness tags: {}", err);
+                    None
+                })
+            } else {
+                None
+            };
+            let source = rodio::Decoder::new(BufReader::new(stream))?;
+            Ok::<_, failure::Error>((tagged_gain, source))
+        })
+        .await??;
+        if self.generation.load(Ordering::SeqCst) != generation {
+            // Playback has been restarted meanwhile.
+            download.cancel();
+            return Ok(());
         }
-        let response = builder.send().await.unwrap();
-        let stream = spawn_blocking(move || FiniteStream::from_response(response).unwrap()).await?;
-        let source =
-            spawn_blocking(move || rodio::Decoder::new(BufReader::new(stream)).unwrap()).await?;
-        self.sink.append(source);
+        if let Some(sink) = self.sink() {
+            let source: BoxedSource = match self.normalizer {
+                Some(ref normalizer) => normalizer.normalize(url, source, tagged_gain),
+                None => Box::new(source),
+            };
+            sink.append(Box::new(self.ducking.apply(self.equalizer.apply(source))));
+            self.n_appended.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
 
+    /// Appends the remaining items one by one, such that the sink always holds the currently
+    /// playing item plus the next one. This way items follow each other without gaps.
+    async fn prefetch(queue: Arc<Self>, generation: usize) {
+        for url in queue.items.iter().skip(1).map(|item| &item.url) {
+            loop {
+                if queue.generation.load(Ordering::SeqCst) != generation {
+                    return;
+                }
+                match queue.sink() {
+                    Some(ref sink) if sink.len() > 1 => {}
+                    _ => break,
+                }
+                tokio::time::delay_for(PREFETCH_POLL_INTERVAL).await;
+            }
+            info!("Prefetching next item {}", url);
+            if let Err(err) = queue.append(url, generation).await {
+                error!("Failed to prefetch item {}: {}", url, err);
+                queue.prefetch_failed.store(true, Ordering::SeqCst);
+                return;
+            }
+        }
+    }
+
+    /// (Re)starts playback with the first item on a fresh sink.
+    async fn start(queue: &Arc<Self>) -> Fallible<()> {
+        let generation = queue.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        queue.stop();
+        *queue.sink.lock().unwrap() = Some(queue.audio_backend.open()?);
+        queue.n_appended.store(0, Ordering::SeqCst);
+        queue.prefetch_failed.store(false, Ordering::SeqCst);
+        queue.append(&queue.items[0].url, generation).await?;
+        tokio::spawn(Self::prefetch(queue.clone(), generation));
         Ok(())
     }
+
+    fn stop(&self) {
+        // A stopped sink cannot be reused, it is replaced on the next start.
+        if let Some(sink) = self.sink.lock().unwrap().take() {
+            sink.stop();
+        }
+        for download in self.downloads.lock().unwrap().drain(..) {
+            download.cancel();
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        let sink_empty = self.sink().map(|sink| sink.is_empty()).unwrap_or(true);
+        let all_appended = self.n_appended.load(Ordering::SeqCst) >= self.items.len()
+            || self.prefetch_failed.load(Ordering::SeqCst);
+        sink_empty && all_appended
+    }
+}
+
+impl HttpPlaybackHandle {
+    /// Title of the first item, if announced by the playlist.
+    pub fn title(&self) -> Option<&str> {
+        self.queue.items[0].title.as_deref()
+    }
 }
 
 #[async_trait]
@@ -54,57 +242,95 @@ impl PlaybackHandle for HttpPlaybackHandle {
     async fn stop(&self) -> Fallible<()> {
         // info!("Cancelling HTTP Player");
         // self.tx.send(()).unwrap();
-        self.sink.stop();
+        self.queue.generation.fetch_add(1, Ordering::SeqCst);
+        self.queue.stop();
         Ok(())
     }
     async fn is_complete(&self) -> Fallible<bool> {
-        Ok(self.sink.empty())
+        Ok(self.queue.is_complete())
     }
 
     async fn pause(&self) -> Fallible<()> {
-        self.sink.pause();
+        if let Some(sink) = self.queue.sink() {
+            sink.pause();
+        }
         Ok(())
     }
-    async fn cont(&self, pause_state: PauseState) -> Fallible<()> {
-        self.sink.play();
+    async fn cont(&self, _pause_state: PauseState) -> Fallible<()> {
+        if let Some(sink) = self.queue.sink() {
+            sink.play();
+        }
         Ok(())
     }
 
     async fn replay(&self) -> Fallible<()> {
-        self.sink.stop();
-        self.queue().await?;
-        self.sink.play();
-        Ok(())
+        PlaybackQueue::start(&self.queue).await
     }
 }
 
 impl HttpPlayer {
-    pub fn new() -> Fallible<Self> {
+    pub fn new(ducking: Ducking, audio_backend: Arc<dyn AudioBackend>) -> Fallible<Self> {
         info!("Creating new HttpPlayer...");
         // let (tx, rx) = crossbeam_channel::bounded(1);
-        let http_client = Arc::new(reqwest::Client::new());
-        let basic_auth = {
-            let username: Option<String> = env::var("HTTP_PLAYER_USERNAME")
-                .map(|x| Some(x))
-                .unwrap_or(None);
-            let password: Option<String> = env::var("HTTP_PLAYER_PASSWORD")
-                .map(|x| Some(x))
-                .unwrap_or(None);
-            if let (Some(username), Some(password)) = (username, password) {
-                Some((username, password))
-            } else {
-                None
-            }
+        let config: Config = envy::from_env()?;
+        let credentials = Credentials::new_from_env()?;
+        let http_client = Arc::new(AuthenticatingClient::new(credentials)?);
+        let normalizer = if config.loudness_normalization {
+            let cache = GainCache::load(&config.loudness_gain_cache_file)?;
+            Some(Arc::new(Normalizer::new(
+                cache,
+                config.loudness_max_gain_db,
+            )))
+        } else {
+            None
         };
+        let equalizer = Equalizer::new(config.equalizer_settings()?);
         let player = HttpPlayer {
             _handle: None,
-            basic_auth,
             http_client,
+            buffer_config: config.into(),
+            audio_backend,
+            normalizer,
+            equalizer,
+            ducking,
         };
 
         Ok(player)
     }
 
+    pub fn http_client(&self) -> Arc<AuthenticatingClient> {
+        self.http_client.clone()
+    }
+
+    /// Settings changes also apply to ongoing playbacks.
+    pub fn equalizer(&self) -> &Equalizer {
+        &self.equalizer
+    }
+
+    /// Expands M3U playlists into their items, other URLs are played as they are.
+    async fn resolve_items(&self, url: &str) -> Fallible<Vec<PlaylistItem>> {
+        let parsed_url = Url::parse(url)?;
+        if !playlist::is_playlist_url(&parsed_url) {
+            return Ok(vec![PlaylistItem {
+                url: url.to_string(),
+                title: None,
+            }]);
+        }
+        let content = self
+            .http_client
+            .fetch(url)
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let items = playlist::parse_m3u(&parsed_url, &content)?;
+        if items.is_empty() {
+            return Err(Error::EmptyPlaylist.into());
+        }
+        info!("Resolved playlist {} into {} items", url, items.len());
+        Ok(items)
+    }
+
     pub async fn start_playback(
         &self,
         url: &str,
@@ -113,13 +339,8 @@ impl HttpPlayer {
         if let Some(pause_state) = pause_state {
             warn!("Ignoring pause state: {:?}", pause_state);
         }
-        let device = rodio::default_output_device().unwrap();
-        let url = url.clone().to_string();
-        let http_client = self.http_client.clone();
-        let basic_auth = self.basic_auth.clone();
+        let items = self.resolve_items(url).await?;
         let (tx, rx) = crossbeam_channel::bounded(1);
-        let sink = Arc::new(Sink::new(&device));
-        let sink_cp = sink.clone();
         let _handle = Builder::new()
             .name("http-player".to_string())
             .spawn(move || {
@@ -131,14 +352,22 @@ impl HttpPlayer {
             })
             .unwrap();
 
-        let handle = HttpPlaybackHandle {
-            tx,
-            sink,
-            basic_auth,
-            url,
+        let queue = Arc::new(PlaybackQueue {
+            items,
+            audio_backend: self.audio_backend.clone(),
+            sink: Mutex::new(None),
             http_client: self.http_client.clone(),
-        };
-        handle.queue().await?;
+            buffer_config: self.buffer_config.clone(),
+            downloads: Mutex::new(Vec::new()),
+            normalizer: self.normalizer.clone(),
+            equalizer: self.equalizer.clone(),
+            ducking: self.ducking.clone(),
+            generation: AtomicUsize::new(0),
+            n_appended: AtomicUsize::new(0),
+            prefetch_failed: AtomicBool::new(false),
+        });
+        let handle = HttpPlaybackHandle { _tx: tx, queue };
+        PlaybackQueue::start(&handle.queue).await?;
         handle
             .cont(PauseState {
                 pos: std::time::Duration::from_secs(0),
@@ -155,6 +384,7 @@ pub mod err {
     pub enum Error {
         IO(std::io::Error),
         Http(reqwest::Error),
+        EmptyPlaylist,
     }
 
     impl Display for Error {
@@ -162,6 +392,7 @@ pub mod err {
             match self {
                 Error::IO(err) => write!(f, "HTTP Player IO Error {}", err),
                 Error::Http(err) => write!(f, "HTTP Player HTTP Error {}", err),
+                Error::EmptyPlaylist => write!(f, "HTTP Player Error: Playlist is empty"),
             }
         }
     }
@@ -180,3 +411,92 @@ pub mod err {
 
     impl std::error::Error for Error {}
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read, Write};
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    use hound::{WavReader, WavSpec, WavWriter};
+
+    use super::super::audio_output::WavBackend;
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 22050;
+
+    fn test_recording() -> Vec<u8> {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut data = Cursor::new(Vec::new());
+        let mut writer = WavWriter::new(&mut data, spec).unwrap();
+        for i in 0..SAMPLE_RATE / 2 {
+            writer.write_sample((i % 100) as i16 * 100).unwrap();
+        }
+        writer.finalize().unwrap();
+        data.into_inner()
+    }
+
+    /// Serves the body to every request, returns the URL to request.
+    fn serve(body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/recording.wav", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: audio/wav\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(&body);
+            }
+        });
+        url
+    }
+
+    fn test_player(audio_backend: Arc<dyn AudioBackend>) -> HttpPlayer {
+        HttpPlayer {
+            _handle: None,
+            http_client: Arc::new(AuthenticatingClient::new(Credentials::default()).unwrap()),
+            buffer_config: BufferConfig::default(),
+            audio_backend,
+            normalizer: None,
+            equalizer: Equalizer::new(EqualizerSettings::default()),
+            ducking: Ducking::new(),
+        }
+    }
+
+    #[test]
+    fn http_stream_is_rendered_into_wav_backend() {
+        let path = std::env::temp_dir().join(format!(
+            "rustberry-http-player-test-{}.wav",
+            std::process::id()
+        ));
+        let url = serve(test_recording());
+        let player = test_player(Arc::new(WavBackend::new(path.clone())));
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let handle = player.start_playback(&url, None).await.unwrap();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !handle.is_complete().await.unwrap() {
+                assert!(Instant::now() < deadline, "playback timed out");
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        });
+
+        let mut rendered = WavReader::open(&path).unwrap();
+        assert_eq!(rendered.spec().sample_rate, SAMPLE_RATE);
+        let rendered: Vec<i16> = rendered.samples().map(|s| s.unwrap()).collect();
+        let mut original = WavReader::new(Cursor::new(test_recording())).unwrap();
+        let original: Vec<i16> = original.samples().map(|s| s.unwrap()).collect();
+        assert_eq!(rendered, original);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -94,7 +94,7 @@ pub mod gpio_cdev {
             "gpio-cdev backend".to_string()
         }
         fn switch_on(&self, led: Led) -> Fallible<()> {
-            if let Some(ref led_handle) = self.leds.get(&led) {
+            if let Some(led_handle) = self.leds.get(&led) {
                 led_handle.set_value(1).map_err(|err| {
                     Error::IO(format!("Failed to switch on LED {:?}: {:?}", &led, err))
                 })?;
@@ -103,7 +103,7 @@ pub mod gpio_cdev {
             Ok(())
         }
         fn switch_off(&self, led: Led) -> Fallible<()> {
-            if let Some(ref led_handle) = self.leds.get(&led) {
+            if let Some(led_handle) = self.leds.get(&led) {
                 led_handle.set_value(0).map_err(|err| {
                     Error::IO(format!("Failed to switch off LED {:?}: {:?}", &led, err))
                 })?;
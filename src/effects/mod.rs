@@ -11,37 +11,54 @@ effects:
 
 */
 
+pub mod announcer;
+pub mod audio_output;
+pub mod earcon;
 pub mod http_player;
 pub mod led;
+pub mod output_device;
+pub mod podcast_player;
 pub mod spotify;
 
 use std::sync::Arc;
 
+use crate::components::ducking::Ducking;
+use crate::components::equalizer::EqualizerSettings;
 use crate::config::Config;
+use announcer::Announcer;
 use async_trait::async_trait;
+use earcon::{Earcon, EarconPlayer};
 use failure::Fallible;
 use http_player::HttpPlayer;
 use led::{Led, LedController};
+use podcast_player::PodcastPlayer;
 use slog_scope::{info, warn};
 use spotify::player::SpotifyPlayer;
 use std::process::Command;
 
 use crate::player::{DynPlaybackHandle, PauseState, PlaybackHandle, PlaybackResource};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Effects {
     PlayHttp { url: String },
     StopHttp,
     PlaySpotify { spotify_uri: String },
+    PlayPodcast { feed_url: String },
     StopSpotify,
     LedOn,
     LedOff,
     GenericCommand(String),
+    SetEqualizer(EqualizerSettings),
+    PlayEarcon(Earcon),
+    Announce(String),
 }
 
 pub struct ProdInterpreter {
     spotify_player: SpotifyPlayer,
-    http_player: HttpPlayer,
+    http_player: Arc<HttpPlayer>,
+    podcast_player: PodcastPlayer,
+    earcon_player: EarconPlayer,
+    announcer: Arc<Announcer>,
     led_controller: Arc<Box<dyn LedController + 'static + Send + Sync>>,
     _config: Config,
 }
@@ -59,6 +76,11 @@ pub trait Interpreter {
     fn led_on(&self) -> Fallible<()>;
     fn led_off(&self) -> Fallible<()>;
     fn generic_command(&self, cmd: String) -> Fallible<()>;
+    fn set_equalizer(&self, settings: EqualizerSettings) -> Fallible<()>;
+    fn play_earcon(&self, earcon: Earcon) -> Fallible<()>;
+    fn announce(&self, text: String) -> Fallible<()>;
+    /// Releases external resources before the system shuts down.
+    fn shutdown(&self) -> Fallible<()>;
 }
 
 #[async_trait]
@@ -74,20 +96,44 @@ impl Interpreter for ProdInterpreter {
         pause_state: Option<PauseState>,
     ) -> Fallible<DynPlaybackHandle> {
         use PlaybackResource::*;
-        match res {
-            SpotifyUri(uri) => self
+        let announce_title = self.announcer.announce_titles() && pause_state.is_none();
+        let result = match res {
+            SpotifyUri(resource) => self
                 .spotify_player
-                .start_playback(&uri, pause_state)
+                .start_playback(&resource, pause_state)
                 .await
-                .map(|x| Box::new(x) as DynPlaybackHandle)
-                .map_err(|err| err.into()),
+                .map(|x| Box::new(x) as DynPlaybackHandle),
             Http(url) => self
                 .http_player
                 .start_playback(&url, pause_state)
                 .await
-                .map(|x| Box::new(x) as DynPlaybackHandle)
-                .map_err(|err| err.into()),
+                .map(|handle| {
+                    if announce_title {
+                        if let Some(title) = handle.title() {
+                            self.announce_in_background(title.to_string());
+                        }
+                    }
+                    Box::new(handle) as DynPlaybackHandle
+                }),
+            Podcast(feed_url) => self
+                .podcast_player
+                .start_playback(&feed_url, pause_state)
+                .await
+                .map(|handle| {
+                    if announce_title {
+                        if let Some(ref title) = handle.episode().title {
+                            self.announce_in_background(title.clone());
+                        }
+                    }
+                    Box::new(handle) as DynPlaybackHandle
+                }),
+        };
+        if let Err(ref err) = result {
+            if announcer::is_connection_error(err) {
+                self.announce_in_background(self.announcer.no_internet_announcement().to_string());
+            }
         }
+        result
     }
 
     // fn stop(&self, handle: DynPlaybackHandle) -> Fallible<()> {
@@ -126,6 +172,23 @@ impl Interpreter for ProdInterpreter {
             }
         }
     }
+    fn set_equalizer(&self, settings: EqualizerSettings) -> Fallible<()> {
+        info!("Setting equalizer to {:?}", settings);
+        self.http_player.equalizer().set(settings);
+        Ok(())
+    }
+    fn play_earcon(&self, earcon: Earcon) -> Fallible<()> {
+        self.earcon_player.play(earcon)
+    }
+    fn announce(&self, text: String) -> Fallible<()> {
+        self.announcer.announce(&text)
+    }
+    fn shutdown(&self) -> Fallible<()> {
+        // Lets the shutdown earcon finish.
+        self.earcon_player.wait();
+        self.spotify_player.terminate();
+        Ok(())
+    }
 }
 
 impl ProdInterpreter {
@@ -134,14 +197,37 @@ impl ProdInterpreter {
         let led_controller = Arc::new(Box::new(led::gpio_cdev::GpioCdev::new()?)
             as Box<dyn LedController + 'static + Send + Sync>);
         let spotify_player = SpotifyPlayer::new(&config)?;
-        let http_player = HttpPlayer::new()?;
+        let ducking = Ducking::new();
+        // Shared by all players, e.g. the WAV backend renders all of them into a single file.
+        let audio_backend = audio_output::backend_from_env()?;
+        let http_player = Arc::new(HttpPlayer::new(ducking.clone(), audio_backend.clone())?);
+        let podcast_player = PodcastPlayer::new(http_player.clone())?;
+        let earcon_player = EarconPlayer::new(audio_backend.clone())?;
+        let announcer = Arc::new(Announcer::new(
+            ducking,
+            spotify_player.ducking()?,
+            audio_backend,
+        )?);
         Ok(ProdInterpreter {
             spotify_player,
             http_player,
+            podcast_player,
+            earcon_player,
+            announcer,
             led_controller,
             _config: config,
         })
     }
+
+    /// Announces while playback continues.
+    fn announce_in_background(&self, text: String) {
+        let announcer = self.announcer.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = announcer.announce(&text) {
+                warn!("Failed to announce '{}': {}", text, err);
+            }
+        });
+    }
 }
 
 pub mod test {
@@ -162,20 +248,26 @@ pub mod test {
         }
     }
 
-    struct DummyPlaybackHandle;
+    /// Reports stopping and pausing via the effect matching the type of playback.
+    struct DummyPlaybackHandle {
+        tx: Sender<Effects>,
+        stop_effect: Effects,
+    }
 
     #[async_trait]
     impl PlaybackHandle for DummyPlaybackHandle {
         async fn stop(&self) -> Fallible<()> {
+            self.tx.send(self.stop_effect.clone())?;
             Ok(())
         }
         async fn is_complete(&self) -> Fallible<bool> {
             Ok(true)
         }
         async fn pause(&self) -> Fallible<()> {
+            self.tx.send(self.stop_effect.clone())?;
             Ok(())
         }
-        async fn cont(&self, pause_state: PauseState) -> Fallible<()> {
+        async fn cont(&self, _pause_state: PauseState) -> Fallible<()> {
             Ok(())
         }
         async fn replay(&self) -> Fallible<()> {
@@ -192,19 +284,34 @@ pub mod test {
         async fn play(
             &self,
             res: PlaybackResource,
-            pause_state: Option<PauseState>,
+            _pause_state: Option<PauseState>,
         ) -> Fallible<DynPlaybackHandle> {
             use PlaybackResource::*;
 
-            match res {
-                SpotifyUri(uri) => self.tx.send(PlaySpotify {
-                    spotify_uri: uri.to_string().clone(),
-                })?,
-                Http(url) => self.tx.send(PlayHttp {
-                    url: url.to_string().clone(),
-                })?,
-            }
-            Ok(Box::new(DummyPlaybackHandle) as DynPlaybackHandle)
+            let stop_effect = match res {
+                SpotifyUri(resource) => {
+                    self.tx.send(PlaySpotify {
+                        spotify_uri: resource.uri().to_string(),
+                    })?;
+                    StopSpotify
+                }
+                Http(url) => {
+                    self.tx.send(PlayHttp {
+                        url: url.to_string().clone(),
+                    })?;
+                    StopHttp
+                }
+                Podcast(feed_url) => {
+                    self.tx.send(PlayPodcast {
+                        feed_url: feed_url.to_string().clone(),
+                    })?;
+                    StopHttp
+                }
+            };
+            Ok(Box::new(DummyPlaybackHandle {
+                tx: self.tx.clone(),
+                stop_effect,
+            }) as DynPlaybackHandle)
         }
 
         fn led_on(&self) -> Fallible<()> {
@@ -221,5 +328,20 @@ pub mod test {
                 .unwrap();
             Ok(())
         }
+        fn set_equalizer(&self, settings: EqualizerSettings) -> Fallible<()> {
+            self.tx.send(SetEqualizer(settings)).unwrap();
+            Ok(())
+        }
+        fn play_earcon(&self, earcon: Earcon) -> Fallible<()> {
+            self.tx.send(PlayEarcon(earcon)).unwrap();
+            Ok(())
+        }
+        fn announce(&self, text: String) -> Fallible<()> {
+            self.tx.send(Announce(text)).unwrap();
+            Ok(())
+        }
+        fn shutdown(&self) -> Fallible<()> {
+            Ok(())
+        }
     }
 }
@@ -0,0 +1,80 @@
+use rodio::{Device, DeviceTrait};
+use slog_scope::{info, warn};
+
+pub use err::*;
+
+/// Returns the names of all available audio output devices.
+pub fn list_output_devices() -> Result<Vec<String>, Error> {
+    let devices =
+        rodio::output_devices().map_err(|err| Error::Enumeration(err.to_string()))?;
+    Ok(devices
+        .map(|device| device_name(&device))
+        .collect())
+}
+
+/// Selects the output device with the given name or the default output device if no name
+/// is given.
+pub fn select_output_device(name: Option<&str>) -> Result<Device, Error> {
+    match name {
+        None => rodio::default_output_device().ok_or(Error::NoDefaultDevice),
+        Some(name) => {
+            let devices =
+                rodio::output_devices().map_err(|err| Error::Enumeration(err.to_string()))?;
+            let mut available = Vec::new();
+            for device in devices {
+                let device_name = device_name(&device);
+                if device_name == name {
+                    info!("Using audio output device '{}'", name);
+                    return Ok(device);
+                }
+                available.push(device_name);
+            }
+            warn!(
+                "Audio output device '{}' not found, available devices: {:?}",
+                name, available
+            );
+            Err(Error::DeviceNotFound {
+                name: name.to_string(),
+                available,
+            })
+        }
+    }
+}
+
+fn device_name(device: &Device) -> String {
+    device
+        .name()
+        .unwrap_or_else(|err| format!("<unnamed device: {}>", err))
+}
+
+pub mod err {
+    use std::fmt::{self, Display};
+
+    #[derive(Debug)]
+    pub enum Error {
+        NoDefaultDevice,
+        DeviceNotFound {
+            name: String,
+            available: Vec<String>,
+        },
+        Enumeration(String),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::NoDefaultDevice => write!(f, "No default audio output device available"),
+                Error::DeviceNotFound { name, available } => write!(
+                    f,
+                    "Audio output device '{}' not found (available: {:?})",
+                    name, available
+                ),
+                Error::Enumeration(err) => {
+                    write!(f, "Failed to enumerate audio output devices: {}", err)
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
@@ -0,0 +1,134 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use failure::Fallible;
+use serde::Deserialize;
+use slog_scope::{error, info};
+
+use crate::components::http_credentials::AuthenticatingClient;
+use crate::components::podcast::{self, Episode, EpisodePolicy, PlayedEpisodes};
+use crate::player::{PauseState, PlaybackHandle};
+
+use super::http_player::{HttpPlaybackHandle, HttpPlayer};
+
+#[derive(Deserialize, Debug, Clone)]
+struct Config {
+    #[serde(default)]
+    podcast_episode_policy: EpisodePolicy,
+    #[serde(default = "default_history_file")]
+    podcast_history_file: PathBuf,
+}
+
+fn default_history_file() -> PathBuf {
+    PathBuf::from("/var/lib/rustberry/podcast-history.json")
+}
+
+pub struct PodcastPlayer {
+    http_player: Arc<HttpPlayer>,
+    http_client: Arc<AuthenticatingClient>,
+    policy: EpisodePolicy,
+    played_episodes: Arc<Mutex<PlayedEpisodes>>,
+}
+
+pub struct PodcastPlaybackHandle {
+    feed_url: String,
+    episode: Episode,
+    handle: HttpPlaybackHandle,
+    played_episodes: Arc<Mutex<PlayedEpisodes>>,
+}
+
+impl PodcastPlaybackHandle {
+    pub fn episode(&self) -> &Episode {
+        &self.episode
+    }
+
+    fn mark_played(&self) {
+        let mut played_episodes = self.played_episodes.lock().unwrap();
+        if let Err(err) = played_episodes.mark_played(&self.feed_url, &self.episode.id) {
+            error!(
+                "Failed to remember podcast episode {} as played: {}",
+                self.episode.id, err
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl PlaybackHandle for PodcastPlaybackHandle {
+    async fn stop(&self) -> Fallible<()> {
+        self.handle.stop().await
+    }
+    async fn is_complete(&self) -> Fallible<bool> {
+        let complete = self.handle.is_complete().await?;
+        if complete {
+            self.mark_played();
+        }
+        Ok(complete)
+    }
+    async fn pause(&self) -> Fallible<()> {
+        self.handle.pause().await
+    }
+    async fn cont(&self, pause_state: PauseState) -> Fallible<()> {
+        self.handle.cont(pause_state).await
+    }
+    async fn replay(&self) -> Fallible<()> {
+        self.handle.replay().await
+    }
+    async fn position(&self) -> Fallible<Option<Duration>> {
+        self.handle.position().await
+    }
+}
+
+impl PodcastPlayer {
+    pub fn new(http_player: Arc<HttpPlayer>) -> Fallible<Self> {
+        info!("Creating new PodcastPlayer...");
+        let config: Config = envy::from_env()?;
+        let played_episodes = PlayedEpisodes::load(&config.podcast_history_file)?;
+        let http_client = http_player.http_client();
+        Ok(PodcastPlayer {
+            http_player,
+            http_client,
+            policy: config.podcast_episode_policy,
+            played_episodes: Arc::new(Mutex::new(played_episodes)),
+        })
+    }
+
+    async fn select_episode(&self, feed_url: &str) -> Fallible<Episode> {
+        let feed = self
+            .http_client
+            .fetch(feed_url)
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let episodes = podcast::parse_feed(&feed)?;
+        let played = self.played_episodes.lock().unwrap().played(feed_url);
+        let episode = podcast::select_episode(&episodes, &played, self.policy)
+            .ok_or(podcast::Error::NoEpisodes)?;
+        Ok(episode.clone())
+    }
+
+    pub async fn start_playback(
+        &self,
+        feed_url: &str,
+        pause_state: Option<PauseState>,
+    ) -> Result<PodcastPlaybackHandle, failure::Error> {
+        let episode = self.select_episode(feed_url).await?;
+        info!(
+            "Playing podcast episode {:?} from feed {}",
+            episode, feed_url
+        );
+        let handle = self
+            .http_player
+            .start_playback(&episode.url, pause_state)
+            .await?;
+        Ok(PodcastPlaybackHandle {
+            feed_url: feed_url.to_string(),
+            episode,
+            handle,
+            played_episodes: self.played_episodes.clone(),
+        })
+    }
+}
@@ -0,0 +1,654 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use http::header::{self, AUTHORIZATION};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use slog_scope::{error, warn};
+use url::Url;
+
+use crate::components::access_token_provider::AccessTokenProvider;
+use crate::components::spotify_uri::SpotifyOffset;
+
+pub use err::*;
+
+pub const DEFAULT_API_BASE_URL: &str = "https://api.spotify.com/v1/";
+pub const DEFAULT_ACCOUNTS_BASE_URL: &str = "https://accounts.spotify.com/";
+
+/// How long a request waits for a forced token refresh before giving up.
+const TOKEN_REFRESH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bounds the retries of rate limited requests and transient failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of attempts including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further retry. Used unless Spotify
+    /// asks for a specific delay via `Retry-After`.
+    pub initial_delay: Duration,
+    /// Requests rate limited for longer than this fail right away.
+    pub max_retry_after: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(500),
+            max_retry_after: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay before the next attempt, `None` if the error is final.
+    fn delay(&self, err: &Error, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let backoff = self.initial_delay * 2u32.pow(attempt - 1);
+        match err {
+            Error::RateLimited(Some(retry_after)) if *retry_after > self.max_retry_after => None,
+            Error::RateLimited(retry_after) => Some(retry_after.unwrap_or(backoff)),
+            Error::Server(_) => Some(backoff),
+            Error::Http(err) if !err.is_builder() => Some(backoff),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a base URL, making sure that relative endpoint paths are joined below it.
+pub fn base_url(url: &str) -> Result<Url, url::ParseError> {
+    if url.ends_with('/') {
+        Url::parse(url)
+    } else {
+        Url::parse(&format!("{}/", url))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    pub is_active: bool,
+    /// Not reported by devices without volume control.
+    pub volume_percent: Option<u8>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct DevicesResponse {
+    pub devices: Vec<Device>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct StartPlayback {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uris: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<SpotifyOffset>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position_ms: Option<u128>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ErrorResponse {
+    error: ApiError,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ApiError {
+    message: Option<String>,
+    reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct CurrentlyPlaying {
+    pub is_playing: bool,
+    pub progress_ms: Option<u64>,
+    pub item: Option<Item>,
+    pub context: Option<PlaybackContext>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct TransferPlayback<'a> {
+    device_ids: [&'a str; 1],
+    play: bool,
+}
+
+/// Playback state of the account, including the device playing.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct PlaybackState {
+    pub device: Option<Device>,
+    pub is_playing: bool,
+    pub progress_ms: Option<u64>,
+    pub item: Option<Item>,
+    pub context: Option<PlaybackContext>,
+}
+
+impl PlaybackState {
+    /// True if a device other than the given one is playing.
+    pub fn is_playing_elsewhere(&self, device_id: &str) -> bool {
+        self.is_playing && self.device.as_ref().is_some_and(|x| x.id != device_id)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct Item {
+    pub uri: String,
+    pub duration_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct PlaybackContext {
+    pub uri: String,
+}
+
+/// Typed access to the Spotify Web API player endpoints.
+#[derive(Debug, Clone)]
+pub struct SpotifyClient {
+    http_client: Client,
+    api_base_url: Url,
+    access_token_provider: Arc<AccessTokenProvider>,
+    retry_policy: RetryPolicy,
+}
+
+impl SpotifyClient {
+    pub fn new(api_base_url: Url, access_token_provider: Arc<AccessTokenProvider>) -> Self {
+        SpotifyClient {
+            http_client: Client::new(),
+            api_base_url,
+            access_token_provider,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn access_token_provider(&self) -> &Arc<AccessTokenProvider> {
+        &self.access_token_provider
+    }
+
+    fn request(&self, method: Method, path: &str) -> Result<RequestBuilder, Error> {
+        let url = self.api_base_url.join(path)?;
+        let access_token = self.access_token_provider.get_bearer_token()?;
+        Ok(self
+            .http_client
+            .request(method, url)
+            .header(AUTHORIZATION, access_token))
+    }
+
+    /// Requests without a payload still need to carry a content length for Spotify.
+    fn empty_body(req: RequestBuilder) -> RequestBuilder {
+        req.body("").header(header::CONTENT_LENGTH, 0)
+    }
+
+    /// Sends the request built by `build`. If the access token is rejected, a token refresh
+    /// is forced and the request is rebuilt and sent once more with the fresh token. Rate
+    /// limited requests, server errors and transport failures are retried according to the
+    /// retry policy.
+    async fn send<F>(&self, msg: &str, build: F) -> Result<Response, Error>
+    where
+        F: Fn() -> Result<RequestBuilder, Error>,
+    {
+        let mut attempt = 1;
+        let mut token_refreshed = false;
+        loop {
+            let generation = self.access_token_provider.generation();
+            let err = match build()?.send().await {
+                Ok(rsp) if rsp.status().is_success() => return Ok(rsp),
+                Ok(rsp) if rsp.status() == StatusCode::UNAUTHORIZED && !token_refreshed => {
+                    warn!("{}: Access token rejected, forcing token refresh", msg);
+                    token_refreshed = true;
+                    self.access_token_provider.force_refresh();
+                    if self.wait_for_fresh_token(generation).await {
+                        continue;
+                    }
+                    Self::status_error(rsp).await
+                }
+                Ok(rsp) => Self::status_error(rsp).await,
+                Err(err) => Error::Http(err),
+            };
+            error!("{}: {}", msg, err);
+            let delay = match self.retry_policy.delay(&err, attempt) {
+                Some(delay) => delay,
+                None => return Err(err),
+            };
+            warn!(
+                "{}: Retrying in {:?} (attempt {} of {})",
+                msg,
+                delay,
+                attempt + 1,
+                self.retry_policy.max_attempts
+            );
+            tokio::time::delay_for(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Classifies a failed response using its status, the `Retry-After` header and the
+    /// reason included in Spotify's error object.
+    async fn status_error(rsp: Response) -> Error {
+        let status = rsp.status();
+        let retry_after = rsp
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.trim().parse().ok())
+            .map(Duration::from_secs);
+        let api_error = rsp.json::<ErrorResponse>().await.ok().map(|x| x.error);
+        let reason = api_error.as_ref().and_then(|x| x.reason.as_deref());
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            Error::RateLimited(retry_after)
+        } else if reason == Some("NO_ACTIVE_DEVICE") {
+            Error::NoActiveDevice
+        } else if status.is_server_error() {
+            Error::Server(status)
+        } else {
+            Error::Status(status, api_error.and_then(|x| x.message))
+        }
+    }
+
+    async fn wait_for_fresh_token(&self, generation: u64) -> bool {
+        let deadline = Instant::now() + TOKEN_REFRESH_TIMEOUT;
+        while Instant::now() < deadline {
+            if self.access_token_provider.generation() != generation {
+                return true;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        warn!(
+            "No fresh access token received within {:?}",
+            TOKEN_REFRESH_TIMEOUT
+        );
+        false
+    }
+
+    pub async fn devices(&self) -> Result<Vec<Device>, Error> {
+        let rsp = self
+            .send("Failed to retrieve Spotify devices", || {
+                self.request(Method::GET, "me/player/devices")
+            })
+            .await?;
+        Ok(rsp.json::<DevicesResponse>().await?.devices)
+    }
+
+    pub async fn play(&self, device_id: &str, payload: &StartPlayback) -> Result<(), Error> {
+        self.send("Failed to start Spotify playback", || {
+            Ok(self
+                .request(Method::PUT, "me/player/play")?
+                .query(&[("device_id", device_id)])
+                .json(payload))
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub async fn pause(&self, device_id: &str) -> Result<(), Error> {
+        self.send("Failed to pause Spotify playback", || {
+            let req = self
+                .request(Method::PUT, "me/player/pause")?
+                .query(&[("device_id", device_id)]);
+            Ok(Self::empty_body(req))
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub async fn seek(&self, device_id: &str, position: Duration) -> Result<(), Error> {
+        let position_ms = position.as_millis().to_string();
+        self.send("Failed to seek Spotify playback", || {
+            let req = self
+                .request(Method::PUT, "me/player/seek")?
+                .query(&[("device_id", device_id), ("position_ms", &position_ms)]);
+            Ok(Self::empty_body(req))
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub async fn set_volume(&self, device_id: &str, volume_percent: u8) -> Result<(), Error> {
+        let volume_percent = volume_percent.min(100).to_string();
+        self.send("Failed to set Spotify volume", || {
+            let req = self.request(Method::PUT, "me/player/volume")?.query(&[
+                ("device_id", device_id),
+                ("volume_percent", &volume_percent),
+            ]);
+            Ok(Self::empty_body(req))
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Makes the device the active device without starting playback on it.
+    pub async fn transfer_playback(&self, device_id: &str) -> Result<(), Error> {
+        let payload = TransferPlayback {
+            device_ids: [device_id],
+            play: false,
+        };
+        self.send("Failed to transfer Spotify playback", || {
+            Ok(self.request(Method::PUT, "me/player")?.json(&payload))
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Returns `None` if there is no active device.
+    pub async fn playback_state(&self) -> Result<Option<PlaybackState>, Error> {
+        let rsp = self
+            .send("Failed to retrieve Spotify playback state", || {
+                // Episodes are only included on request.
+                Ok(self
+                    .request(Method::GET, "me/player")?
+                    .query(&[("additional_types", "track,episode")]))
+            })
+            .await?;
+        if rsp.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Ok(Some(rsp.json().await?))
+    }
+
+    /// Returns `None` if nothing is being played at all.
+    pub async fn currently_playing(&self) -> Result<Option<CurrentlyPlaying>, Error> {
+        let rsp = self
+            .send("Failed to retrieve currently-playing information", || {
+                let req = self.request(Method::GET, "me/player/currently-playing")?;
+                Ok(Self::empty_body(req))
+            })
+            .await?;
+        if rsp.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Ok(Some(rsp.json().await?))
+    }
+}
+
+pub mod err {
+    use std::fmt::{self, Display};
+
+    use std::time::Duration;
+
+    use reqwest::StatusCode;
+
+    use crate::components::access_token_provider::AtpError;
+
+    #[derive(Debug)]
+    pub enum Error {
+        Http(reqwest::Error),
+        Token(AtpError),
+        Url(url::ParseError),
+        /// Rate limited by Spotify, including the delay requested via `Retry-After`.
+        RateLimited(Option<Duration>),
+        /// Spotify failed with a 5xx status.
+        Server(StatusCode),
+        /// The player command requires an active device.
+        NoActiveDevice,
+        /// Any other unsuccessful status, including Spotify's error message.
+        Status(StatusCode, Option<String>),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::Http(err) => write!(f, "Spotify HTTP Error {}", err),
+                Error::Token(err) => write!(f, "Spotify Access Token Error {}", err),
+                Error::Url(err) => write!(f, "Spotify URL Error {}", err),
+                Error::RateLimited(Some(retry_after)) => {
+                    write!(
+                        f,
+                        "Spotify rate limit exceeded, retry after {:?}",
+                        retry_after
+                    )
+                }
+                Error::RateLimited(None) => write!(f, "Spotify rate limit exceeded"),
+                Error::Server(status) => write!(f, "Spotify Server Error {}", status),
+                Error::NoActiveDevice => write!(f, "No active Spotify device"),
+                Error::Status(status, Some(message)) => {
+                    write!(f, "Spotify HTTP Failure {}: {}", status, message)
+                }
+                Error::Status(status, None) => write!(f, "Spotify HTTP Failure {}", status),
+            }
+        }
+    }
+
+    impl From<reqwest::Error> for Error {
+        fn from(err: reqwest::Error) -> Self {
+            Error::Http(err)
+        }
+    }
+
+    impl From<AtpError> for Error {
+        fn from(err: AtpError) -> Self {
+            Error::Token(err)
+        }
+    }
+
+    impl From<url::ParseError> for Error {
+        fn from(err: url::ParseError) -> Self {
+            Error::Url(err)
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::runtime::Runtime;
+
+    use super::super::mock_server::MockServer;
+    use super::*;
+
+    #[test]
+    fn player_endpoints_are_requested_below_base_url() {
+        let server = MockServer::start();
+        server.respond(
+            "POST",
+            "/accounts/api/token",
+            200,
+            r#"{"access_token": "token", "token_type": "Bearer", "scope": "", "expires_in": 3600}"#,
+        );
+        server.respond(
+            "GET",
+            "/v1/me/player/devices",
+            200,
+            r#"{"devices": [{"id": "abc", "name": "jukebox", "is_active": true}]}"#,
+        );
+        server.respond("PUT", "/v1/me/player/play", 204, "");
+        server.respond("GET", "/v1/me/player/currently-playing", 204, "");
+
+        let accounts_base_url = server.base_url().join("accounts").unwrap();
+        let access_token_provider = Arc::new(
+            AccessTokenProvider::new(
+                &base_url(accounts_base_url.as_str()).unwrap(),
+                "id",
+                "secret",
+                Some("refresh"),
+                None,
+            )
+            .unwrap(),
+        );
+        access_token_provider.wait_for_token().unwrap();
+        let client = SpotifyClient::new(
+            server.base_url().join("v1/").unwrap(),
+            access_token_provider,
+        );
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let devices = client.devices().await.unwrap();
+            assert_eq!(
+                devices,
+                vec![Device {
+                    id: "abc".to_string(),
+                    name: "jukebox".to_string(),
+                    is_active: true,
+                    volume_percent: None,
+                }]
+            );
+            let payload = StartPlayback {
+                context_uri: Some("spotify:album:xyz".to_string()),
+                uris: None,
+                offset: None,
+                position_ms: None,
+            };
+            client.play("abc", &payload).await.unwrap();
+            assert_eq!(client.currently_playing().await.unwrap(), None);
+            assert!(client.pause("abc").await.is_err());
+        });
+
+        let requests: Vec<(String, String)> = server
+            .requests()
+            .into_iter()
+            .map(|req| (req.method, req.path))
+            .collect();
+        assert_eq!(
+            &requests[1..],
+            &[
+                ("GET".to_string(), "/v1/me/player/devices".to_string()),
+                (
+                    "PUT".to_string(),
+                    "/v1/me/player/play?device_id=abc".to_string()
+                ),
+                (
+                    "GET".to_string(),
+                    "/v1/me/player/currently-playing".to_string()
+                ),
+                (
+                    "PUT".to_string(),
+                    "/v1/me/player/pause?device_id=abc".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            server.requests()[2].body,
+            r#"{"context_uri":"spotify:album:xyz"}"#
+        );
+    }
+
+    #[test]
+    fn rejected_token_is_refreshed() {
+        let server = MockServer::start();
+        server.respond(
+            "POST",
+            "/api/token",
+            200,
+            r#"{"access_token": "token", "token_type": "Bearer", "scope": "", "expires_in": 3600}"#,
+        );
+        server.respond("GET", "/v1/me/player/devices", 200, r#"{"devices": []}"#);
+        server.respond_times("GET", "/v1/me/player/devices", 401, "", 1);
+
+        let access_token_provider = Arc::new(
+            AccessTokenProvider::new(&server.base_url(), "id", "secret", Some("refresh"), None)
+                .unwrap(),
+        );
+        access_token_provider.wait_for_token().unwrap();
+        let client = SpotifyClient::new(
+            server.base_url().join("v1/").unwrap(),
+            access_token_provider.clone(),
+        );
+
+        let mut runtime = Runtime::new().unwrap();
+        let devices = runtime.block_on(client.devices()).unwrap();
+        assert!(devices.is_empty());
+        assert_eq!(access_token_provider.generation(), 2);
+        let paths: Vec<String> = server.requests().into_iter().map(|req| req.path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/api/token",
+                "/v1/me/player/devices",
+                "/api/token",
+                "/v1/me/player/devices"
+            ]
+        );
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        let server = MockServer::start();
+        server.respond(
+            "POST",
+            "/api/token",
+            200,
+            r#"{"access_token": "token", "token_type": "Bearer", "scope": "", "expires_in": 3600}"#,
+        );
+        server.respond("GET", "/v1/me/player/devices", 200, r#"{"devices": []}"#);
+        server.respond_times_with_headers(
+            "GET",
+            "/v1/me/player/devices",
+            429,
+            &[("Retry-After", "0")],
+            "",
+            1,
+        );
+        server.respond_times("GET", "/v1/me/player/devices", 503, "", 1);
+        server.respond(
+            "PUT",
+            "/v1/me/player/pause",
+            404,
+            r#"{"error": {"status": 404, "message": "Player command failed: No active device found", "reason": "NO_ACTIVE_DEVICE"}}"#,
+        );
+        server.respond_times_with_headers(
+            "PUT",
+            "/v1/me/player/play",
+            429,
+            &[("Retry-After", "3600")],
+            "",
+            1,
+        );
+
+        let access_token_provider = Arc::new(
+            AccessTokenProvider::new(&server.base_url(), "id", "secret", Some("refresh"), None)
+                .unwrap(),
+        );
+        access_token_provider.wait_for_token().unwrap();
+        let client = SpotifyClient::new(
+            server.base_url().join("v1/").unwrap(),
+            access_token_provider,
+        )
+        .with_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(10),
+            max_retry_after: Duration::from_secs(1),
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            assert!(client.devices().await.unwrap().is_empty());
+            assert!(matches!(
+                client.pause("abc").await,
+                Err(Error::NoActiveDevice)
+            ));
+            let payload = StartPlayback {
+                context_uri: Some("spotify:album:xyz".to_string()),
+                uris: None,
+                offset: None,
+                position_ms: None,
+            };
+            match client.play("abc", &payload).await {
+                Err(Error::RateLimited(retry_after)) => {
+                    assert_eq!(retry_after, Some(Duration::from_secs(3600)))
+                }
+                res => panic!("Unexpected result {:?}", res),
+            }
+        });
+
+        let paths: Vec<String> = server.requests().into_iter().map(|req| req.path).collect();
+        assert_eq!(
+            &paths[1..],
+            &[
+                "/v1/me/player/devices",
+                "/v1/me/player/devices",
+                "/v1/me/player/devices",
+                "/v1/me/player/pause?device_id=abc",
+                "/v1/me/player/play?device_id=abc",
+            ]
+        );
+    }
+}
@@ -1,19 +1,86 @@
+use std::process::ExitStatus;
 use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time::Duration;
 
+use slog::Level;
 use slog_scope::{error, info};
 
-use crate::components::access_token_provider::AccessTokenProvider;
-
+use super::devices::{DeviceEvent, DeviceService};
+use super::events::{self, DeviceEvents, EventHook};
+use super::librespot::{self, Credentials, LibrespotConfig};
 use super::util;
 
 pub enum SupervisorCommands {
     Terminate,
+    /// Replaces the running librespot process, also leaving a crash loop.
+    Restart,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SupervisorHealth {
+    Running,
+    /// Waiting for the restart backoff after librespot terminated.
+    Restarting,
+    /// librespot crashed too often in a row and is only restarted on request or after
+    /// `CRASH_LOOP_COOL_DOWN`.
+    CrashLoop,
+    Terminated,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SupervisorStatus {
+    pub health: SupervisorHealth,
+    pub pid: Option<u32>,
+    pub restart_count: u32,
+    pub last_exit: Option<ExitStatus>,
+}
+
+/// Delay before the first restart, doubled for every further crash in a row.
+const INITIAL_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// Crashes in a row after which librespot is not restarted anymore.
+const MAX_CONSECUTIVE_CRASHES: u32 = 10;
+/// Running at least this long resets the crash counter.
+const STABLE_RUNTIME: Duration = Duration::from_secs(60);
+/// Time after which librespot is tried again once it has been considered to be in a crash loop.
+const CRASH_LOOP_COOL_DOWN: Duration = Duration::from_secs(30 * 60);
+
+/// Returns `None` once librespot is considered to be in a crash loop.
+fn restart_delay(consecutive_crashes: u32) -> Option<Duration> {
+    if consecutive_crashes > MAX_CONSECUTIVE_CRASHES {
+        return None;
+    }
+    let delay = INITIAL_RESTART_DELAY * 2u32.saturating_pow(consecutive_crashes - 1);
+    Some(delay.min(MAX_RESTART_DELAY))
+}
+
+/// Level of a librespot log line as formatted by env_logger, e.g.
+/// `[2020-05-01T10:00:00Z INFO  librespot] ...` or `INFO:librespot: ...`.
+fn librespot_log_level(line: &str) -> Level {
+    let tokens = line
+        .split(|c: char| c.is_whitespace() || c == '[')
+        .filter(|x| !x.is_empty())
+        .take(2);
+    for token in tokens {
+        match token.split(':').next().unwrap_or_default() {
+            "ERROR" => return Level::Error,
+            "WARN" => return Level::Warning,
+            "INFO" => return Level::Info,
+            "DEBUG" => return Level::Debug,
+            "TRACE" => return Level::Trace,
+            _ => {}
+        }
+    }
+    Level::Info
 }
 
 pub trait SpotifyConnector {
     fn wait_until_ready(&self) -> Result<(), util::JukeboxError> {
+        if self.awaiting_discovery() {
+            info!("Spotify Connect device not logged in yet, waiting for discovery");
+            return Ok(());
+        }
         let n_attempts = 30;
         for _idx in 0..n_attempts {
             if self.device_id().is_some() {
@@ -29,37 +96,125 @@ pub trait SpotifyConnector {
     }
     fn device_id(&self) -> Option<String>;
     fn request_restart(&self);
+    fn terminate(&self) {}
+    fn supervisor_status(&self) -> Option<SupervisorStatus> {
+        None
+    }
+    /// Playback state reported by the device itself, if it reports any.
+    fn device_events(&self) -> Option<Arc<DeviceEvents>> {
+        None
+    }
+    /// True while the device cannot be known to Spotify before a user selected it.
+    fn awaiting_discovery(&self) -> bool {
+        false
+    }
 }
 
 pub mod external_command {
 
     use super::*;
 
-    use crate::effects::spotify::{self, util::JukeboxError};
+    use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
     use failure::{Context, Fallible};
-    use slog_scope::{error, info, warn};
+    use slog_scope::{debug, error, info, trace, warn};
     use std::env;
-    use std::process::{Child, Command};
+    use std::io::{BufRead, BufReader, Read};
+    use std::path::Path;
+    use std::process::{Child, Command, Stdio};
+    use std::sync::Mutex;
     use std::thread::{self, JoinHandle};
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
+
+    /// Interval for checking whether librespot is still running.
+    const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+    /// In discovery mode librespot only logs in once it has been selected in a Spotify app,
+    /// until then the device is unknown to the Web API.
+    fn awaiting_discovery(librespot_config: &LibrespotConfig, cache_directory: &str) -> bool {
+        librespot_config.credentials == Credentials::Discovery
+            && librespot::cached_credentials(Path::new(cache_directory)).is_none()
+    }
+
+    /// Logs in to Spotify once, storing reusable credentials in the cache directory. librespot
+    /// prompts for the password on the terminal, so that it never appears in the process
+    /// arguments.
+    pub fn login(
+        librespot_cmd: &str,
+        librespot_config: &LibrespotConfig,
+        device_name: &str,
+        username: &str,
+        cache_directory: &str,
+        timeout: Duration,
+    ) -> Fallible<()> {
+        let cache_path = Path::new(cache_directory);
+        let previous_credentials = librespot::cached_credentials(cache_path);
+        let mut child = Command::new(librespot_cmd)
+            .arg("--name")
+            .arg(device_name)
+            .arg("--username")
+            .arg(username)
+            .arg("--cache")
+            .arg(cache_directory)
+            .args(librespot_config.args())
+            .spawn()?;
+        let deadline = Instant::now() + timeout;
+        let result = loop {
+            let credentials = librespot::cached_credentials(cache_path);
+            if credentials.is_some() && credentials != previous_credentials {
+                break Ok(());
+            }
+            if let Some(status) = child.try_wait()? {
+                break Err(failure::format_err!(
+                    "librespot terminated with {} before storing credentials",
+                    status
+                ));
+            }
+            if Instant::now() >= deadline {
+                break Err(failure::format_err!(
+                    "Timed out waiting for librespot login"
+                ));
+            }
+            thread::sleep(Duration::from_millis(500));
+        };
+        let _ = child.kill();
+        let _ = child.wait();
+        result
+    }
+
+    pub fn login_from_env(device_name: &str) -> Fallible<()> {
+        let username = env::var("SPOTIFY_CONNECT_USERNAME").map_err(Context::new)?;
+        let librespot_cmd = env::var("SPOTIFY_CONNECT_LIBRESPOT").map_err(Context::new)?;
+        let cache_directory = env::var("SPOTIFY_CONNECT_CACHE_DIRECTORY").map_err(Context::new)?;
+        let librespot_config = LibrespotConfig::from_env()?;
+        login(
+            &librespot_cmd,
+            &librespot_config,
+            device_name,
+            &username,
+            &cache_directory,
+            Duration::from_secs(300),
+        )
+    }
 
     pub struct ExternalCommand {
+        librespot_config: LibrespotConfig,
+        cache_directory: String,
         device_id: Arc<RwLock<Option<String>>>,
-        // status: Receiver<T>,
-        child: Arc<RwLock<Child>>,
-        // command: Sender<SupervisorCommands>,
-        _supervisor: JoinHandle<()>,
+        status: Arc<RwLock<SupervisorStatus>>,
+        device_events: Option<Arc<DeviceEvents>>,
+        commands: Sender<SupervisorCommands>,
+        supervisor: Mutex<Option<JoinHandle<()>>>,
     }
 
     struct SupervisedCommand {
-        pub cmd: String,
         pub device_name: String,
-        pub username: String,
-        pub password: String,
         pub cache_directory: String,
         pub device_id: Arc<RwLock<Option<String>>>,
         pub librespot_cmd: String,
-        pub access_token_provider: AccessTokenProvider,
+        pub librespot_config: LibrespotConfig,
+        pub device_service: Arc<DeviceService>,
+        event_hook: Option<EventHook>,
+        status: Arc<RwLock<SupervisorStatus>>,
         child: Arc<RwLock<Child>>,
     }
 
@@ -81,83 +236,177 @@ pub mod external_command {
         //
 
         fn spawn(
-            username: &str,
-            password: &str,
             device_name: &str,
             librespot_cmd: &str,
+            librespot_config: &LibrespotConfig,
             cache_directory: &str,
+            event_hook: Option<&EventHook>,
         ) -> Result<Child, std::io::Error> {
-            Command::new(librespot_cmd)
-                .arg("--name")
+            let mut cmd = Command::new(librespot_cmd);
+            cmd.arg("--name")
                 .arg(device_name)
-                .arg("--username")
-                .arg(username)
-                .arg("--password")
-                .arg(password)
-                .arg("--bitrate")
-                .arg("160")
                 .arg("--cache")
                 .arg(cache_directory)
-                .arg("--enable-volume-normalisation")
-                .arg("--linear-volume")
-                .arg("--initial-volume=100")
-                .spawn()
+                .args(librespot_config.args());
+            if let Some(event_hook) = event_hook {
+                cmd.arg("--onevent")
+                    .arg(&event_hook.command)
+                    .env(events::SOCKET_ENV, &event_hook.socket);
+            }
+            let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+            let pid = child.id();
+            if let Some(stdout) = child.stdout.take() {
+                Self::forward_output(stdout, "stdout", pid)?;
+            }
+            if let Some(stderr) = child.stderr.take() {
+                Self::forward_output(stderr, "stderr", pid)?;
+            }
+            Ok(child)
+        }
+
+        /// Forwards the output line by line into the log until the process terminates.
+        fn forward_output<R: Read + Send + 'static>(
+            output: R,
+            stream: &'static str,
+            pid: u32,
+        ) -> Result<JoinHandle<()>, std::io::Error> {
+            thread::Builder::new()
+                .name(format!("librespot-{}", stream))
+                .spawn(move || {
+                    for line in BufReader::new(output).lines() {
+                        let line = match line {
+                            Ok(line) => line,
+                            Err(_) => break,
+                        };
+                        match librespot_log_level(&line) {
+                            Level::Critical | Level::Error => {
+                                error!("{}", line; "source" => "librespot", "stream" => stream, "pid" => pid)
+                            }
+                            Level::Warning => {
+                                warn!("{}", line; "source" => "librespot", "stream" => stream, "pid" => pid)
+                            }
+                            Level::Info => {
+                                info!("{}", line; "source" => "librespot", "stream" => stream, "pid" => pid)
+                            }
+                            Level::Debug => {
+                                debug!("{}", line; "source" => "librespot", "stream" => stream, "pid" => pid)
+                            }
+                            Level::Trace => {
+                                trace!("{}", line; "source" => "librespot", "stream" => stream, "pid" => pid)
+                            }
+                        }
+                    }
+                })
         }
 
         fn respawn(&mut self) -> Result<(), std::io::Error> {
             let child = Self::spawn(
-                &self.username,
-                &self.password,
                 &self.device_name,
                 &self.librespot_cmd,
+                &self.librespot_config,
                 &self.cache_directory,
+                self.event_hook.as_ref(),
             )?;
+            // The state reported by the previous process is gone.
+            if let Some(ref event_hook) = self.event_hook {
+                event_hook.events.reset();
+            }
             *(self.child.write().unwrap()) = child;
             Ok(())
         }
 
-        fn spawn_supervisor(self) -> JoinHandle<()> {
+        fn spawn_supervisor(self, commands: Receiver<SupervisorCommands>) -> JoinHandle<()> {
             info!("Spawning supervisor for Spotify Connect command");
             thread::Builder::new()
                 .name("spotify-supervisor".to_string())
-                .spawn(move || Self::supervisor(self))
+                .spawn(move || Self::supervisor(self, commands))
                 .unwrap()
         }
 
         fn spawn_device_id_watcher(&self) -> JoinHandle<()> {
             info!("Spawning device ID watcher for Spotify Connect command");
-            let access_token_provider = Arc::new(self.access_token_provider.clone());
+            let device_service = self.device_service.clone();
             let device_name = self.device_name.clone();
-            let device_id = Arc::clone(&self.device_id);
+            let librespot_config = self.librespot_config.clone();
+            let cache_directory = self.cache_directory.clone();
             let child = Arc::clone(&self.child);
             thread::Builder::new()
                 .name("spotify-device-watcher".to_string())
                 .spawn(move || {
                     thread::sleep(Duration::from_secs(2));
-                    Self::device_id_watcher(access_token_provider, device_name, device_id, child)
+                    Self::device_id_watcher(
+                        device_service,
+                        device_name,
+                        librespot_config,
+                        cache_directory,
+                        child,
+                    )
+                })
+                .unwrap()
+        }
+
+        /// Keeps the device ID up to date with the device list, no matter which component
+        /// triggered the refresh.
+        fn spawn_device_event_listener(&self) -> JoinHandle<()> {
+            let events = self.device_service.subscribe();
+            let device_name = self.device_name.clone();
+            let device_id = Arc::clone(&self.device_id);
+            thread::Builder::new()
+                .name("spotify-device-events".to_string())
+                .spawn(move || {
+                    for event in events.iter() {
+                        match event {
+                            DeviceEvent::Added(device) | DeviceEvent::Changed(device)
+                                if device.name == device_name =>
+                            {
+                                *(device_id.write().unwrap()) = Some(device.id);
+                            }
+                            DeviceEvent::Removed(device) if device.name == device_name => {
+                                *(device_id.write().unwrap()) = None;
+                            }
+                            _ => {}
+                        }
+                    }
                 })
                 .unwrap()
         }
 
         fn device_id_watcher(
-            access_token_provider: Arc<AccessTokenProvider>,
+            device_service: Arc<DeviceService>,
             device_name: String,
-            device_id: Arc<RwLock<Option<String>>>,
+            librespot_config: LibrespotConfig,
+            cache_directory: String,
             child: Arc<RwLock<Child>>,
         ) {
+            // Connections opened here are pooled by the shared client, a threaded runtime
+            // keeps driving them between refreshes.
+            let mut runtime = match tokio::runtime::Builder::new()
+                .threaded_scheduler()
+                .core_threads(1)
+                .enable_all()
+                .build()
+            {
+                Ok(runtime) => runtime,
+                Err(err) => {
+                    error!("Failed to create runtime for device ID watcher: {}", err);
+                    return;
+                }
+            };
             loop {
-                // info!("device ID watcher tick");
-                // info!("Looking for device named '{}'", device_name);
-                match spotify::util::lookup_device_by_name(&access_token_provider, &device_name) {
-                    Ok(device) => {
-                        *(device_id.write().unwrap()) = Some(device.id);
+                match runtime.block_on(device_service.refresh()) {
+                    Ok(devices) if devices.iter().any(|x| x.name == device_name) => {}
+                    // Restarting librespot would not make it appear any sooner.
+                    Ok(_) if awaiting_discovery(&librespot_config, &cache_directory) => {
+                        info!(
+                            "Spotify device '{}' is waiting to be selected in a Spotify app",
+                            device_name
+                        );
                     }
-                    Err(JukeboxError::DeviceNotFound { .. }) => {
+                    Ok(_) => {
                         warn!(
                             "No Spotify device ID found for device name '{}'",
                             device_name
                         );
-                        *(device_id.write().unwrap()) = None;
                         // kill child
                         if let Err(err) = child.write().unwrap().kill() {
                             error!("Failed to terminate Spotify Connector: {}", err);
@@ -174,9 +423,39 @@ pub mod external_command {
             }
         }
 
-        fn supervisor(mut self) {
+        fn supervisor(mut self, commands: Receiver<SupervisorCommands>) {
+            let mut started_at = Instant::now();
+            let mut consecutive_crashes = 0;
+            let mut crash_loop_since: Option<Instant> = None;
             loop {
-                // info!("supervisor tick");
+                // The supervisor terminates librespot on request or once the connector is gone.
+                match commands.recv_timeout(POLL_INTERVAL) {
+                    Ok(SupervisorCommands::Terminate) | Err(RecvTimeoutError::Disconnected) => {
+                        self.terminate();
+                        return;
+                    }
+                    Ok(SupervisorCommands::Restart) => {
+                        info!("Restarting Spotify Connector on request");
+                        self.stop();
+                        consecutive_crashes = 0;
+                        crash_loop_since = None;
+                        started_at = Instant::now();
+                        self.restart();
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                }
+                if let Some(since) = crash_loop_since {
+                    if since.elapsed() < CRASH_LOOP_COOL_DOWN {
+                        continue;
+                    }
+                    info!("Trying Spotify Connector again after crash loop cool-down");
+                    consecutive_crashes = 0;
+                    crash_loop_since = None;
+                    started_at = Instant::now();
+                    self.restart();
+                    continue;
+                }
 
                 // Child is expected to be running.
                 // Check if it has terminated for some reason:
@@ -184,129 +463,366 @@ pub mod external_command {
                     let mut writer = self.child.write().unwrap();
                     writer.try_wait()
                 };
-                match res {
-                    Ok(Some(status)) => {
-                        // child terminated. needs to be restarted.
-                        warn!(
-                            "Spotify Connector terminated unexpectedly with status {}",
-                            status
-                        );
-                        if let Err(err) = self.respawn() {
-                            error!("Failed to respawn Spotify Connector: {}", err);
-                        } else {
-                            let pid = self.child.read().unwrap().id();
-                            info!("Respawned new Spotify Connector (PID {})", pid);
-                        }
-                    }
-                    Ok(None) => {}
+                let status = match res {
+                    Ok(Some(status)) => status,
+                    Ok(None) => continue,
                     Err(err) => {
                         error!(
                             "Failed to check if Spotify Connector is still running: {}",
                             err
                         );
                         // fixme, what to do for resilience?
+                        continue;
+                    }
+                };
+                if started_at.elapsed() >= STABLE_RUNTIME {
+                    consecutive_crashes = 0;
+                }
+                consecutive_crashes += 1;
+                warn!(
+                    "Spotify Connector terminated unexpectedly with status {}", status;
+                    "consecutive_crashes" => consecutive_crashes
+                );
+                {
+                    let mut supervisor_status = self.status.write().unwrap();
+                    supervisor_status.last_exit = Some(status);
+                    supervisor_status.pid = None;
+                }
+
+                let delay = match restart_delay(consecutive_crashes) {
+                    Some(delay) => delay,
+                    None => {
+                        error!(
+                            "Spotify Connector keeps crashing, giving up after {} attempts",
+                            consecutive_crashes
+                        );
+                        self.status.write().unwrap().health = SupervisorHealth::CrashLoop;
+                        crash_loop_since = Some(Instant::now());
+                        continue;
+                    }
+                };
+                self.status.write().unwrap().health = SupervisorHealth::Restarting;
+                info!("Restarting Spotify Connector in {:?}", delay);
+                match commands.recv_timeout(delay) {
+                    Ok(SupervisorCommands::Terminate) | Err(RecvTimeoutError::Disconnected) => {
+                        self.terminate();
+                        return;
+                    }
+                    Ok(SupervisorCommands::Restart) => {
+                        info!("Restarting Spotify Connector on request");
+                        consecutive_crashes = 0;
                     }
+                    Err(RecvTimeoutError::Timeout) => {}
+                }
+                started_at = Instant::now();
+                self.restart();
+            }
+        }
+
+        /// Spawns a new librespot process in place of the terminated one.
+        fn restart(&mut self) {
+            if let Err(err) = self.respawn() {
+                error!("Failed to respawn Spotify Connector: {}", err);
+            } else {
+                let pid = self.child.read().unwrap().id();
+                info!("Respawned new Spotify Connector (PID {})", pid);
+                let mut status = self.status.write().unwrap();
+                status.health = SupervisorHealth::Running;
+                status.pid = Some(pid);
+                status.restart_count += 1;
+            }
+        }
+
+        /// Kills librespot, if still running, and waits for it to terminate.
+        fn stop(&mut self) {
+            let mut child = self.child.write().unwrap();
+            if let Ok(None) = child.try_wait() {
+                if let Err(err) = child.kill() {
+                    error!("Failed to terminate Spotify Connector: {}", err);
                 }
-                thread::sleep(Duration::from_millis(2000));
             }
+            match child.wait() {
+                Ok(status) => self.status.write().unwrap().last_exit = Some(status),
+                Err(err) => error!("Failed to wait for Spotify Connector: {}", err),
+            }
+            self.status.write().unwrap().pid = None;
+        }
+
+        fn terminate(&mut self) {
+            info!("Terminating Spotify Connector");
+            self.stop();
+            self.status.write().unwrap().health = SupervisorHealth::Terminated;
         }
 
+        #[allow(clippy::too_many_arguments)]
         pub fn new(
-            cmd: String,
             device_name: &str,
             librespot_cmd: String,
-            username: String,
-            password: String,
+            librespot_config: LibrespotConfig,
             cache_directory: String,
             device_id: Arc<RwLock<Option<String>>>,
-            access_token_provider: &AccessTokenProvider,
-        ) -> Result<(Self, Arc<RwLock<Child>>), std::io::Error> {
+            device_service: Arc<DeviceService>,
+            event_hook: Option<EventHook>,
+            status: Arc<RwLock<SupervisorStatus>>,
+        ) -> Result<Self, std::io::Error> {
             let child = Self::spawn(
-                &username,
-                &password,
-                &device_name,
+                device_name,
                 &librespot_cmd,
+                &librespot_config,
                 &cache_directory,
+                event_hook.as_ref(),
             )?;
-            let rw_child = Arc::new(RwLock::new(child));
+            status.write().unwrap().pid = Some(child.id());
             let supervised_cmd = SupervisedCommand {
-                cmd,
+                status,
                 device_name: device_name.to_string().clone(),
-                access_token_provider: access_token_provider.clone(),
-                child: Arc::clone(&rw_child),
+                device_service,
+                event_hook,
+                child: Arc::new(RwLock::new(child)),
                 device_id,
                 librespot_cmd,
-                username,
-                password,
+                librespot_config,
                 cache_directory,
             };
-            Ok((supervised_cmd, rw_child))
+            Ok(supervised_cmd)
         }
     }
 
     impl ExternalCommand {
+        /// The event hook can be overridden via `SPOTIFY_CONNECT_EVENT_HOOK`, where an empty
+        /// value disables it.
         pub fn new_from_env(
-            access_token_provider: &AccessTokenProvider,
+            device_service: Arc<DeviceService>,
             device_name: String,
+            event_hook: Option<String>,
         ) -> Fallible<Self> {
-            let cmd = env::var("SPOTIFY_CONNECT_COMMAND").map_err(Context::new)?;
-            let username = env::var("SPOTIFY_CONNECT_USERNAME").map_err(Context::new)?;
-            let password = env::var("SPOTIFY_CONNECT_PASSWORD").map_err(Context::new)?;
+            if env::var_os("SPOTIFY_CONNECT_PASSWORD").is_some() {
+                warn!("SPOTIFY_CONNECT_PASSWORD is ignored, use cached credentials or discovery instead");
+            }
             let librespot_cmd = env::var("SPOTIFY_CONNECT_LIBRESPOT").map_err(Context::new)?;
             let cache_directory =
                 env::var("SPOTIFY_CONNECT_CACHE_DIRECTORY").map_err(Context::new)?;
+            let librespot_config = LibrespotConfig::from_env()?;
+            let event_hook = match env::var("SPOTIFY_CONNECT_EVENT_HOOK") {
+                Ok(event_hook) if event_hook.trim().is_empty() => None,
+                Ok(event_hook) => Some(event_hook),
+                Err(_) => event_hook,
+            };
             Self::new(
-                access_token_provider,
-                cmd,
+                device_service,
                 device_name,
-                username,
-                password,
                 cache_directory,
                 librespot_cmd,
+                librespot_config,
+                event_hook,
             )
         }
         pub fn new(
-            access_token_provider: &AccessTokenProvider,
-            cmd: String,
+            device_service: Arc<DeviceService>,
             device_name: String,
-            username: String,
-            password: String,
             cache_directory: String,
             librespot_cmd: String,
+            librespot_config: LibrespotConfig,
+            event_hook: Option<String>,
         ) -> Fallible<Self> {
+            librespot_config.validate()?;
+            if librespot_config.credentials == Credentials::Cached
+                && librespot::cached_credentials(Path::new(&cache_directory)).is_none()
+            {
+                return Err(failure::format_err!(
+                    "No cached librespot credentials in {}, run `jukeboxd librespot-login` once or use discovery",
+                    cache_directory
+                ));
+            }
             let device_id = Arc::new(RwLock::new(None));
-            let (supervised_cmd, rw_child) = SupervisedCommand::new(
-                cmd.to_string().clone(),
+            let status = Arc::new(RwLock::new(SupervisorStatus {
+                health: SupervisorHealth::Running,
+                pid: None,
+                restart_count: 0,
+                last_exit: None,
+            }));
+            let event_hook = match event_hook {
+                Some(command) => {
+                    let event_hook = EventHook::new(command, Path::new(&cache_directory))?;
+                    info!(
+                        "Receiving librespot events on {}",
+                        event_hook.socket.display()
+                    );
+                    Some(event_hook)
+                }
+                None => None,
+            };
+            let device_events = event_hook.as_ref().map(|x| Arc::clone(&x.events));
+            let supervised_cmd = SupervisedCommand::new(
                 &device_name,
                 librespot_cmd,
-                username,
-                password,
-                cache_directory,
+                librespot_config.clone(),
+                cache_directory.clone(),
                 Arc::clone(&device_id),
-                access_token_provider,
+                device_service,
+                event_hook,
+                Arc::clone(&status),
             )?;
+            let _ = supervised_cmd.spawn_device_event_listener();
             let _ = supervised_cmd.spawn_device_id_watcher();
-            let supervisor = supervised_cmd.spawn_supervisor();
+            let (commands, commands_rx) = crossbeam_channel::unbounded();
+            let supervisor = supervised_cmd.spawn_supervisor(commands_rx);
 
             Ok(ExternalCommand {
+                librespot_config,
+                cache_directory,
                 device_id,
-                child: rw_child,
-                _supervisor: supervisor,
+                status,
+                device_events,
+                commands,
+                supervisor: Mutex::new(Some(supervisor)),
             })
         }
     }
 
     impl SpotifyConnector for ExternalCommand {
+        fn awaiting_discovery(&self) -> bool {
+            awaiting_discovery(&self.librespot_config, &self.cache_directory)
+        }
+        /// Restarts librespot right away, also after it has been given up on.
         fn request_restart(&self) {
-            if let Err(err) = self.child.write().unwrap().kill() {
-                error!("While trying to restart Spotify Connector ExternalCommand, terminating the running process failed: {}", err);
-            } else {
-                error!("While trying to restart Spotify Connector ExternalCommand, successfully killed running process");
+            if self.commands.send(SupervisorCommands::Restart).is_err() {
+                error!("Failed to restart Spotify Connector, supervisor has terminated");
             }
         }
         fn device_id(&self) -> Option<String> {
             let reader = self.device_id.read().unwrap();
             (*reader).clone()
         }
+        /// Terminates librespot and waits for the supervisor to finish.
+        fn terminate(&self) {
+            let supervisor = self.supervisor.lock().unwrap().take();
+            if let Some(supervisor) = supervisor {
+                let _ = self.commands.send(SupervisorCommands::Terminate);
+                if supervisor.join().is_err() {
+                    error!("Spotify Connect supervisor panicked");
+                }
+            }
+        }
+        fn supervisor_status(&self) -> Option<SupervisorStatus> {
+            Some(self.status.read().unwrap().clone())
+        }
+        fn device_events(&self) -> Option<Arc<DeviceEvents>> {
+            self.device_events.clone()
+        }
+    }
+
+    impl Drop for ExternalCommand {
+        fn drop(&mut self) {
+            self.terminate();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Instant;
+
+    use super::super::client::SpotifyClient;
+    use super::super::devices::DEFAULT_MAX_AGE;
+    use super::super::mock_server::MockServer;
+    use super::external_command::ExternalCommand;
+    use super::*;
+    use crate::components::access_token_provider::AccessTokenProvider;
+
+    fn wait_for_status<F: Fn(&SupervisorStatus) -> bool>(connector: &ExternalCommand, f: F) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !f(&connector.supervisor_status().unwrap()) {
+            assert!(
+                Instant::now() < deadline,
+                "{:?}",
+                connector.supervisor_status()
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn librespot_is_restarted_on_request_and_after_crashes() {
+        let server = MockServer::start();
+        let access_token_provider = Arc::new(
+            AccessTokenProvider::new(&server.base_url(), "id", "secret", Some("refresh"), None)
+                .unwrap(),
+        );
+        let client = SpotifyClient::new(
+            server.base_url().join("v1/").unwrap(),
+            access_token_provider,
+        );
+        let device_service = Arc::new(DeviceService::new(Arc::new(client), DEFAULT_MAX_AGE));
+        let cache_directory =
+            std::env::temp_dir().join(format!("rustberry-supervisor-test-{}", std::process::id()));
+        fs::create_dir_all(&cache_directory).unwrap();
+        fs::write(librespot::credentials_file(&cache_directory), "{}").unwrap();
+        let librespot_cmd = cache_directory.join("librespot");
+        fs::write(&librespot_cmd, "#!/bin/sh\nexec sleep 60\n").unwrap();
+        fs::set_permissions(&librespot_cmd, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let connector = ExternalCommand::new(
+            device_service,
+            "jukebox".to_string(),
+            cache_directory.to_str().unwrap().to_string(),
+            librespot_cmd.to_str().unwrap().to_string(),
+            LibrespotConfig::default(),
+            None,
+        )
+        .unwrap();
+        let first_pid = connector.supervisor_status().unwrap().pid.unwrap();
+
+        connector.request_restart();
+        wait_for_status(&connector, |status| status.restart_count == 1);
+        let status = connector.supervisor_status().unwrap();
+        assert_eq!(status.health, SupervisorHealth::Running);
+        let second_pid = status.pid.unwrap();
+        assert_ne!(second_pid, first_pid);
+
+        // The exited process is not reported as running anymore until it has been replaced.
+        std::process::Command::new("kill")
+            .arg(second_pid.to_string())
+            .status()
+            .unwrap();
+        wait_for_status(&connector, |status| {
+            status.pid.is_none() && status.health == SupervisorHealth::Restarting
+        });
+        wait_for_status(&connector, |status| status.restart_count == 2);
+        assert!(connector.supervisor_status().unwrap().pid.is_some());
+
+        connector.terminate();
+        assert_eq!(
+            connector.supervisor_status().unwrap().health,
+            SupervisorHealth::Terminated
+        );
+        let _ = fs::remove_dir_all(&cache_directory);
+    }
+
+    #[test]
+    fn restarts_back_off_until_crash_loop() {
+        assert_eq!(restart_delay(1), Some(Duration::from_secs(1)));
+        assert_eq!(restart_delay(3), Some(Duration::from_secs(4)));
+        assert_eq!(restart_delay(10), Some(MAX_RESTART_DELAY));
+        assert_eq!(restart_delay(11), None);
+    }
+
+    #[test]
+    fn librespot_log_levels_are_recognized() {
+        assert_eq!(
+            librespot_log_level("[2020-05-01T10:00:00Z ERROR librespot_core::session] Failed"),
+            Level::Error
+        );
+        assert_eq!(
+            librespot_log_level("[2020-05-01T10:00:00Z WARN  librespot_playback] Underrun"),
+            Level::Warning
+        );
+        assert_eq!(
+            librespot_log_level("DEBUG:librespot: Connecting"),
+            Level::Debug
+        );
+        assert_eq!(librespot_log_level("Using alsa sink"), Level::Info);
     }
 }
@@ -0,0 +1,281 @@
+//! Cached view of the Spotify Connect devices of the account, shared by the connector and the
+//! player.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender};
+use slog_scope::info;
+
+use super::client::{self, Device, SpotifyClient};
+use super::util::JukeboxError;
+
+/// How long a retrieved device list is used before asking Spotify again.
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(30);
+
+/// Interval for polling the devices while waiting for a playback transfer.
+const TRANSFER_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Change of the device list, devices are identified by their ID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Added(Device),
+    Removed(Device),
+    Changed(Device),
+}
+
+#[derive(Default)]
+struct Cache {
+    devices: Vec<Device>,
+    /// `None` if the devices have not been retrieved yet or have been invalidated.
+    fetched_at: Option<Instant>,
+}
+
+pub struct DeviceService {
+    spotify_client: Arc<SpotifyClient>,
+    max_age: Duration,
+    cache: Mutex<Cache>,
+    subscribers: Mutex<Vec<Sender<DeviceEvent>>>,
+}
+
+impl DeviceService {
+    pub fn new(spotify_client: Arc<SpotifyClient>, max_age: Duration) -> Self {
+        DeviceService {
+            spotify_client,
+            max_age,
+            cache: Mutex::new(Cache::default()),
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn spotify_client(&self) -> &Arc<SpotifyClient> {
+        &self.spotify_client
+    }
+
+    /// Receives the changes detected by every following refresh.
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Forces the next lookup to ask Spotify, e.g. after Spotify did not know a device.
+    pub fn invalidate(&self) {
+        self.cache.lock().unwrap().fetched_at = None;
+    }
+
+    /// The cached devices, retrieved from Spotify if the cache is stale.
+    pub async fn devices(&self) -> Result<Vec<Device>, client::Error> {
+        match self.cached() {
+            Some(devices) => Ok(devices),
+            None => self.refresh().await,
+        }
+    }
+
+    /// Retrieves the devices from Spotify and reports the changes to the subscribers.
+    pub async fn refresh(&self) -> Result<Vec<Device>, client::Error> {
+        let devices = self.spotify_client.devices().await?;
+        let events = {
+            let mut cache = self.cache.lock().unwrap();
+            let events = diff(&cache.devices, &devices);
+            cache.devices = devices.clone();
+            cache.fetched_at = Some(Instant::now());
+            events
+        };
+        if !events.is_empty() {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            for event in events {
+                info!("Spotify device list changed: {:?}", event);
+                subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+            }
+        }
+        Ok(devices)
+    }
+
+    /// Looks the device up in the cache first, devices not known yet cause a refresh.
+    pub async fn lookup_by_name(&self, device_name: &str) -> Result<Device, JukeboxError> {
+        let find = |devices: Vec<Device>| devices.into_iter().find(|x| x.name == device_name);
+        if let Some(device) = self.cached().and_then(find) {
+            return Ok(device);
+        }
+        find(self.refresh().await?).ok_or_else(|| JukeboxError::DeviceNotFound {
+            device_name: device_name.to_string(),
+        })
+    }
+
+    /// Makes the device the active Spotify device unless it already is and waits until
+    /// Spotify reports it as active. Always asks Spotify, since another device may have
+    /// taken over since the last refresh.
+    pub async fn activate(&self, device_id: &str, timeout: Duration) -> Result<(), JukeboxError> {
+        let is_active =
+            |devices: Vec<Device>| devices.iter().any(|x| x.id == device_id && x.is_active);
+        if is_active(self.refresh().await?) {
+            return Ok(());
+        }
+        info!("Transferring Spotify playback to device {}", device_id);
+        self.spotify_client.transfer_playback(device_id).await?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            if is_active(self.refresh().await?) {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(JukeboxError::TransferTimeout {
+                    device_id: device_id.to_string(),
+                });
+            }
+            tokio::time::delay_for(TRANSFER_POLL_INTERVAL).await;
+        }
+    }
+
+    fn cached(&self) -> Option<Vec<Device>> {
+        let cache = self.cache.lock().unwrap();
+        match cache.fetched_at {
+            Some(fetched_at) if fetched_at.elapsed() < self.max_age => Some(cache.devices.clone()),
+            _ => None,
+        }
+    }
+}
+
+fn diff(old: &[Device], new: &[Device]) -> Vec<DeviceEvent> {
+    let mut events = Vec::new();
+    for device in new {
+        match old.iter().find(|x| x.id == device.id) {
+            None => events.push(DeviceEvent::Added(device.clone())),
+            Some(old_device) if old_device != device => {
+                events.push(DeviceEvent::Changed(device.clone()))
+            }
+            Some(_) => {}
+        }
+    }
+    for device in old {
+        if !new.iter().any(|x| x.id == device.id) {
+            events.push(DeviceEvent::Removed(device.clone()));
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::runtime::Runtime;
+
+    use super::super::mock_server::MockServer;
+    use super::*;
+    use crate::components::access_token_provider::AccessTokenProvider;
+
+    const TOKEN_RESPONSE: &str =
+        r#"{"access_token": "token", "token_type": "Bearer", "scope": "", "expires_in": 3600}"#;
+
+    fn service(server: &MockServer) -> DeviceService {
+        let access_token_provider = Arc::new(
+            AccessTokenProvider::new(&server.base_url(), "id", "secret", Some("refresh"), None)
+                .unwrap(),
+        );
+        access_token_provider.wait_for_token().unwrap();
+        let client = SpotifyClient::new(
+            server.base_url().join("v1/").unwrap(),
+            access_token_provider,
+        );
+        DeviceService::new(Arc::new(client), DEFAULT_MAX_AGE)
+    }
+
+    fn device(id: &str, name: &str, is_active: bool) -> Device {
+        Device {
+            id: id.to_string(),
+            name: name.to_string(),
+            is_active,
+            volume_percent: None,
+        }
+    }
+
+    #[test]
+    fn devices_are_cached_and_changes_reported() {
+        let server = MockServer::start();
+        server.respond("POST", "/api/token", 200, TOKEN_RESPONSE);
+        server.respond(
+            "GET",
+            "/v1/me/player/devices",
+            200,
+            r#"{"devices": [{"id": "abc", "name": "jukebox", "is_active": true}]}"#,
+        );
+        server.respond_times(
+            "GET",
+            "/v1/me/player/devices",
+            200,
+            r#"{"devices": [{"id": "abc", "name": "jukebox", "is_active": false}, {"id": "def", "name": "phone", "is_active": true}]}"#,
+            1,
+        );
+        let service = service(&server);
+        let events = service.subscribe();
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let jukebox = service.lookup_by_name("jukebox").await.unwrap();
+            assert_eq!(jukebox, device("abc", "jukebox", false));
+            assert_eq!(service.devices().await.unwrap().len(), 2);
+            service.invalidate();
+            let jukebox = service.lookup_by_name("jukebox").await.unwrap();
+            assert_eq!(jukebox, device("abc", "jukebox", true));
+            assert!(matches!(
+                service.lookup_by_name("radio").await,
+                Err(JukeboxError::DeviceNotFound { .. })
+            ));
+        });
+
+        let n_device_requests = server
+            .requests()
+            .iter()
+            .filter(|req| req.path == "/v1/me/player/devices")
+            .count();
+        assert_eq!(n_device_requests, 3);
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                DeviceEvent::Added(device("abc", "jukebox", false)),
+                DeviceEvent::Added(device("def", "phone", true)),
+                DeviceEvent::Changed(device("abc", "jukebox", true)),
+                DeviceEvent::Removed(device("def", "phone", true)),
+            ]
+        );
+    }
+
+    #[test]
+    fn inactive_device_is_activated() {
+        let server = MockServer::start();
+        server.respond("POST", "/api/token", 200, TOKEN_RESPONSE);
+        server.respond(
+            "GET",
+            "/v1/me/player/devices",
+            200,
+            r#"{"devices": [{"id": "abc", "name": "jukebox", "is_active": true}]}"#,
+        );
+        server.respond_times(
+            "GET",
+            "/v1/me/player/devices",
+            200,
+            r#"{"devices": [{"id": "abc", "name": "jukebox", "is_active": false}, {"id": "def", "name": "phone", "is_active": true}]}"#,
+            2,
+        );
+        server.respond("PUT", "/v1/me/player", 204, "");
+        let service = service(&server);
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime
+            .block_on(service.activate("abc", Duration::from_secs(5)))
+            .unwrap();
+        // Already active, nothing is transferred.
+        runtime
+            .block_on(service.activate("abc", Duration::from_secs(5)))
+            .unwrap();
+
+        let requests = server.requests();
+        let transfers: Vec<&str> = requests
+            .iter()
+            .filter(|req| req.method == "PUT")
+            .map(|req| req.body.as_str())
+            .collect();
+        assert_eq!(transfers, vec![r#"{"device_ids":["abc"],"play":false}"#]);
+        assert_eq!(requests.len(), 6);
+    }
+}
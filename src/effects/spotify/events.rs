@@ -0,0 +1,478 @@
+//! Playback events reported by librespot via its `--onevent` hook. librespot runs the hook
+//! program for every event, which forwards the event to the daemon via a Unix socket.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use slog_scope::{debug, warn};
+
+pub use err::*;
+
+/// Subcommand of jukeboxd forwarding an event to the daemon.
+pub const HOOK_SUBCOMMAND: &str = "librespot-event";
+
+/// Variable passed to librespot and thereby to the hook, pointing to the daemon's socket.
+pub const SOCKET_ENV: &str = "SPOTIFY_CONNECT_EVENT_SOCKET";
+
+/// File within the cache directory the daemon listens on.
+const SOCKET_FILE: &str = "librespot-events.sock";
+
+/// Maximum time for a hook to send its event, such that a stuck hook cannot block others.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Variables set by librespot for the hook, the others are not forwarded.
+const HOOK_VARS: &[&str] = &[
+    "PLAYER_EVENT",
+    "TRACK_ID",
+    "OLD_TRACK_ID",
+    "POSITION_MS",
+    "DURATION_MS",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlayerEvent {
+    Playing {
+        track_id: String,
+        position_ms: u64,
+        duration_ms: u64,
+    },
+    Paused {
+        track_id: String,
+        position_ms: u64,
+        duration_ms: u64,
+    },
+    /// A new track has been loaded, e.g. the next track of an album.
+    Changed {
+        track_id: String,
+    },
+    Stopped {
+        track_id: String,
+    },
+}
+
+impl PlayerEvent {
+    /// Parses the variables librespot passes to the hook. Events not relevant for playback,
+    /// such as volume changes, yield `None`.
+    pub fn from_vars(vars: &HashMap<String, String>) -> Result<Option<Self>, Error> {
+        let event = match vars.get("PLAYER_EVENT") {
+            Some(event) => event.as_str(),
+            None => return Err(Error::MissingVariable("PLAYER_EVENT")),
+        };
+        // Event names differ between librespot versions.
+        let event = match event {
+            "playing" => PlayerEvent::Playing {
+                track_id: track_id(vars)?,
+                position_ms: number(vars, "POSITION_MS")?,
+                duration_ms: number(vars, "DURATION_MS")?,
+            },
+            "paused" => PlayerEvent::Paused {
+                track_id: track_id(vars)?,
+                position_ms: number(vars, "POSITION_MS")?,
+                duration_ms: number(vars, "DURATION_MS")?,
+            },
+            "start" | "started" | "change" | "changed" => PlayerEvent::Changed {
+                track_id: track_id(vars)?,
+            },
+            "stop" | "stopped" => PlayerEvent::Stopped {
+                track_id: track_id(vars)?,
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(event))
+    }
+}
+
+fn variable<'a>(vars: &'a HashMap<String, String>, name: &'static str) -> Result<&'a str, Error> {
+    vars.get(name)
+        .map(String::as_str)
+        .ok_or(Error::MissingVariable(name))
+}
+
+/// Base62 ID of the track, some librespot versions pass the URI instead.
+fn track_id(vars: &HashMap<String, String>) -> Result<String, Error> {
+    let track_id = variable(vars, "TRACK_ID")?;
+    Ok(track_id.rsplit(':').next().unwrap_or_default().to_string())
+}
+
+fn number(vars: &HashMap<String, String>, name: &'static str) -> Result<u64, Error> {
+    let value = variable(vars, name)?;
+    value
+        .parse()
+        .map_err(|_| Error::InvalidVariable(name, value.to_string()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceStatus {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+/// Playback state of our device according to the events of librespot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceState {
+    pub track_id: String,
+    pub status: DeviceStatus,
+    pub position_ms: u64,
+    pub duration_ms: Option<u64>,
+    pub updated_at: Instant,
+}
+
+impl DeviceState {
+    /// Position within the track, advanced by the time passed since the last event while
+    /// playing.
+    pub fn position(&self) -> Duration {
+        let position = Duration::from_millis(self.position_ms);
+        match self.status {
+            DeviceStatus::Playing => position + self.updated_at.elapsed(),
+            DeviceStatus::Paused | DeviceStatus::Stopped => position,
+        }
+    }
+}
+
+/// Latest playback state reported by librespot, shared between the listener and the player.
+#[derive(Debug, Default)]
+pub struct DeviceEvents {
+    state: RwLock<Option<DeviceState>>,
+}
+
+impl DeviceEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `None` if librespot has not reported anything since the last reset.
+    pub fn state(&self) -> Option<DeviceState> {
+        self.state.read().unwrap().clone()
+    }
+
+    /// Forgets the state, e.g. before starting a new playback or after librespot restarted.
+    pub fn reset(&self) {
+        *self.state.write().unwrap() = None;
+    }
+
+    pub fn apply(&self, event: PlayerEvent) {
+        let mut state = self.state.write().unwrap();
+        let now = Instant::now();
+        *state = Some(match event {
+            PlayerEvent::Playing {
+                track_id,
+                position_ms,
+                duration_ms,
+            } => DeviceState {
+                track_id,
+                status: DeviceStatus::Playing,
+                position_ms,
+                duration_ms: Some(duration_ms),
+                updated_at: now,
+            },
+            PlayerEvent::Paused {
+                track_id,
+                position_ms,
+                duration_ms,
+            } => DeviceState {
+                track_id,
+                status: DeviceStatus::Paused,
+                position_ms,
+                duration_ms: Some(duration_ms),
+                updated_at: now,
+            },
+            PlayerEvent::Changed { track_id } => DeviceState {
+                track_id,
+                status: state.as_ref().map_or(DeviceStatus::Playing, |x| x.status),
+                position_ms: 0,
+                duration_ms: None,
+                updated_at: now,
+            },
+            PlayerEvent::Stopped { track_id } => DeviceState {
+                position_ms: state
+                    .as_ref()
+                    .filter(|x| x.track_id == track_id)
+                    .map_or(0, |x| x.position().as_millis() as u64),
+                duration_ms: state
+                    .as_ref()
+                    .filter(|x| x.track_id == track_id)
+                    .and_then(|x| x.duration_ms),
+                track_id,
+                status: DeviceStatus::Stopped,
+                updated_at: now,
+            },
+        });
+    }
+}
+
+/// Hook registered with librespot and the socket its events arrive on.
+pub struct EventHook {
+    /// Program run by librespot, which splits it at whitespace into program and arguments.
+    pub command: String,
+    pub socket: PathBuf,
+    pub events: Arc<DeviceEvents>,
+}
+
+impl EventHook {
+    /// Listens for the events of the hook in the background.
+    pub fn new(command: String, cache_directory: &Path) -> Result<Self, Error> {
+        let socket = cache_directory.join(SOCKET_FILE);
+        let events = Arc::new(DeviceEvents::new());
+        let _ = listen(&socket, Arc::clone(&events))?;
+        Ok(EventHook {
+            command,
+            socket,
+            events,
+        })
+    }
+}
+
+/// Hook running this executable, only meaningful within jukeboxd.
+pub fn default_hook() -> Option<String> {
+    hook_command(&env::current_exe().ok()?)
+}
+
+/// librespot splits the hook command on whitespace, hence executables whose path contains
+/// whitespace cannot be used.
+fn hook_command(exe: &Path) -> Option<String> {
+    match exe.to_str() {
+        Some(exe) if !exe.contains(char::is_whitespace) => {
+            Some(format!("{} {}", exe, HOOK_SUBCOMMAND))
+        }
+        _ => {
+            warn!(
+                "Not receiving librespot events, path of {} contains whitespace or is not valid UTF-8, set SPOTIFY_CONNECT_EVENT_HOOK instead",
+                exe.display()
+            );
+            None
+        }
+    }
+}
+
+pub fn listen(socket: &Path, events: Arc<DeviceEvents>) -> Result<JoinHandle<()>, Error> {
+    // A socket left behind by a previous run prevents binding.
+    match fs::remove_file(socket) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(Error::from(err)),
+        _ => {}
+    }
+    let listener = UnixListener::bind(socket)?;
+    // Only librespot, running as the same user, is to send events.
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+    let handle = thread::Builder::new()
+        .name("librespot-events".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream.map_err(Error::from).and_then(receive) {
+                    Ok(Some(event)) => {
+                        debug!("Received librespot event {:?}", event);
+                        events.apply(event);
+                    }
+                    Ok(None) => {}
+                    Err(err) => warn!("Failed to receive librespot event: {}", err),
+                }
+            }
+        })?;
+    Ok(handle)
+}
+
+fn receive(mut stream: UnixStream) -> Result<Option<PlayerEvent>, Error> {
+    stream.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+    let mut message = String::new();
+    stream.read_to_string(&mut message)?;
+    let vars: HashMap<String, String> = serde_json::from_str(&message)?;
+    PlayerEvent::from_vars(&vars)
+}
+
+/// Forwards the event librespot passed to the hook in the environment to the daemon.
+pub fn forward_from_env() -> Result<(), Error> {
+    let socket = env::var_os(SOCKET_ENV).ok_or(Error::MissingVariable(SOCKET_ENV))?;
+    let vars: HashMap<String, String> = env::vars()
+        .filter(|(name, _)| HOOK_VARS.contains(&name.as_str()))
+        .collect();
+    forward(Path::new(&socket), &vars)
+}
+
+fn forward(socket: &Path, vars: &HashMap<String, String>) -> Result<(), Error> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.write_all(serde_json::to_string(vars)?.as_bytes())?;
+    Ok(())
+}
+
+pub mod err {
+    use std::fmt::{self, Display};
+
+    #[derive(Debug)]
+    pub enum Error {
+        IO(std::io::Error),
+        Json(serde_json::Error),
+        MissingVariable(&'static str),
+        InvalidVariable(&'static str, String),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::IO(err) => write!(f, "librespot event IO error: {}", err),
+                Error::Json(err) => write!(f, "Invalid librespot event: {}", err),
+                Error::MissingVariable(name) => {
+                    write!(f, "Missing librespot event variable {}", name)
+                }
+                Error::InvalidVariable(name, value) => {
+                    write!(f, "Invalid librespot event variable {}={}", name, value)
+                }
+            }
+        }
+    }
+
+    impl From<std::io::Error> for Error {
+        fn from(err: std::io::Error) -> Self {
+            Error::IO(err)
+        }
+    }
+
+    impl From<serde_json::Error> for Error {
+        fn from(err: serde_json::Error) -> Self {
+            Error::Json(err)
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn hook_variables_are_parsed() {
+        assert_eq!(
+            PlayerEvent::from_vars(&vars(&[
+                ("PLAYER_EVENT", "paused"),
+                ("TRACK_ID", "6rqhFgbbKwnb9MLmUQDhG6"),
+                ("POSITION_MS", "1500"),
+                ("DURATION_MS", "200000"),
+            ]))
+            .unwrap(),
+            Some(PlayerEvent::Paused {
+                track_id: "6rqhFgbbKwnb9MLmUQDhG6".to_string(),
+                position_ms: 1500,
+                duration_ms: 200000,
+            })
+        );
+        assert_eq!(
+            PlayerEvent::from_vars(&vars(&[
+                ("PLAYER_EVENT", "change"),
+                ("OLD_TRACK_ID", "6rqhFgbbKwnb9MLmUQDhG6"),
+                ("TRACK_ID", "spotify:track:4uLU6hMCjMI75M1A2tKUQC"),
+            ]))
+            .unwrap(),
+            Some(PlayerEvent::Changed {
+                track_id: "4uLU6hMCjMI75M1A2tKUQC".to_string(),
+            })
+        );
+        assert_eq!(
+            PlayerEvent::from_vars(&vars(&[("PLAYER_EVENT", "volume_set"), ("VOLUME", "100")]))
+                .unwrap(),
+            None
+        );
+        assert!(PlayerEvent::from_vars(&vars(&[
+            ("PLAYER_EVENT", "playing"),
+            ("TRACK_ID", "6rqhFgbbKwnb9MLmUQDhG6"),
+            ("POSITION_MS", "soon"),
+            ("DURATION_MS", "200000"),
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn hook_command_requires_path_without_whitespace() {
+        assert_eq!(
+            hook_command(Path::new("/usr/bin/jukeboxd")),
+            Some("/usr/bin/jukeboxd librespot-event".to_string())
+        );
+        assert_eq!(hook_command(Path::new("/opt/juke box/jukeboxd")), None);
+    }
+
+    #[test]
+    fn stalled_hooks_do_not_block_others() {
+        let socket = env::temp_dir().join(format!(
+            "rustberry-librespot-stalled-{}.sock",
+            std::process::id()
+        ));
+        let events = Arc::new(DeviceEvents::new());
+        let _ = listen(&socket, Arc::clone(&events)).unwrap();
+
+        // Connects without ever finishing its event.
+        let _stalled = UnixStream::connect(&socket).unwrap();
+        forward(
+            &socket,
+            &vars(&[
+                ("PLAYER_EVENT", "stop"),
+                ("TRACK_ID", "6rqhFgbbKwnb9MLmUQDhG6"),
+            ]),
+        )
+        .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while events.state().is_none() {
+            assert!(Instant::now() < deadline, "event was not received");
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_file(&socket).unwrap();
+    }
+
+    #[test]
+    fn forwarded_events_update_device_state() {
+        let socket = env::temp_dir().join(format!(
+            "rustberry-librespot-events-{}.sock",
+            std::process::id()
+        ));
+        let events = Arc::new(DeviceEvents::new());
+        let _ = listen(&socket, Arc::clone(&events)).unwrap();
+        let mode = fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        forward(
+            &socket,
+            &vars(&[
+                ("PLAYER_EVENT", "paused"),
+                ("TRACK_ID", "6rqhFgbbKwnb9MLmUQDhG6"),
+                ("POSITION_MS", "1500"),
+                ("DURATION_MS", "200000"),
+            ]),
+        )
+        .unwrap();
+        forward(
+            &socket,
+            &vars(&[
+                ("PLAYER_EVENT", "stop"),
+                ("TRACK_ID", "6rqhFgbbKwnb9MLmUQDhG6"),
+            ]),
+        )
+        .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while events.state().map(|x| x.status) != Some(DeviceStatus::Stopped) {
+            assert!(Instant::now() < deadline, "{:?}", events.state());
+            thread::sleep(Duration::from_millis(10));
+        }
+        let state = events.state().unwrap();
+        assert_eq!(state.track_id, "6rqhFgbbKwnb9MLmUQDhG6");
+        assert_eq!(state.position(), Duration::from_millis(1500));
+        assert_eq!(state.duration_ms, Some(200000));
+        events.reset();
+        assert_eq!(events.state(), None);
+        fs::remove_file(&socket).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+//! Launch parameters of the supervised librespot process, read from `LIBRESPOT_*` variables.
+
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::Deserialize;
+
+pub use err::*;
+
+const BITRATES: &[u16] = &[96, 160, 320];
+
+/// Arguments set by the supervisor itself, which must not be overridden via extra args.
+const MANAGED_ARGS: &[&str] = &[
+    "--name",
+    "-n",
+    "--username",
+    "-u",
+    "--password",
+    "-p",
+    "--cache",
+    "-c",
+    "--onevent",
+];
+
+/// File within the cache directory where librespot stores reusable credentials.
+const CREDENTIALS_FILE: &str = "credentials.json";
+
+/// How librespot logs in. Passwords are never passed to librespot.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Credentials {
+    /// Credentials cached by a previous login, see `jukeboxd librespot-login`.
+    #[default]
+    Cached,
+    /// Zeroconf discovery, the device appears once it has been selected in a Spotify app
+    /// on the local network. Credentials are cached afterwards.
+    Discovery,
+}
+
+pub fn credentials_file(cache_directory: &Path) -> PathBuf {
+    cache_directory.join(CREDENTIALS_FILE)
+}
+
+/// Modification time of the cached credentials, `None` if there are none.
+pub fn cached_credentials(cache_directory: &Path) -> Option<SystemTime> {
+    credentials_file(cache_directory)
+        .metadata()
+        .and_then(|x| x.modified())
+        .ok()
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LibrespotConfig {
+    /// Audio backend, librespot's default if unset.
+    pub backend: Option<String>,
+    /// Output device of the backend.
+    pub device: Option<String>,
+    pub mixer: Option<String>,
+    #[serde(default = "default_bitrate")]
+    pub bitrate: u16,
+    /// Initial volume in percent.
+    #[serde(default = "default_initial_volume")]
+    pub initial_volume: u8,
+    #[serde(default = "default_true")]
+    pub volume_normalisation: bool,
+    #[serde(default = "default_true")]
+    pub linear_volume: bool,
+    /// Further arguments, separated by whitespace.
+    #[serde(default)]
+    pub extra_args: String,
+    #[serde(default)]
+    pub credentials: Credentials,
+}
+
+fn default_bitrate() -> u16 {
+    160
+}
+
+fn default_initial_volume() -> u8 {
+    100
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for LibrespotConfig {
+    fn default() -> Self {
+        LibrespotConfig {
+            backend: None,
+            device: None,
+            mixer: None,
+            bitrate: default_bitrate(),
+            initial_volume: default_initial_volume(),
+            volume_normalisation: true,
+            linear_volume: true,
+            extra_args: String::new(),
+            credentials: Credentials::default(),
+        }
+    }
+}
+
+impl LibrespotConfig {
+    /// Reads and validates the configuration.
+    pub fn from_env() -> Result<Self, Error> {
+        let config: Self = envy::prefixed("LIBRESPOT_").from_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if !BITRATES.contains(&self.bitrate) {
+            return Err(Error::InvalidBitrate(self.bitrate));
+        }
+        if self.initial_volume > 100 {
+            return Err(Error::InvalidInitialVolume(self.initial_volume));
+        }
+        for (name, value) in &[
+            ("backend", &self.backend),
+            ("device", &self.device),
+            ("mixer", &self.mixer),
+        ] {
+            if value.as_ref().is_some_and(|x| x.trim().is_empty()) {
+                return Err(Error::Empty(name.to_string()));
+            }
+        }
+        for arg in self.extra_args.split_whitespace() {
+            // Short options may carry their value, e.g. `-nName`.
+            let name = if arg.starts_with("--") {
+                arg.split('=').next().unwrap_or_default()
+            } else {
+                arg.get(..2).unwrap_or(arg)
+            };
+            if MANAGED_ARGS.contains(&name) {
+                return Err(Error::ManagedArgument(name.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Arguments for librespot, not including the ones managed by the supervisor.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (name, value) in &[
+            ("--backend", &self.backend),
+            ("--device", &self.device),
+            ("--mixer", &self.mixer),
+        ] {
+            if let Some(value) = value {
+                args.push(name.to_string());
+                args.push(value.clone());
+            }
+        }
+        args.push("--bitrate".to_string());
+        args.push(self.bitrate.to_string());
+        if self.volume_normalisation {
+            args.push("--enable-volume-normalisation".to_string());
+        }
+        if self.linear_volume {
+            args.push("--linear-volume".to_string());
+        }
+        args.push(format!("--initial-volume={}", self.initial_volume));
+        args.extend(self.extra_args.split_whitespace().map(str::to_string));
+        args
+    }
+}
+
+pub mod err {
+    use std::fmt::{self, Display};
+
+    #[derive(Debug)]
+    pub enum Error {
+        Env(envy::Error),
+        InvalidBitrate(u16),
+        InvalidInitialVolume(u8),
+        Empty(String),
+        ManagedArgument(String),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::Env(err) => write!(f, "Invalid librespot configuration: {}", err),
+                Error::InvalidBitrate(bitrate) => write!(
+                    f,
+                    "Invalid librespot bitrate {}, expected 96, 160 or 320",
+                    bitrate
+                ),
+                Error::InvalidInitialVolume(volume) => {
+                    write!(f, "Invalid librespot initial volume {}%", volume)
+                }
+                Error::Empty(name) => write!(f, "Empty librespot {}", name),
+                Error::ManagedArgument(arg) => {
+                    write!(
+                        f,
+                        "librespot argument {} must not be set via extra args",
+                        arg
+                    )
+                }
+            }
+        }
+    }
+
+    impl From<envy::Error> for Error {
+        fn from(err: envy::Error) -> Self {
+            Error::Env(err)
+        }
+    }
+
+    impl std::error::Error for Error {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn credentials_mode_is_read_from_env() {
+        let config: LibrespotConfig = envy::prefixed("LIBRESPOT_")
+            .from_iter(vec![
+                ("LIBRESPOT_CREDENTIALS".to_string(), "discovery".to_string()),
+                ("LIBRESPOT_BITRATE".to_string(), "320".to_string()),
+            ])
+            .unwrap();
+        assert_eq!(config.credentials, Credentials::Discovery);
+        assert_eq!(config.bitrate, 320);
+
+        let config: LibrespotConfig = envy::prefixed("LIBRESPOT_")
+            .from_iter(Vec::<(String, String)>::new())
+            .unwrap();
+        assert_eq!(config, LibrespotConfig::default());
+    }
+
+    #[test]
+    fn defaults_match_previous_arguments_and_values_are_validated() {
+        let config = LibrespotConfig::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.args(),
+            vec![
+                "--bitrate",
+                "160",
+                "--enable-volume-normalisation",
+                "--linear-volume",
+                "--initial-volume=100",
+            ]
+        );
+
+        let config = LibrespotConfig {
+            backend: Some("alsa".to_string()),
+            device: Some("hw:1".to_string()),
+            bitrate: 320,
+            initial_volume: 50,
+            volume_normalisation: false,
+            linear_volume: false,
+            extra_args: "--verbose  --format S16".to_string(),
+            ..LibrespotConfig::default()
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.args(),
+            vec![
+                "--backend",
+                "alsa",
+                "--device",
+                "hw:1",
+                "--bitrate",
+                "320",
+                "--initial-volume=50",
+                "--verbose",
+                "--format",
+                "S16",
+            ]
+        );
+
+        for invalid in [
+            LibrespotConfig {
+                bitrate: 128,
+                ..LibrespotConfig::default()
+            },
+            LibrespotConfig {
+                initial_volume: 101,
+                ..LibrespotConfig::default()
+            },
+            LibrespotConfig {
+                mixer: Some(" ".to_string()),
+                ..LibrespotConfig::default()
+            },
+            LibrespotConfig {
+                extra_args: "--password=secret".to_string(),
+                ..LibrespotConfig::default()
+            },
+            LibrespotConfig {
+                extra_args: "-u user".to_string(),
+                ..LibrespotConfig::default()
+            },
+            LibrespotConfig {
+                extra_args: "--verbose -cdir".to_string(),
+                ..LibrespotConfig::default()
+            },
+            LibrespotConfig {
+                extra_args: "--onevent=/bin/hook".to_string(),
+                ..LibrespotConfig::default()
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{:?}", invalid);
+        }
+    }
+}
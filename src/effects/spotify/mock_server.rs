@@ -0,0 +1,175 @@
+//! Minimal HTTP server standing in for the Spotify Web API and accounts service in tests.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use url::Url;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    /// Path including the query string.
+    pub path: String,
+    pub body: String,
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    method: String,
+    path: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+    /// Number of requests the route still answers, unlimited if `None`.
+    remaining: Option<usize>,
+}
+
+#[derive(Default)]
+struct State {
+    routes: Vec<Route>,
+    requests: Vec<Request>,
+}
+
+pub struct MockServer {
+    base_url: Url,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+        let server_state = state.clone();
+        thread::Builder::new()
+            .name("spotify-mock-server".to_string())
+            .spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let _ = Self::handle(stream, &server_state);
+                }
+            })
+            .unwrap();
+        MockServer { base_url, state }
+    }
+
+    pub fn base_url(&self) -> Url {
+        self.base_url.clone()
+    }
+
+    /// Responds to requests for the path, ignoring the query string. Routes registered later
+    /// take precedence, unmatched requests are answered with 404.
+    pub fn respond(&self, method: &str, path: &str, status: u16, body: &str) {
+        self.add_route(method, path, status, &[], body, None);
+    }
+
+    /// Like `respond`, but the route only answers the next `times` matching requests.
+    pub fn respond_times(&self, method: &str, path: &str, status: u16, body: &str, times: usize) {
+        self.add_route(method, path, status, &[], body, Some(times));
+    }
+
+    /// Like `respond_times`, additionally sending the given response headers.
+    pub fn respond_times_with_headers(
+        &self,
+        method: &str,
+        path: &str,
+        status: u16,
+        headers: &[(&str, &str)],
+        body: &str,
+        times: usize,
+    ) {
+        self.add_route(method, path, status, headers, body, Some(times));
+    }
+
+    fn add_route(
+        &self,
+        method: &str,
+        path: &str,
+        status: u16,
+        headers: &[(&str, &str)],
+        body: &str,
+        remaining: Option<usize>,
+    ) {
+        self.state.lock().unwrap().routes.push(Route {
+            method: method.to_string(),
+            path: path.to_string(),
+            status,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: body.to_string(),
+            remaining,
+        });
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    fn handle(stream: TcpStream, state: &Mutex<State>) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(idx) = line.find(':') {
+                if line[..idx].eq_ignore_ascii_case("content-length") {
+                    content_length = line[idx + 1..].trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+
+        let route = {
+            let mut state = state.lock().unwrap();
+            state.requests.push(Request {
+                method: method.clone(),
+                path: path.clone(),
+                body: String::from_utf8_lossy(&body).to_string(),
+            });
+            let path_without_query = path.split('?').next().unwrap_or_default();
+            let route = state.routes.iter_mut().rev().find(|route| {
+                route.method == method
+                    && route.path == path_without_query
+                    && route.remaining != Some(0)
+            });
+            route.map(|route| {
+                if let Some(ref mut remaining) = route.remaining {
+                    *remaining -= 1;
+                }
+                route.clone()
+            })
+        };
+        let (status, headers, body) = match route {
+            Some(route) => (route.status, route.headers, route.body),
+            None => (404, Vec::new(), String::new()),
+        };
+        let headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect();
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            headers,
+            body
+        )?;
+        stream.flush()
+    }
+}
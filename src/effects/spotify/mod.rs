@@ -1,3 +1,9 @@
+pub mod client;
 pub mod connect;
+pub mod devices;
+pub mod events;
+pub mod librespot;
+#[cfg(test)]
+pub(crate) mod mock_server;
 pub mod player;
 pub mod util;
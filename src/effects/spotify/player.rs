@@ -1,224 +1,492 @@
 use std::convert::From;
 use std::fmt::{self, Display};
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use async_trait::async_trait;
 use failure::Fallible;
-use http::header::{self, AUTHORIZATION};
-use reqwest::Client;
-use serde::Serialize;
-use slog_scope::{error, info};
+use slog_scope::{info, warn};
+use tokio::runtime::Runtime;
 
-use crate::components::access_token_provider::{self, AccessTokenProvider};
+use crate::components::access_token_provider::{self, AccessTokenProvider, TokenStatus};
+use crate::components::spotify_credentials;
+use crate::components::spotify_uri::{SpotifyOffset, SpotifyResource, SpotifyUri, SpotifyUriKind};
 use crate::config::Config;
 use crate::player::{PauseState, PlaybackHandle};
 
-use super::connect::{self, SpotifyConnector};
-use super::util::is_currently_playing;
+use super::client::{self, PlaybackState, SpotifyClient, StartPlayback};
+use super::connect::{self, SpotifyConnector, SupervisorStatus};
+use super::devices::{self, DeviceService};
+use super::events::{self, DeviceEvents, DeviceState, DeviceStatus};
+use super::util::JukeboxError;
+
+/// How long starting playback waits for Spotify to confirm the transfer to our device.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Playback stopped this close to the end of the track counts as complete.
+const COMPLETION_MARGIN: Duration = Duration::from_secs(2);
 
 pub use err::*;
 
 pub struct SpotifyPlayer {
-    http_client: Arc<Client>,
+    spotify_client: Arc<SpotifyClient>,
+    device_service: Arc<DeviceService>,
     access_token_provider: Arc<AccessTokenProvider>,
     spotify_connector: Arc<Box<dyn SpotifyConnector + 'static + Sync + Send>>,
     device_name: Arc<String>,
 }
 
-#[derive(Debug, Clone, Serialize)]
-struct StartPlayback {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    context_uri: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    uris: Option<Vec<String>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    position_ms: Option<u128>,
+/// Lowers the volume of our Spotify Connect device while announcements are playing.
+pub struct SpotifyDucking {
+    /// Announcements run outside of the async context. Connections opened here are pooled by
+    /// the shared client, a threaded runtime keeps driving them between announcements.
+    runtime: Mutex<Runtime>,
+    spotify_client: Arc<SpotifyClient>,
+    spotify_connector: Arc<Box<dyn SpotifyConnector + 'static + Sync + Send>>,
+    /// Volume before ducking, if ducked.
+    volume: Mutex<Option<u8>>,
 }
 
 pub struct SpotifyPlaybackHandle {
     device_name: Arc<String>,
-    http_client: Arc<Client>,
-    access_token_provider: Arc<AccessTokenProvider>,
-    uri: String,
+    spotify_client: Arc<SpotifyClient>,
+    device_service: Arc<DeviceService>,
+    resource: SpotifyResource,
     spotify_connector: Arc<Box<dyn SpotifyConnector + 'static + Sync + Send>>,
+    device_events: Option<Arc<DeviceEvents>>,
+    /// Track and position within the track at the time of the last pause.
+    resume_point: Mutex<Option<ResumePoint>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ResumePoint {
+    track: SpotifyUri,
+    position: Duration,
+}
+
+impl ResumePoint {
+    fn from_playback_state(playback_state: PlaybackState) -> Option<Self> {
+        let track = playback_state.item?.uri.parse().ok()?;
+        let position = Duration::from_millis(playback_state.progress_ms?);
+        Some(ResumePoint { track, position })
+    }
+
+    /// librespot only reports the ID of the track, which is an episode within shows.
+    fn from_device_state(resource: &SpotifyResource, device_state: &DeviceState) -> Option<Self> {
+        let kind = match resource.uri().kind() {
+            SpotifyUriKind::Episode | SpotifyUriKind::Show => SpotifyUriKind::Episode,
+            _ => SpotifyUriKind::Track,
+        };
+        let track = SpotifyUri::new(kind, &device_state.track_id).ok()?;
+        Some(ResumePoint {
+            track,
+            position: device_state.position(),
+        })
+    }
 }
 
 #[async_trait]
 impl PlaybackHandle for SpotifyPlaybackHandle {
     async fn stop(&self) -> Fallible<()> {
-        let msg = "Failed to stop Spotify playback";
-        let access_token = self.access_token_provider.get_token()?;
-        let device_id = match self.spotify_connector.device_id() {
-            Some(device_id) => device_id,
-            None => return Err(Error::NoSpotifyDevice.into()),
-        };
-        self.http_client
-            .put("https://api.spotify.com/v1/me/player/pause")
-            .query(&[("device_id", &device_id)])
-            .body("")
-            .header(header::CONTENT_LENGTH, 0)
-            .header(AUTHORIZATION, format!("Bearer {}", access_token))
-            .send()
+        let device_id = self.device_id()?;
+        if self.taken_over(&device_id).await? {
+            return Ok(());
+        }
+        self.spotify_client
+            .pause(&device_id)
             .await
-            .map_err(|err| {
-                error!("{}: Executing HTTP request failed: {}", msg, err);
-                err
-            })
-            .map(|rsp| {
-                if !rsp.status().is_success() {
-                    error!("{}: HTTP Failure {}", msg, rsp.status());
-                }
-                rsp
-            })?
-            .error_for_status()
-            .map(|_| ())
-            .map_err(|err| Error::HTTP(err).into())
+            .map_err(|err| self.command_error(err))?;
+        Ok(())
     }
     async fn is_complete(&self) -> Fallible<bool> {
-        is_currently_playing(
-            &*self.http_client,
-            &*self.access_token_provider,
-            &*self.device_name,
-        )
-        .await
-        .map(|x| !x)
+        if let Some(device_state) = self.device_state() {
+            return Ok(Self::complete_on_device(&self.resource, &device_state));
+        }
+        let device_id = self.device_id()?;
+        let playback_state = self
+            .spotify_client
+            .playback_state()
+            .await
+            .map_err(Error::from)?;
+        if let Some(ref playback_state) = playback_state {
+            if self.check_taken_over(playback_state, &device_id) {
+                return Ok(true);
+            }
+        }
+        Ok(Self::playback_complete(
+            &self.resource,
+            &device_id,
+            playback_state.as_ref(),
+        ))
     }
     async fn pause(&self) -> Fallible<()> {
-        let msg = "Failed to stop Spotify playback";
-        let access_token = self.access_token_provider.get_token()?;
-        let device_id = match self.spotify_connector.device_id() {
-            Some(device_id) => device_id,
-            None => return Err(Error::NoSpotifyDevice.into()),
+        let device_id = self.device_id()?;
+        if let Some(device_state) = self.device_state() {
+            // Nothing to pause if our device stopped, e.g. because playback has been taken
+            // over.
+            if device_state.status == DeviceStatus::Stopped {
+                *self.resume_point.lock().unwrap() = None;
+                return Ok(());
+            }
+            let resume_point = ResumePoint::from_device_state(&self.resource, &device_state);
+            self.spotify_client
+                .pause(&device_id)
+                .await
+                .map_err(|err| self.command_error(err))?;
+            *self.resume_point.lock().unwrap() = resume_point;
+            return Ok(());
+        }
+        // Failing to determine the resume point is not fatal, resuming then falls back to the
+        // position derived from the playback duration.
+        let playback_state = match self.spotify_client.playback_state().await {
+            Ok(playback_state) => playback_state,
+            Err(err) => {
+                warn!("Failed to retrieve track to resume later: {}", err);
+                None
+            }
         };
-        self.http_client
-            .put("https://api.spotify.com/v1/me/player/pause")
-            .query(&[("device_id", &device_id)])
-            .body("")
-            .header(header::CONTENT_LENGTH, 0)
-            .header(AUTHORIZATION, format!("Bearer {}", access_token))
-            .send()
+        if let Some(ref playback_state) = playback_state {
+            if self.check_taken_over(playback_state, &device_id) {
+                *self.resume_point.lock().unwrap() = None;
+                return Ok(());
+            }
+        }
+        let resume_point = playback_state.and_then(ResumePoint::from_playback_state);
+        self.spotify_client
+            .pause(&device_id)
             .await
-            .map_err(|err| {
-                error!("{}: Executing HTTP request failed: {}", msg, err);
-                err
-            })
-            .map(|rsp| {
-                if !rsp.status().is_success() {
-                    error!("{}: HTTP Failure {}", msg, rsp.status());
-                }
-                rsp
-            })?
-            .error_for_status()
-            .map(|_| ())
-            .map_err(|err| Error::HTTP(err).into())
+            .map_err(|err| self.command_error(err))?;
+        *self.resume_point.lock().unwrap() = resume_point;
+        Ok(())
     }
     async fn cont(&self, pause_state: PauseState) -> Fallible<()> {
-        let msg = "Failed to start Spotify playback";
-        let access_token = self.access_token_provider.get_token()?;
-        let device_id = match self.spotify_connector.device_id() {
-            Some(device_id) => device_id,
-            None => return Err(Error::NoSpotifyDevice.into()),
-        };
+        let device_id = self.device_id()?;
+        let resume_point = self.resume_point.lock().unwrap().take();
         let req =
-            Self::derive_start_playback_payload_from_spotify_uri(&self.uri, &Some(pause_state));
-
-        self.http_client
-            .put("https://api.spotify.com/v1/me/player/play")
-            .query(&[("device_id", &device_id)])
-            .header(AUTHORIZATION, format!("Bearer {}", access_token))
-            .json(&req)
-            .send()
+            Self::derive_start_playback_payload(&self.resource, &Some(pause_state), resume_point);
+        self.spotify_client
+            .play(&device_id, &req)
             .await
-            .map_err(|err| {
-                error!("{}: Executing HTTP request failed: {}", msg, err);
-                err
-            })
-            .map(|rsp| {
-                if !rsp.status().is_success() {
-                    error!("{}: HTTP Failure {}", msg, rsp.status());
-                }
-                rsp
-            })?
-            .error_for_status()
-            .map(|_| ())
-            .map_err(|err| Error::HTTP(err).into())
+            .map_err(|err| self.command_error(err))?;
+        Ok(())
     }
     async fn replay(&self) -> Fallible<()> {
-        let msg = "Failed to start Spotify playback";
-        let access_token = self.access_token_provider.get_token()?;
-        let device_id = match self.spotify_connector.device_id() {
-            Some(device_id) => device_id,
-            None => return Err(Error::NoSpotifyDevice.into()),
-        };
-        let req = Self::derive_start_playback_payload_from_spotify_uri(&self.uri, &None);
-
-        self.http_client
-            .put("https://api.spotify.com/v1/me/player/play")
-            .query(&[("device_id", &device_id)])
-            .header(AUTHORIZATION, format!("Bearer {}", access_token))
-            .json(&req)
-            .send()
+        let device_id = self.device_id()?;
+        let req = Self::derive_start_playback_payload(&self.resource, &None, None);
+        self.spotify_client
+            .play(&device_id, &req)
             .await
-            .map_err(|err| {
-                error!("{}: Executing HTTP request failed: {}", msg, err);
-                err
-            })
-            .map(|rsp| {
-                if !rsp.status().is_success() {
-                    error!("{}: HTTP Failure {}", msg, rsp.status());
-                }
-                rsp
-            })?
-            .error_for_status()
-            .map(|_| ())
-            .map_err(|err| Error::HTTP(err).into())
+            .map_err(|err| self.command_error(err))?;
+        Ok(())
+    }
+    async fn position(&self) -> Fallible<Option<Duration>> {
+        if let Some(device_state) = self.device_state() {
+            return Ok(Some(device_state.position())
+                .filter(|_| device_state.status != DeviceStatus::Stopped));
+        }
+        let device_id = self.device_id()?;
+        let playback_state = self
+            .spotify_client
+            .playback_state()
+            .await
+            .map_err(Error::from)?;
+        Ok(playback_state
+            .filter(|x| Self::is_playing_resource(&self.resource, &device_id, x))
+            .and_then(|x| x.progress_ms)
+            .map(Duration::from_millis))
+    }
+    async fn is_taken_over(&self) -> Fallible<bool> {
+        // Nobody took over while librespot reports to be playing.
+        if let Some(device_state) = self.device_state() {
+            if device_state.status == DeviceStatus::Playing {
+                return Ok(false);
+            }
+        }
+        let device_id = self.device_id()?;
+        Ok(self.taken_over(&device_id).await?)
     }
 }
 
 impl SpotifyPlaybackHandle {
-    fn derive_start_playback_payload_from_spotify_uri(
-        spotify_uri: &str,
+    fn device_id(&self) -> Result<String, Error> {
+        self.spotify_connector
+            .device_id()
+            .ok_or(Error::NoSpotifyDevice)
+    }
+
+    /// State reported by librespot since this playback started. Until then, or without event
+    /// hook, the state is retrieved via the Web API.
+    fn device_state(&self) -> Option<DeviceState> {
+        self.device_events.as_ref().and_then(|x| x.state())
+    }
+
+    /// Spotify not knowing our device means that the cached device list is outdated.
+    fn command_error(&self, err: client::Error) -> Error {
+        let device_unknown = match err {
+            client::Error::NoActiveDevice => true,
+            client::Error::Status(status, _) => status == reqwest::StatusCode::NOT_FOUND,
+            _ => false,
+        };
+        if device_unknown {
+            self.device_service.invalidate();
+        }
+        Error::from(err)
+    }
+
+    /// True if our device is on the resource, no matter whether it is paused. Contexts are
+    /// compared by the context URI, single tracks and episodes by the item URI.
+    fn is_playing_resource(
+        resource: &SpotifyResource,
+        device_id: &str,
+        playback_state: &PlaybackState,
+    ) -> bool {
+        let is_our_device = playback_state
+            .device
+            .as_ref()
+            .is_some_and(|x| x.id == device_id);
+        let uri = if resource.uri().is_context() {
+            playback_state.context.as_ref().map(|x| x.uri.as_str())
+        } else {
+            playback_state.item.as_ref().map(|x| x.uri.as_str())
+        };
+        let uri: Option<SpotifyUri> = uri.and_then(|x| x.parse().ok());
+        is_our_device && uri.as_ref() == Some(resource.uri())
+    }
+
+    /// Playback is complete if our device moved on to something else, e.g. Spotify's autoplay
+    /// after the end of an album, or if it stopped at the end or beginning of a track.
+    /// Playback paused somewhere in the middle has been paused by someone else and is not
+    /// complete.
+    fn playback_complete(
+        resource: &SpotifyResource,
+        device_id: &str,
+        playback_state: Option<&PlaybackState>,
+    ) -> bool {
+        let playback_state = match playback_state {
+            Some(playback_state) => playback_state,
+            None => return true,
+        };
+        if !Self::is_playing_resource(resource, device_id, playback_state) {
+            return true;
+        }
+        if playback_state.is_playing {
+            return false;
+        }
+        let progress_ms = playback_state.progress_ms.unwrap_or(0);
+        let duration_ms = playback_state.item.as_ref().and_then(|x| x.duration_ms);
+        Self::at_track_boundary(progress_ms, duration_ms)
+    }
+
+    /// Same as `playback_complete`, but based on the events of librespot. librespot stops at
+    /// the end of a context unless started with `--autoplay`, other tracks within a context
+    /// cannot be told apart from the events.
+    fn complete_on_device(resource: &SpotifyResource, device_state: &DeviceState) -> bool {
+        let uri = resource.uri();
+        if device_state.status == DeviceStatus::Stopped
+            || (!uri.is_context() && device_state.track_id != uri.id())
+        {
+            return true;
+        }
+        match device_state.status {
+            DeviceStatus::Paused => Self::at_track_boundary(
+                device_state.position().as_millis() as u64,
+                device_state.duration_ms,
+            ),
+            _ => false,
+        }
+    }
+
+    fn at_track_boundary(progress_ms: u64, duration_ms: Option<u64>) -> bool {
+        progress_ms == 0
+            || duration_ms.is_some_and(|duration_ms| {
+                progress_ms + COMPLETION_MARGIN.as_millis() as u64 >= duration_ms
+            })
+    }
+
+    async fn taken_over(&self, device_id: &str) -> Result<bool, Error> {
+        Ok(match self.spotify_client.playback_state().await? {
+            Some(playback_state) => self.check_taken_over(&playback_state, device_id),
+            None => false,
+        })
+    }
+
+    /// Another device playing means that somebody took over the account, which we must not
+    /// interrupt.
+    fn check_taken_over(&self, playback_state: &PlaybackState, device_id: &str) -> bool {
+        let taken_over = playback_state.is_playing_elsewhere(device_id);
+        if taken_over {
+            info!(
+                "Spotify playback has been taken over from {} by {}",
+                self.device_name,
+                playback_state
+                    .device
+                    .as_ref()
+                    .map_or("unknown device", |x| x.name.as_str())
+            );
+        }
+        taken_over
+    }
+
+    fn derive_start_playback_payload(
+        resource: &SpotifyResource,
         pause_state: &Option<PauseState>,
+        resume_point: Option<ResumePoint>,
     ) -> StartPlayback {
-        let position_ms = pause_state.as_ref().map(|x| x.pos.as_millis());
-        if &spotify_uri[0..14] == "spotify:album:" || &spotify_uri[0..17] == "spotify:playlist:" {
+        let uri = resource.uri();
+        let (offset, position) = match resume_point {
+            // Continue with the track played when pausing.
+            Some(resume_point) if uri.supports_offset() => (
+                Some(SpotifyOffset::Uri(resume_point.track)),
+                Some(resume_point.position),
+            ),
+            Some(ref resume_point) if resume_point.track == *uri => {
+                (None, Some(resume_point.position))
+            }
+            // Artists and shows cannot be started at a given track, the paused track is
+            // continued on its own instead.
+            Some(resume_point) if uri.is_context() => {
+                return StartPlayback {
+                    uris: Some(vec![resume_point.track.to_string()]),
+                    context_uri: None,
+                    offset: None,
+                    position_ms: Some(resume_point.position.as_millis()),
+                }
+            }
+            // The position would be applied to the first track, which need not be the paused one.
+            _ if uri.is_context() && !uri.supports_offset() => (None, None),
+            _ => (
+                resource.offset().cloned(),
+                pause_state.as_ref().map(|x| x.pos),
+            ),
+        };
+        let position_ms = position.map(|x| x.as_millis());
+        if uri.is_context() {
             StartPlayback {
                 uris: None,
-                context_uri: Some(spotify_uri.clone().to_string()),
+                context_uri: Some(uri.to_string()),
+                offset,
                 position_ms,
             }
         } else {
             StartPlayback {
-                uris: Some(vec![spotify_uri.clone().to_string()]),
+                uris: Some(vec![uri.to_string()]),
                 context_uri: None,
+                offset: None,
                 position_ms,
             }
         }
     }
 }
 
+impl SpotifyDucking {
+    fn new(
+        spotify_client: Arc<SpotifyClient>,
+        spotify_connector: Arc<Box<dyn SpotifyConnector + 'static + Sync + Send>>,
+    ) -> Fallible<Self> {
+        let runtime = tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .core_threads(1)
+            .enable_all()
+            .build()?;
+        Ok(SpotifyDucking {
+            runtime: Mutex::new(runtime),
+            spotify_client,
+            spotify_connector,
+            volume: Mutex::new(None),
+        })
+    }
+
+    /// Scales the volume by the factor, if our device is playing. Blocks until done.
+    pub fn duck(&self, factor: f32) -> Result<(), Error> {
+        self.runtime
+            .lock()
+            .unwrap()
+            .block_on(self.duck_async(factor))
+    }
+
+    /// Restores the volume from before ducking. Blocks until done.
+    pub fn restore(&self) -> Result<(), Error> {
+        self.runtime.lock().unwrap().block_on(self.restore_async())
+    }
+
+    async fn duck_async(&self, factor: f32) -> Result<(), Error> {
+        let device_id = match self.spotify_connector.device_id() {
+            Some(device_id) => device_id,
+            None => return Ok(()),
+        };
+        let volume = match self.spotify_client.playback_state().await? {
+            Some(state) if state.is_playing => state
+                .device
+                .filter(|device| device.id == device_id)
+                .and_then(|device| device.volume_percent),
+            _ => None,
+        };
+        if let Some(volume) = volume {
+            let ducked = (volume as f32 * factor).round() as u8;
+            self.spotify_client.set_volume(&device_id, ducked).await?;
+            *self.volume.lock().unwrap() = Some(volume);
+        }
+        Ok(())
+    }
+
+    async fn restore_async(&self) -> Result<(), Error> {
+        let volume = self.volume.lock().unwrap().take();
+        if let (Some(volume), Some(device_id)) = (volume, self.spotify_connector.device_id()) {
+            self.spotify_client.set_volume(&device_id, volume).await?;
+        }
+        Ok(())
+    }
+}
+
 impl SpotifyPlayer {
     pub fn new(config: &Config) -> Fallible<Self> {
-        let http_client = Arc::new(Client::new());
+        let api_base_url = client::base_url(
+            config
+                .spotify_api_base_url
+                .as_deref()
+                .unwrap_or(client::DEFAULT_API_BASE_URL),
+        )?;
+        let accounts_base_url = client::base_url(
+            config
+                .spotify_accounts_base_url
+                .as_deref()
+                .unwrap_or(client::DEFAULT_ACCOUNTS_BASE_URL),
+        )?;
+        let state_file = config
+            .spotify_state_file
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(spotify_credentials::DEFAULT_STATE_FILE));
         // Create Access Token Provider
         let access_token_provider = Arc::new(access_token_provider::AccessTokenProvider::new(
+            &accounts_base_url,
             &config.client_id,
             &config.client_secret,
-            &config.refresh_token,
+            config.refresh_token.as_deref(),
+            Some(&state_file),
         )?);
+        let spotify_client = Arc::new(SpotifyClient::new(
+            api_base_url,
+            access_token_provider.clone(),
+        ));
+        let device_service = Arc::new(DeviceService::new(
+            spotify_client.clone(),
+            devices::DEFAULT_MAX_AGE,
+        ));
         let spotify_connector = Arc::new(Box::new(
             connect::external_command::ExternalCommand::new_from_env(
-                &access_token_provider.clone(),
+                device_service.clone(),
                 config.device_name.clone(),
-            )
-            .unwrap(),
+                events::default_hook(),
+            )?,
         )
             as Box<dyn SpotifyConnector + 'static + Sync + Send>);
 
         info!("Creating new SpotifyPlayer...");
 
         Ok(SpotifyPlayer {
-            http_client,
+            spotify_client,
+            device_service,
             access_token_provider,
             spotify_connector,
             device_name: Arc::new(config.device_name.clone()),
@@ -226,30 +494,72 @@ impl SpotifyPlayer {
     }
 
     pub fn wait_until_ready(&self) -> Result<(), Error> {
-        self.spotify_connector
-            .wait_until_ready()
-            .map_err(|_err| Error::NoSpotifyDevice)?;
+        self.spotify_connector.wait_until_ready().map_err(|_err| {
+            warn!(
+                "Spotify Connect device not ready: {:?}",
+                self.connector_status()
+            );
+            Error::NoSpotifyDevice
+        })?;
         self.access_token_provider
             .wait_for_token()
-            .map_err(|_err| Error::NoToken)?;
+            .map_err(|_err| {
+                warn!("No access token available: {:?}", self.token_status());
+                Error::NoToken
+            })?;
         Ok(())
     }
 
+    pub fn token_status(&self) -> TokenStatus {
+        self.access_token_provider.status()
+    }
+
+    /// Health of the supervised Spotify Connect process, if there is one.
+    pub fn connector_status(&self) -> Option<SupervisorStatus> {
+        self.spotify_connector.supervisor_status()
+    }
+
+    /// Terminates the Spotify Connect process.
+    pub fn terminate(&self) {
+        self.spotify_connector.terminate()
+    }
+
+    pub fn ducking(&self) -> Fallible<SpotifyDucking> {
+        SpotifyDucking::new(self.spotify_client.clone(), self.spotify_connector.clone())
+    }
+
     pub async fn start_playback(
         &self,
-        spotify_uri: &str,
-        pause_state: Option<PauseState>,
+        resource: &SpotifyResource,
+        _pause_state: Option<PauseState>,
     ) -> Result<SpotifyPlaybackHandle, failure::Error> {
-        // let req = Self::derive_start_playback_payload_from_spotify_uri(spotify_uri, &pause_state);
+        // let req = Self::derive_start_playback_payload(resource, &pause_state, None);
         let handle = SpotifyPlaybackHandle {
-            http_client: self.http_client.clone(),
-            access_token_provider: self.access_token_provider.clone(),
-            uri: spotify_uri.to_string().clone(),
+            spotify_client: self.spotify_client.clone(),
+            device_service: self.device_service.clone(),
+            resource: resource.clone(),
             spotify_connector: self.spotify_connector.clone(),
+            device_events: self.spotify_connector.device_events(),
             device_name: self.device_name.clone(),
+            resume_point: Mutex::new(None),
         };
 
-        let _ = handle.replay().await?;
+        // Playing on an inactive device is unreliable, e.g. after the account has been used
+        // on a phone.
+        let device = self
+            .device_service
+            .lookup_by_name(&self.device_name)
+            .await
+            .map_err(Error::from)?;
+        self.device_service
+            .activate(&device.id, TRANSFER_TIMEOUT)
+            .await
+            .map_err(Error::from)?;
+        // Events of a previous playback must not be taken for this one.
+        if let Some(ref device_events) = handle.device_events {
+            device_events.reset();
+        }
+        handle.replay().await?;
 
         Ok(handle)
     }
@@ -263,6 +573,15 @@ pub mod err {
         HTTP(reqwest::Error),
         NoSpotifyDevice,
         NoToken,
+        /// Still rate limited after retrying, including the delay requested by Spotify.
+        RateLimited(Option<Duration>),
+        /// Spotify kept failing with server errors.
+        Unavailable(reqwest::StatusCode),
+        /// Spotify does not consider our device active.
+        NoActiveDevice,
+        /// Spotify did not confirm the transfer of playback to our device.
+        TransferTimeout,
+        Client(client::Error),
     }
 
     impl Display for Error {
@@ -274,6 +593,16 @@ pub mod err {
                     f,
                     "Failed to obtain access token from Access Token Provider"
                 ),
+                Error::RateLimited(_) => write!(f, "Spotify rate limit exceeded"),
+                Error::Unavailable(status) => write!(f, "Spotify unavailable: {}", status),
+                Error::NoActiveDevice => write!(f, "Spotify Connect Device not active"),
+                Error::TransferTimeout => {
+                    write!(
+                        f,
+                        "Timed out transferring playback to Spotify Connect Device"
+                    )
+                }
+                Error::Client(err) => write!(f, "{}", err),
             }
         }
     }
@@ -284,13 +613,356 @@ pub mod err {
         }
     }
 
+    impl From<client::Error> for Error {
+        fn from(err: client::Error) -> Self {
+            match err {
+                client::Error::Http(err) => Error::HTTP(err),
+                client::Error::Token(err) => Error::from(err),
+                client::Error::RateLimited(retry_after) => Error::RateLimited(retry_after),
+                client::Error::Server(status) => Error::Unavailable(status),
+                client::Error::NoActiveDevice => Error::NoActiveDevice,
+                err => Error::Client(err),
+            }
+        }
+    }
+
+    impl From<JukeboxError> for Error {
+        fn from(err: JukeboxError) -> Self {
+            match err {
+                JukeboxError::DeviceNotFound { .. } => Error::NoSpotifyDevice,
+                JukeboxError::ClientError { err } => Error::from(err),
+                JukeboxError::TransferTimeout { .. } => Error::TransferTimeout,
+            }
+        }
+    }
+
     impl From<access_token_provider::AtpError> for Error {
         fn from(err: access_token_provider::err::AtpError) -> Self {
             match err {
-                access_token_provider::AtpError::NoTokenReceivedYet => Error::NoToken,
+                access_token_provider::AtpError::NoTokenReceivedYet
+                | access_token_provider::AtpError::TokenExpired
+                | access_token_provider::AtpError::NoRefreshToken => Error::NoToken,
             }
         }
     }
 
     impl std::error::Error for Error {}
 }
+
+#[cfg(test)]
+mod test {
+    use super::super::mock_server::MockServer;
+    use super::*;
+
+    struct TestConnector;
+
+    impl SpotifyConnector for TestConnector {
+        fn device_id(&self) -> Option<String> {
+            Some("abc".to_string())
+        }
+        fn request_restart(&self) {}
+    }
+
+    fn playback_handle(server: &MockServer, resource: SpotifyResource) -> SpotifyPlaybackHandle {
+        let access_token_provider = Arc::new(
+            AccessTokenProvider::new(&server.base_url(), "id", "secret", Some("refresh"), None)
+                .unwrap(),
+        );
+        access_token_provider.wait_for_token().unwrap();
+        let spotify_client = Arc::new(SpotifyClient::new(
+            server.base_url().join("v1/").unwrap(),
+            access_token_provider,
+        ));
+        SpotifyPlaybackHandle {
+            device_name: Arc::new("jukebox".to_string()),
+            device_service: Arc::new(DeviceService::new(
+                spotify_client.clone(),
+                devices::DEFAULT_MAX_AGE,
+            )),
+            spotify_client,
+            resource,
+            spotify_connector: Arc::new(Box::new(TestConnector)),
+            device_events: None,
+            resume_point: Mutex::new(None),
+        }
+    }
+
+    fn playback_state_json(device_id: &str, device_name: &str) -> String {
+        format!(
+            r#"{{"device": {{"id": "{}", "name": "{}", "is_active": true}}, "is_playing": true, "progress_ms": 1000, "item": {{"uri": "spotify:track:5j6ZZwA9BnxZi5Bk0Ng4jB", "duration_ms": 200000}}, "context": null}}"#,
+            device_id, device_name
+        )
+    }
+
+    #[test]
+    fn ducking_lowers_and_restores_the_volume() {
+        let server = MockServer::start();
+        server.respond(
+            "POST",
+            "/api/token",
+            200,
+            r#"{"access_token": "token", "token_type": "Bearer", "scope": "", "expires_in": 3600}"#,
+        );
+        server.respond(
+            "GET",
+            "/v1/me/player",
+            200,
+            r#"{"device": {"id": "abc", "name": "jukebox", "is_active": true, "volume_percent": 60}, "is_playing": true, "progress_ms": 1000, "item": null, "context": null}"#,
+        );
+        server.respond("PUT", "/v1/me/player/volume", 204, "");
+        let handle = playback_handle(
+            &server,
+            "spotify:track:5j6ZZwA9BnxZi5Bk0Ng4jB".parse().unwrap(),
+        );
+        let ducking = SpotifyDucking::new(
+            handle.spotify_client.clone(),
+            handle.spotify_connector.clone(),
+        )
+        .unwrap();
+
+        ducking.duck(0.2).unwrap();
+        ducking.restore().unwrap();
+        // Nothing left to restore.
+        ducking.restore().unwrap();
+        let volumes: Vec<String> = server
+            .requests()
+            .into_iter()
+            .filter(|req| req.method == "PUT")
+            .map(|req| req.path)
+            .collect();
+        assert_eq!(
+            volumes,
+            vec![
+                "/v1/me/player/volume?device_id=abc&volume_percent=12",
+                "/v1/me/player/volume?device_id=abc&volume_percent=60",
+            ]
+        );
+    }
+
+    #[test]
+    fn playback_taken_over_by_another_device_is_detected() {
+        let server = MockServer::start();
+        server.respond(
+            "POST",
+            "/api/token",
+            200,
+            r#"{"access_token": "token", "token_type": "Bearer", "scope": "", "expires_in": 3600}"#,
+        );
+        // The phone takes over after the first check.
+        server.respond(
+            "GET",
+            "/v1/me/player",
+            200,
+            &playback_state_json("def", "phone"),
+        );
+        server.respond_times(
+            "GET",
+            "/v1/me/player",
+            200,
+            &playback_state_json("abc", "jukebox"),
+            1,
+        );
+        let handle = playback_handle(
+            &server,
+            "spotify:track:5j6ZZwA9BnxZi5Bk0Ng4jB".parse().unwrap(),
+        );
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            assert!(!handle.is_taken_over().await.unwrap());
+            assert!(handle.is_taken_over().await.unwrap());
+            // Nothing to pause on our device anymore.
+            handle.stop().await.unwrap();
+        });
+        assert!(!server.requests().iter().any(|req| req.method == "PUT"));
+    }
+
+    #[test]
+    fn payload_depends_on_uri_type() {
+        let artist: SpotifyResource = "spotify:artist:0OdUWJ0sBjDrqHygGUXeCF".parse().unwrap();
+        let payload = SpotifyPlaybackHandle::derive_start_playback_payload(&artist, &None, None);
+        assert_eq!(
+            payload.context_uri.as_deref(),
+            Some("spotify:artist:0OdUWJ0sBjDrqHygGUXeCF")
+        );
+        assert_eq!(payload.uris, None);
+
+        let episode: SpotifyResource = "spotify:episode:512ojhOuo1ktJprKbVcKyQ".parse().unwrap();
+        let pause_state = PauseState {
+            pos: Duration::from_secs(3),
+        };
+        let payload = SpotifyPlaybackHandle::derive_start_playback_payload(
+            &episode,
+            &Some(pause_state),
+            None,
+        );
+        assert_eq!(payload.context_uri, None);
+        assert_eq!(
+            payload.uris,
+            Some(vec!["spotify:episode:512ojhOuo1ktJprKbVcKyQ".to_string()])
+        );
+        assert_eq!(payload.position_ms, Some(3000));
+    }
+
+    #[test]
+    fn resume_continues_with_paused_track() {
+        let album: SpotifyUri = "spotify:album:1DFixLWuPkv3KT3TnV35m3".parse().unwrap();
+        let resource = SpotifyResource::new(album, Some(SpotifyOffset::Position(2))).unwrap();
+        let payload = SpotifyPlaybackHandle::derive_start_playback_payload(&resource, &None, None);
+        assert_eq!(payload.offset, Some(SpotifyOffset::Position(2)));
+        assert_eq!(payload.position_ms, None);
+
+        let track: SpotifyUri = "spotify:track:5j6ZZwA9BnxZi5Bk0Ng4jB".parse().unwrap();
+        let resume_point = ResumePoint {
+            track: track.clone(),
+            position: Duration::from_millis(42_000),
+        };
+        let pause_state = PauseState {
+            pos: Duration::from_secs(500),
+        };
+        let payload = SpotifyPlaybackHandle::derive_start_playback_payload(
+            &resource,
+            &Some(pause_state),
+            Some(resume_point),
+        );
+        assert_eq!(payload.offset, Some(SpotifyOffset::Uri(track)));
+        assert_eq!(payload.position_ms, Some(42_000));
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap()["offset"]["uri"],
+            "spotify:track:5j6ZZwA9BnxZi5Bk0Ng4jB"
+        );
+    }
+
+    #[test]
+    fn resume_within_artist_continues_paused_track_on_its_own() {
+        let artist: SpotifyResource = "spotify:artist:0OdUWJ0sBjDrqHygGUXeCF".parse().unwrap();
+        let pause_state = PauseState {
+            pos: Duration::from_secs(500),
+        };
+        let payload = SpotifyPlaybackHandle::derive_start_playback_payload(
+            &artist,
+            &Some(pause_state.clone()),
+            None,
+        );
+        assert_eq!(
+            payload.context_uri.as_deref(),
+            Some("spotify:artist:0OdUWJ0sBjDrqHygGUXeCF")
+        );
+        assert_eq!(payload.position_ms, None);
+
+        let resume_point = ResumePoint {
+            track: "spotify:track:5j6ZZwA9BnxZi5Bk0Ng4jB".parse().unwrap(),
+            position: Duration::from_millis(42_000),
+        };
+        let payload = SpotifyPlaybackHandle::derive_start_playback_payload(
+            &artist,
+            &Some(pause_state),
+            Some(resume_point),
+        );
+        assert_eq!(payload.context_uri, None);
+        assert_eq!(
+            payload.uris,
+            Some(vec!["spotify:track:5j6ZZwA9BnxZi5Bk0Ng4jB".to_string()])
+        );
+        assert_eq!(payload.offset, None);
+        assert_eq!(payload.position_ms, Some(42_000));
+    }
+
+    #[test]
+    fn completion_is_derived_from_playback_state() {
+        let album: SpotifyResource = "spotify:album:1DFixLWuPkv3KT3TnV35m3".parse().unwrap();
+        let state =
+            |device_id: &str, context: &str, is_playing: bool, progress_ms: u64| PlaybackState {
+                device: Some(client::Device {
+                    id: device_id.to_string(),
+                    name: "jukebox".to_string(),
+                    is_active: true,
+                    volume_percent: None,
+                }),
+                is_playing,
+                progress_ms: Some(progress_ms),
+                item: Some(client::Item {
+                    uri: "spotify:track:5j6ZZwA9BnxZi5Bk0Ng4jB".to_string(),
+                    duration_ms: Some(200_000),
+                }),
+                context: Some(client::PlaybackContext {
+                    uri: context.to_string(),
+                }),
+            };
+        let complete = |state: Option<PlaybackState>| {
+            SpotifyPlaybackHandle::playback_complete(&album, "abc", state.as_ref())
+        };
+        let album_uri = "spotify:album:1DFixLWuPkv3KT3TnV35m3";
+
+        assert!(!complete(Some(state("abc", album_uri, true, 1000))));
+        // Paused by someone else in the middle of a track.
+        assert!(!complete(Some(state("abc", album_uri, false, 1000))));
+        // Stopped at the end.
+        assert!(complete(Some(state("abc", album_uri, false, 0))));
+        assert!(complete(Some(state("abc", album_uri, false, 199_500))));
+        // Autoplay moved on to another context.
+        assert!(complete(Some(state(
+            "abc",
+            "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M",
+            true,
+            1000
+        ))));
+        assert!(complete(Some(state("def", album_uri, false, 1000))));
+        assert!(complete(None));
+    }
+
+    #[test]
+    fn completion_and_resume_point_are_derived_from_device_events() {
+        let track: SpotifyResource = "spotify:track:5j6ZZwA9BnxZi5Bk0Ng4jB".parse().unwrap();
+        let show: SpotifyResource = "spotify:show:5CfCWKI5pZ28U0uOzXkDHe".parse().unwrap();
+        let state = |track_id: &str, status: DeviceStatus, position_ms: u64| DeviceState {
+            track_id: track_id.to_string(),
+            status,
+            position_ms,
+            duration_ms: Some(200_000),
+            updated_at: std::time::Instant::now(),
+        };
+        let complete = |resource: &SpotifyResource, state: DeviceState| {
+            SpotifyPlaybackHandle::complete_on_device(resource, &state)
+        };
+
+        assert!(!complete(
+            &track,
+            state("5j6ZZwA9BnxZi5Bk0Ng4jB", DeviceStatus::Playing, 1000)
+        ));
+        assert!(!complete(
+            &track,
+            state("5j6ZZwA9BnxZi5Bk0Ng4jB", DeviceStatus::Paused, 1000)
+        ));
+        assert!(complete(
+            &track,
+            state("5j6ZZwA9BnxZi5Bk0Ng4jB", DeviceStatus::Paused, 199_500)
+        ));
+        assert!(complete(
+            &track,
+            state("5j6ZZwA9BnxZi5Bk0Ng4jB", DeviceStatus::Stopped, 1000)
+        ));
+        // Moved on to another track.
+        assert!(complete(
+            &track,
+            state("4uLU6hMCjMI75M1A2tKUQC", DeviceStatus::Playing, 1000)
+        ));
+        assert!(!complete(
+            &show,
+            state("4uLU6hMCjMI75M1A2tKUQC", DeviceStatus::Playing, 1000)
+        ));
+
+        let resume_point = ResumePoint::from_device_state(
+            &show,
+            &state("4uLU6hMCjMI75M1A2tKUQC", DeviceStatus::Paused, 42_000),
+        )
+        .unwrap();
+        assert_eq!(
+            resume_point,
+            ResumePoint {
+                track: "spotify:episode:4uLU6hMCjMI75M1A2tKUQC".parse().unwrap(),
+                position: Duration::from_millis(42_000),
+            }
+        );
+    }
+}
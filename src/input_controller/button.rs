@@ -8,6 +8,7 @@ pub enum Command {
     Shutdown,
     VolumeUp,
     VolumeDown,
+    NextEqualizerPreset,
 }
 
 #[derive(Debug, Clone)]
@@ -15,6 +16,7 @@ pub struct Config {
     pub shutdown_pin: Option<u32>,
     pub volume_up_pin: Option<u32>,
     pub volume_down_pin: Option<u32>,
+    pub equalizer_preset_pin: Option<u32>,
     pub start_time: Option<Instant>,
 }
 
@@ -52,6 +54,7 @@ pub mod cdev_gpio {
         shutdown_pin: Option<u32>,
         volume_up_pin: Option<u32>,
         volume_down_pin: Option<u32>,
+        equalizer_preset_pin: Option<u32>,
     }
 
     impl From<EnvConfig> for Config {
@@ -61,6 +64,7 @@ pub mod cdev_gpio {
                 shutdown_pin: env_config.shutdown_pin,
                 volume_up_pin: env_config.volume_up_pin,
                 volume_down_pin: env_config.volume_down_pin,
+                equalizer_preset_pin: env_config.equalizer_preset_pin,
                 start_time,
             }
         }
@@ -90,6 +94,9 @@ pub mod cdev_gpio {
             if let Some(pin) = config.volume_down_pin {
                 map.insert(pin, Command::VolumeDown);
             }
+            if let Some(pin) = config.equalizer_preset_pin {
+                map.insert(pin, Command::NextEqualizerPreset);
+            }
             let chip = Chip::new("/dev/gpiochip0")
                 .map_err(|err| Error::IO(format!("Failed to open Chip: {:?}", err)))?;
             let (tx, rx) = crossbeam_channel::bounded(1);
@@ -168,7 +175,7 @@ pub mod cdev_gpio {
             // Spawn threads for requested GPIO lines.
             for (line_id, cmd) in self.map.iter() {
                 info!("Listening for {:?} on GPIO line {}", cmd, line_id);
-                let line_id = *line_id as u32;
+                let line_id = *line_id;
                 let line = chip
                     .get_line(line_id)
                     .map_err(|err| Error::IO(format!("Failed to get GPIO line: {:?}", err)))?;
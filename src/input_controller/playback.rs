@@ -1,7 +1,11 @@
+use std::sync::Arc;
+
 use crossbeam_channel::{self, Receiver, Sender};
 use failure::Fallible;
 use slog_scope::{error, info, warn};
 
+use crate::effects::earcon::Earcon;
+use crate::effects::Interpreter;
 use crate::player::{PlaybackRequest, PlaybackResource};
 
 #[cfg(test)]
@@ -9,9 +13,13 @@ mod test {
     use super::*;
     #[test]
     fn test_user_request_spotify_uri_serialization() {
-        let user_req = PlaybackResource::SpotifyUri("foo".to_string());
+        let uri = "spotify:album:1DFixLWuPkv3KT3TnV35m3";
+        let user_req = PlaybackResource::SpotifyUri(uri.parse().unwrap());
         let serialized = serde_json::to_string(&user_req).unwrap();
-        assert_eq!(serialized, "{\"SpotifyUri\":\"foo\"}".to_string());
+        assert_eq!(serialized, format!("{{\"SpotifyUri\":\"{}\"}}", uri));
+        let deserialized: PlaybackResource = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, user_req);
+        assert!(serde_json::from_str::<PlaybackResource>("{\"SpotifyUri\":\"foo\"}").is_err());
     }
 }
 
@@ -34,16 +42,25 @@ pub mod rfid {
     pub struct PlaybackRequestTransmitterRfid<T> {
         picc: RfidController,
         tx: Sender<T>,
+        interpreter: Arc<Box<dyn Interpreter + Sync + Send + 'static>>,
     }
 
     impl<T: 'static + Send + Sync + Clone + std::fmt::Debug> PlaybackRequestTransmitterRfid<T> {
-        pub fn new<F>(msg_transformer: F) -> Fallible<Handle<T>>
+        #[allow(clippy::new_ret_no_self)]
+        pub fn new<F>(
+            interpreter: Arc<Box<dyn Interpreter + Sync + Send + 'static>>,
+            msg_transformer: F,
+        ) -> Fallible<Handle<T>>
         where
             F: Fn(PlaybackRequest) -> Option<T> + 'static + Send + Sync,
         {
             let (tx, rx) = crossbeam_channel::bounded(1);
             let picc = RfidController::new()?;
-            let transmitter = Self { picc, tx };
+            let transmitter = Self {
+                picc,
+                tx,
+                interpreter,
+            };
             std::thread::Builder::new()
                 .name("playback-transmitter".to_string())
                 .spawn(move || transmitter.run(msg_transformer).unwrap())?;
@@ -79,7 +96,7 @@ pub mod rfid {
                         let current_uid = format!("{:?}", tag.uid);
                         if last_uid != Some(current_uid.clone()) {
                             // new tag!
-                            if let Err(err) = Self::handle_tag(&tag, &msg_transformer, &self.tx) {
+                            if let Err(err) = self.handle_tag(&tag, &msg_transformer) {
                                 error!("Failed to handle tag: {}", err);
                                 std::thread::sleep(std::time::Duration::from_millis(80));
                                 continue;
@@ -102,7 +119,13 @@ pub mod rfid {
             }
         }
 
-        fn handle_tag<F>(tag: &Tag, msg_transformer: &F, tx: &Sender<T>) -> Fallible<()>
+        fn play_earcon(&self, earcon: Earcon) {
+            if let Err(err) = self.interpreter.play_earcon(earcon) {
+                warn!("Failed to play earcon {:?}: {}", earcon, err);
+            }
+        }
+
+        fn handle_tag<F>(&self, tag: &Tag, msg_transformer: &F) -> Fallible<()>
         where
             F: Fn(PlaybackRequest) -> Option<T> + 'static + Send,
         {
@@ -116,13 +139,15 @@ pub mod rfid {
                         "Failed to deserialize RFID tag string `{}`: {}",
                         request_string, err
                     );
+                    self.play_earcon(Earcon::TagUnknown);
                     return Err(err.into());
                 }
             };
+            self.play_earcon(Earcon::TagRecognized);
             if let Some(req_transformed) =
                 msg_transformer(PlaybackRequest::Start(request_deserialized.clone()))
             {
-                tx.send(req_transformed)?;
+                self.tx.send(req_transformed)?;
             } else {
                 info!("Dropping playback request '{:?}'", &request_deserialized);
             }
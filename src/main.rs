@@ -1,15 +1,21 @@
+use std::cell::Cell;
+use std::env;
+use std::process::Command;
 use std::sync::Arc;
 use std::time::Duration;
 
 use crossbeam_channel::{self, Receiver, Select};
 use failure::Fallible;
 use slog::{self, o, Drain};
-use slog_async;
 use slog_scope::{error, info, warn};
-use slog_term;
 
+use rustberry::components::equalizer::EqualizerSettings;
+use rustberry::components::spotify_authorization;
 use rustberry::config::Config;
-use rustberry::effects::{Interpreter, ProdInterpreter};
+use rustberry::effects::earcon::Earcon;
+use rustberry::effects::spotify::connect::external_command;
+use rustberry::effects::spotify::events;
+use rustberry::effects::{output_device, Interpreter, ProdInterpreter};
 use rustberry::input_controller::{button, playback, Input};
 use rustberry::player::{self, PlaybackRequest, Player};
 
@@ -22,7 +28,49 @@ fn main() -> Fallible<()> {
     let logger = slog::Logger::root(drain, o!());
     let _guard = slog_scope::set_global_logger(logger);
 
-    slog_scope::scope(&slog_scope::logger().new(o!()), || main_with_log())
+    slog_scope::scope(&slog_scope::logger().new(o!()), || {
+        let args: Vec<String> = env::args().skip(1).collect();
+        match args.first().map(|arg| arg.as_str()) {
+            None => main_with_log(),
+            Some("list-output-devices") => list_output_devices(),
+            Some("auth") => auth(),
+            Some("librespot-login") => librespot_login(),
+            Some(events::HOOK_SUBCOMMAND) => librespot_event(),
+            Some(subcommand) => Err(failure::format_err!(
+                "Unknown subcommand '{}', supported subcommands: list-output-devices, auth, librespot-login, librespot-event",
+                subcommand
+            )),
+        }
+    })
+}
+
+fn list_output_devices() -> Fallible<()> {
+    for name in output_device::list_output_devices()? {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+/// Obtains Spotify credentials interactively via the OAuth authorization code flow.
+fn auth() -> Fallible<()> {
+    let config = envy::from_env::<spotify_authorization::Config>()?;
+    let state_file = spotify_authorization::authorize(&config, Duration::from_secs(300))?;
+    println!("Spotify credentials written to {}", state_file.display());
+    Ok(())
+}
+
+/// Logs librespot in once, so that it can use cached credentials afterwards.
+fn librespot_login() -> Fallible<()> {
+    let config = envy::from_env::<Config>()?;
+    external_command::login_from_env(&config.device_name)?;
+    println!("librespot credentials cached");
+    Ok(())
+}
+
+/// Run by librespot for its player events, forwards the event to the running daemon.
+fn librespot_event() -> Fallible<()> {
+    events::forward_from_env()?;
+    Ok(())
 }
 
 fn main_with_log() -> Fallible<()> {
@@ -49,14 +97,16 @@ fn main_with_log() -> Fallible<()> {
     let button_controller_handle =
         button::cdev_gpio::CdevGpio::new_from_env(|cmd| Some(Input::Button(cmd)))?;
     let playback_controller_handle =
-        playback::rfid::PlaybackRequestTransmitterRfid::new(|req| Some(Input::Playback(req)))?;
+        playback::rfid::PlaybackRequestTransmitterRfid::new(interpreter.clone(), |req| {
+            Some(Input::Playback(req))
+        })?;
 
     // Execute Application Logic, producing Effects.
     let application = App::new(
         config,
         interpreter.clone(),
         blinker,
-        &vec![
+        &[
             button_controller_handle.channel(),
             playback_controller_handle.channel(),
         ],
@@ -64,6 +114,7 @@ fn main_with_log() -> Fallible<()> {
     .unwrap();
     application.run().map_err(|err| {
         warn!("Jukebox loop terminated, terminating application: {}", err);
+        let _ = interpreter.play_earcon(Earcon::Error);
         err
     })?;
     unreachable!();
@@ -75,7 +126,11 @@ struct App {
     interpreter: Arc<Box<dyn Interpreter + Sync + Send + 'static>>,
     inputs: Vec<Receiver<Input>>,
     blinker: Blinker,
-    runtime: tokio::runtime::Runtime,
+    /// Drives the player.
+    _runtime: tokio::runtime::Runtime,
+    equalizer_presets: Vec<EqualizerSettings>,
+    /// Index of the currently selected equalizer preset, if any.
+    equalizer_preset: Cell<Option<usize>>,
 }
 
 impl App {
@@ -87,20 +142,66 @@ impl App {
     ) -> Fallible<Self> {
         let runtime = tokio::runtime::Runtime::new().unwrap();
         let player = Player::new(runtime.handle(), interpreter.clone())?;
+        let equalizer_presets = match config.equalizer_presets {
+            Some(ref presets) => EqualizerSettings::parse_presets(presets)?,
+            None => Vec::new(),
+        };
         let app = Self {
-            runtime,
+            _runtime: runtime,
             config,
             inputs: inputs.to_vec(),
             player,
             interpreter,
             blinker,
+            equalizer_presets,
+            equalizer_preset: Cell::new(None),
         };
         Ok(app)
     }
 
-    pub fn run(self) -> Fallible<()> {
-        let runtime = tokio::runtime::Runtime::new();
+    fn play_earcon(&self, earcon: Earcon) {
+        if let Err(err) = self.interpreter.play_earcon(earcon) {
+            warn!("Failed to play earcon {:?}: {}", earcon, err);
+        }
+    }
+
+    /// Asks the configured command, if any, whether the volume limit has been reached.
+    /// A non-zero exit status is a regular answer, hence it is not treated as a failure.
+    fn volume_limit_reached(&self) -> bool {
+        let cmd = match self.config.volume_limit_reached_command {
+            Some(ref cmd) => cmd,
+            None => return false,
+        };
+        match Command::new("/bin/sh").arg("-c").arg(cmd).status() {
+            Ok(exit_status) => exit_status.success(),
+            Err(err) => {
+                warn!("Failed to execute volume limit command '{}': {}", cmd, err);
+                false
+            }
+        }
+    }
 
+    /// Selects the next equalizer preset, starting over after the last one.
+    fn next_equalizer_preset(&self) {
+        if self.equalizer_presets.is_empty() {
+            warn!("No equalizer presets configured");
+            return;
+        }
+        let index = match self.equalizer_preset.get() {
+            Some(index) => (index + 1) % self.equalizer_presets.len(),
+            None => 0,
+        };
+        self.equalizer_preset.set(Some(index));
+        info!("Selecting equalizer preset {}", index);
+        if let Err(err) = self
+            .interpreter
+            .set_equalizer(self.equalizer_presets[index].clone())
+        {
+            error!("Failed to set equalizer preset {}: {}", index, err);
+        }
+    }
+
+    pub fn run(self) -> Fallible<()> {
         self.blinker.run_async(led::Cmd::Repeat(
             1,
             Box::new(led::Cmd::Many(vec![
@@ -112,8 +213,9 @@ impl App {
         for r in &self.inputs {
             sel.recv(r);
         }
+        let mut n_open_inputs = self.inputs.len();
 
-        loop {
+        while n_open_inputs > 0 {
             // Wait until a receive operation becomes ready and try executing it.
             let index = sel.ready();
             let res = self.inputs[index].try_recv();
@@ -124,7 +226,10 @@ impl App {
                         // If the operation turns out not to be ready, retry.
                         continue;
                     } else {
-                        error!("Failed to receive input event: {}", err);
+                        // A disconnected input would be ready forever.
+                        warn!("Input {} has been closed", index);
+                        sel.remove(index);
+                        n_open_inputs -= 1;
                     }
                 }
                 Ok(input) => {
@@ -132,6 +237,10 @@ impl App {
                     match input {
                         Input::Button(cmd) => match cmd {
                             button::Command::Shutdown => {
+                                self.play_earcon(Earcon::ShuttingDown);
+                                if let Err(err) = self.interpreter.shutdown() {
+                                    error!("Failed to prepare shutdown: {}", err);
+                                }
                                 if let Err(err) = self.interpreter.generic_command(
                                     self.config
                                         .shutdown_command
@@ -142,7 +251,10 @@ impl App {
                                 }
                             }
                             button::Command::VolumeUp => {
-                                if let Err(err) = self.interpreter.generic_command(
+                                if self.volume_limit_reached() {
+                                    info!("Volume limit reached, not increasing volume");
+                                    self.play_earcon(Earcon::VolumeLimitReached);
+                                } else if let Err(err) = self.interpreter.generic_command(
                                     self.config
                                         .volume_up_command
                                         .clone()
@@ -161,10 +273,12 @@ impl App {
                                     error!("Failed to decrease volume: {}", err);
                                 }
                             }
+                            button::Command::NextEqualizerPreset => self.next_equalizer_preset(),
                         },
                         Input::Playback(request) => {
                             if let Err(err) = self.player.playback(request.clone()) {
                                 error!("Failed to execute playback request {:?}: {}", request, err);
+                                self.play_earcon(Earcon::Error);
                             }
                             match request {
                                 PlaybackRequest::Start(_) => {
@@ -179,6 +293,8 @@ impl App {
                 }
             };
         }
+        info!("All inputs have been closed");
+        Ok(())
     }
 }
 
@@ -195,9 +311,8 @@ mod test {
         let (interpreter, effects_rx) = TestInterpreter::new();
         let interpreter =
             Arc::new(Box::new(interpreter) as Box<dyn Interpreter + Send + Sync + 'static>);
-        let (effects_tx, effects_rx) = crossbeam_channel::bounded(10);
         let config: Config = Config {
-            refresh_token: "token".to_string(),
+            refresh_token: Some("token".to_string()),
             client_id: "client".to_string(),
             client_secret: "secret".to_string(),
             device_name: "device".to_string(),
@@ -205,21 +320,66 @@ mod test {
             shutdown_command: None,
             volume_up_command: None,
             volume_down_command: None,
+            volume_limit_reached_command: None,
+            equalizer_presets: None,
+            spotify_api_base_url: None,
+            spotify_accounts_base_url: None,
+            spotify_state_file: None,
         };
         let blinker = Blinker::new(interpreter.clone()).unwrap();
         let inputs = vec![Input::Button(button::Command::Shutdown)];
-        let effects_expected = vec![Effects::GenericCommand("sudo shutdown -h now".to_string())];
+        let effects_expected = vec![
+            Effects::PlayEarcon(Earcon::ShuttingDown),
+            Effects::GenericCommand("sudo shutdown -h now".to_string()),
+        ];
         let (input_tx, input_rx) = crossbeam_channel::unbounded();
-        let app = App::new(config, interpreter, blinker, &vec![input_rx]).unwrap();
+        let app = App::new(config, interpreter, blinker, &[input_rx]).unwrap();
         for input in inputs {
             input_tx.send(input).unwrap();
         }
         drop(input_tx);
-        app.run();
+        app.run().unwrap();
         let produced_effects: Vec<Effects> = effects_rx.iter().collect();
 
         assert_eq!(produced_effects, effects_expected);
     }
+
+    #[test]
+    fn equalizer_presets_are_cycled() {
+        let (interpreter, effects_rx) = TestInterpreter::new();
+        let interpreter =
+            Arc::new(Box::new(interpreter) as Box<dyn Interpreter + Send + Sync + 'static>);
+        let config: Config = Config {
+            refresh_token: Some("token".to_string()),
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            device_name: "device".to_string(),
+            post_init_command: None,
+            shutdown_command: None,
+            volume_up_command: None,
+            volume_down_command: None,
+            volume_limit_reached_command: None,
+            equalizer_presets: Some("low-shelf:250:-6;".to_string()),
+            spotify_api_base_url: None,
+            spotify_accounts_base_url: None,
+            spotify_state_file: None,
+        };
+        let blinker = Blinker::new(interpreter.clone()).unwrap();
+        let app = App::new(config, interpreter, blinker, &[]).unwrap();
+        for _ in 0..3 {
+            app.next_equalizer_preset();
+        }
+        drop(app);
+        let boomy = EqualizerSettings::parse("low-shelf:250:-6").unwrap();
+        let effects_expected = vec![
+            Effects::SetEqualizer(boomy.clone()),
+            Effects::SetEqualizer(EqualizerSettings::default()),
+            Effects::SetEqualizer(boomy),
+        ];
+        let produced_effects: Vec<Effects> = effects_rx.try_iter().collect();
+
+        assert_eq!(produced_effects, effects_expected);
+    }
 }
 
 mod led {
@@ -4,16 +4,20 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use failure::Fallible;
 use serde::{Deserialize, Serialize};
 use slog_scope::{error, info};
 use tokio::runtime;
 
+use crate::components::spotify_uri::SpotifyResource;
 use crate::effects::Interpreter;
 
 pub use err::*;
 
+/// Interval for checking whether playback has been taken over while playing.
+const TAKEOVER_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
 #[async_trait]
 pub trait PlaybackHandle {
     async fn stop(&self) -> Fallible<()>;
@@ -21,6 +25,14 @@ pub trait PlaybackHandle {
     async fn pause(&self) -> Fallible<()>;
     async fn cont(&self, pause_state: PauseState) -> Fallible<()>;
     async fn replay(&self) -> Fallible<()>;
+    /// Playback position as reported by the backend, `None` if the backend cannot tell.
+    async fn position(&self) -> Fallible<Option<Duration>> {
+        Ok(None)
+    }
+    /// True if someone else took over playback, e.g. via the Spotify app on a phone.
+    async fn is_taken_over(&self) -> Fallible<bool> {
+        Ok(false)
+    }
 }
 #[derive(Debug, Clone)]
 pub struct PauseState {
@@ -92,8 +104,9 @@ pub enum PlaybackRequest {
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum PlaybackResource {
-    SpotifyUri(String),
+    SpotifyUri(SpotifyResource),
     Http(String),
+    Podcast(String),
 }
 
 impl PlayerHandle {
@@ -120,7 +133,7 @@ impl Player {
         interpreter
             .play(resource.clone(), pause_state)
             .await
-            .map(|x| Arc::new(x))
+            .map(Arc::new)
     }
 
     async fn state_machine(
@@ -263,7 +276,12 @@ impl Player {
             }
             self::PlaybackRequest::Stop => {
                 match state {
-                    Idle | Paused { .. } => {
+                    Idle => {
+                        // Expected once playback has been taken over.
+                        info!("Player received Playback-Stop-Request while not playing");
+                        (Ok(()), Idle)
+                    }
+                    Paused { .. } => {
                         // Unexpected code path.
                         error!("Player received Playback-Stop-Request while not playing");
                         (Ok(()), Idle)
@@ -276,8 +294,14 @@ impl Player {
                     } => {
                         let is_completed = handle.is_complete().await.unwrap_or(true);
 
-                        let now = std::time::Instant::now();
-                        let played_pos = offset + now.duration_since(playing_since);
+                        let played_pos = match handle.position().await {
+                            Ok(Some(pos)) => pos,
+                            Ok(None) => offset + playing_since.elapsed(),
+                            Err(err) => {
+                                error!("Failed to retrieve playback position: {}", err);
+                                offset + playing_since.elapsed()
+                            }
+                        };
 
                         if let Err(err) = handle.pause().await {
                             error!("Failed to execute playback pause: {}", err);
@@ -302,33 +326,64 @@ impl Player {
         }
     }
 
+    /// Playback taken over by someone else ends our playback, as if the tag had been removed
+    /// at the end of the resource.
+    async fn check_taken_over(
+        interpreter: Arc<Box<dyn Send + Sync + 'static + Interpreter>>,
+        state: PlayerState,
+    ) -> PlayerState {
+        if let PlayerState::Playing { ref handle, .. } = state {
+            match handle.is_taken_over().await {
+                Ok(true) => {
+                    info!("Player State Transition: {} -> Idle (taken over)", state);
+                    if let Err(err) = interpreter.led_off() {
+                        error!("Failed to switch off LED: {}", err);
+                    }
+                    return PlayerState::Idle;
+                }
+                Ok(false) => {}
+                Err(err) => error!(
+                    "Failed to check whether playback has been taken over: {}",
+                    err
+                ),
+            }
+        }
+        state
+    }
+
     async fn player_loop(mut player: Player) {
         loop {
-            let command = player.rx.recv().unwrap();
-            match command {
-                PlayerCommand {
-                    result_transmitter,
-                    request,
-                } => {
-                    let current_state = player.state.clone();
-                    let (res, new_state) =
-                        Self::state_machine(player.interpreter.clone(), request, current_state)
+            let command = match player.rx.recv_timeout(TAKEOVER_CHECK_INTERVAL) {
+                Ok(command) => command,
+                Err(RecvTimeoutError::Timeout) => {
+                    player.state =
+                        Self::check_taken_over(player.interpreter.clone(), player.state.clone())
                             .await;
-                    if let Err(ref err) = res {
-                        error!(
-                            "Player State Transition Failure: {}, staying in State {}",
-                            err, &player.state
-                        );
-                    } else {
-                        info!("Player State Transition: {} -> {}", player.state, new_state);
-                    }
-                    player.state = new_state;
-                    result_transmitter.send(res).unwrap();
+                    continue;
                 }
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            let PlayerCommand {
+                result_transmitter,
+                request,
+            } = command;
+            let current_state = player.state.clone();
+            let (res, new_state) =
+                Self::state_machine(player.interpreter.clone(), request, current_state).await;
+            if let Err(ref err) = res {
+                error!(
+                    "Player State Transition Failure: {}, staying in State {}",
+                    err, &player.state
+                );
+            } else {
+                info!("Player State Transition: {} -> {}", player.state, new_state);
             }
+            player.state = new_state;
+            result_transmitter.send(res).unwrap();
         }
     }
 
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(
         runtime: &runtime::Handle,
         interpreter: Arc<Box<dyn Send + Sync + 'static + Interpreter>>,
@@ -400,10 +455,10 @@ mod test {
         let (interpreter, effects_rx) = TestInterpreter::new();
         let interpreter =
             Arc::new(Box::new(interpreter) as Box<dyn Interpreter + Send + Sync + 'static>);
-        let player_handle = Player::new(&runtime.handle(), interpreter).unwrap();
-        let playback_requests = vec![
+        let player_handle = Player::new(runtime.handle(), interpreter).unwrap();
+        let playback_requests = [
             PlaybackRequest::Start(PlaybackResource::SpotifyUri(
-                "spotify:track:5j6ZZwA9BnxZi5Bk0Ng4jB".to_string(),
+                "spotify:track:5j6ZZwA9BnxZi5Bk0Ng4jB".parse()?,
             )),
             PlaybackRequest::Stop,
         ];
@@ -416,9 +471,57 @@ mod test {
         for req in playback_requests.iter() {
             player_handle.playback(req.clone()).unwrap();
         }
+        // Terminates the player loop, which closes the channel of effects.
+        drop(player_handle);
         let produced_effects: Vec<_> = effects_rx.iter().collect();
 
         assert_eq!(produced_effects, effects_expected);
         Ok(())
     }
+
+    struct TakenOverHandle;
+
+    #[async_trait]
+    impl PlaybackHandle for TakenOverHandle {
+        async fn stop(&self) -> Fallible<()> {
+            Ok(())
+        }
+        async fn is_complete(&self) -> Fallible<bool> {
+            Ok(false)
+        }
+        async fn pause(&self) -> Fallible<()> {
+            Ok(())
+        }
+        async fn cont(&self, _pause_state: PauseState) -> Fallible<()> {
+            Ok(())
+        }
+        async fn replay(&self) -> Fallible<()> {
+            Ok(())
+        }
+        async fn is_taken_over(&self) -> Fallible<bool> {
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn taken_over_playback_becomes_idle() {
+        let mut runtime = Runtime::new().unwrap();
+        let (interpreter, effects_rx) = TestInterpreter::new();
+        let interpreter =
+            Arc::new(Box::new(interpreter) as Box<dyn Interpreter + Send + Sync + 'static>);
+        let playing = PlayerState::Playing {
+            resource: PlaybackResource::Http("http://example.org/stream.mp3".to_string()),
+            playing_since: Instant::now(),
+            offset: Duration::from_secs(0),
+            handle: Arc::new(Box::new(TakenOverHandle) as DynPlaybackHandle),
+        };
+
+        let state = runtime.block_on(Player::check_taken_over(interpreter, playing));
+
+        assert!(matches!(state, PlayerState::Idle));
+        assert_eq!(
+            effects_rx.try_iter().collect::<Vec<_>>(),
+            vec![Effects::LedOff]
+        );
+    }
 }